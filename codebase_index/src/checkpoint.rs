@@ -0,0 +1,202 @@
+use anyhow::Result;
+use tracing::{warn, error};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::IndexError;
+
+// Minimum time between checkpoint writes while ingesting
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Progress of an ingestion, persisted so an interrupted run can be resumed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub root_name: String,
+    pub root_id: String,
+    // Folder ids by path relative to the root
+    pub folders: HashMap<String, String>,
+    // Files by path relative to the root
+    pub files: HashMap<String, FileProgress>,
+    // Folder the root was ingested from; a checkpoint is only resumed from the same one
+    #[serde(default)]
+    pub root_path: PathBuf,
+    // Outstanding embedding jobs per file id
+    #[serde(skip)]
+    pending: HashMap<String, usize>,
+    // File ids whose entities have all been created and queued
+    #[serde(skip)]
+    processed: HashMap<String, String>,
+    #[serde(skip)]
+    last_saved: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileProgress {
+    pub file_id: String,
+    // Entities created and every chunk embedded
+    pub complete: bool,
+}
+
+lazy_static! {
+    static ref CHECKPOINT: Mutex<Option<Checkpoint>> = Mutex::new(None);
+}
+
+// Checkpoint file in the working directory. Characters other than letters,
+// digits, '-', '_' and '.' in the root name, such as path separators, are
+// replaced so that any name gives a file there.
+fn checkpoint_path(root_name: &str) -> PathBuf {
+    let name: String = root_name.chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    PathBuf::from(format!(".checkpoint-{}.json", name))
+}
+
+// Load the checkpoint left by an interrupted ingestion of this root, if any.
+// A checkpoint of another folder, such as another checkout with the same name,
+// is refused rather than adding this folder's files to that run's root.
+pub fn load(root_path: &Path, root_name: &str) -> Result<Option<Checkpoint>> {
+    let Ok(contents) = fs::read_to_string(checkpoint_path(root_name)) else {
+        return Ok(None);
+    };
+    let checkpoint: Checkpoint = match serde_json::from_str(&contents) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            warn!("Ignoring unreadable checkpoint for {}: {}", root_name, e);
+            return Ok(None);
+        }
+    };
+    if checkpoint.root_name != root_name || checkpoint.root_path != root_path {
+        return Err(IndexError::Config(format!(
+            "The checkpoint for {} is of {} {}, not {}; resume from that folder, or ingest without --resume",
+            root_name, checkpoint.root_name, checkpoint.root_path.display(), root_path.display()
+        )).into());
+    }
+    Ok(Some(checkpoint))
+}
+
+// Start tracking progress for an ingestion, continuing from `resumed` if given
+pub fn start(root_path: &Path, root_name: &str, root_id: &str, resumed: Option<Checkpoint>) {
+    let mut checkpoint = resumed.unwrap_or_default();
+    checkpoint.root_name = root_name.to_string();
+    checkpoint.root_id = root_id.to_string();
+    checkpoint.root_path = root_path.to_path_buf();
+    *CHECKPOINT.lock().unwrap() = Some(checkpoint);
+    save(true);
+}
+
+// Stop tracking, removing the checkpoint file once every file is complete.
+// An interrupted run keeps it, since files it never reached are not listed.
+pub fn finish(interrupted: bool) -> Result<()> {
+    let Some(checkpoint) = CHECKPOINT.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let path = checkpoint_path(&checkpoint.root_name);
+    if !interrupted && checkpoint.files.values().all(|file| file.complete) {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    } else {
+        fs::write(path, serde_json::to_string(&checkpoint)?)?;
+    }
+    Ok(())
+}
+
+fn relative_key(checkpoint: &Checkpoint, path: &Path) -> String {
+    path.strip_prefix(&checkpoint.root_path)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Id of a folder created by a previous run
+pub fn folder_id(path: &Path) -> Option<String> {
+    let guard = CHECKPOINT.lock().unwrap();
+    let checkpoint = guard.as_ref()?;
+    checkpoint.folders.get(&relative_key(checkpoint, path)).cloned()
+}
+
+pub fn record_folder(path: &Path, folder_id: &str) {
+    if let Some(checkpoint) = CHECKPOINT.lock().unwrap().as_mut() {
+        let key = relative_key(checkpoint, path);
+        checkpoint.folders.insert(key, folder_id.to_string());
+    }
+    save(false);
+}
+
+// Progress of a file from a previous run
+pub fn file_progress(path: &Path) -> Option<FileProgress> {
+    let guard = CHECKPOINT.lock().unwrap();
+    let checkpoint = guard.as_ref()?;
+    checkpoint.files.get(&relative_key(checkpoint, path)).cloned()
+}
+
+pub fn record_file(path: &Path, file_id: &str) {
+    if let Some(checkpoint) = CHECKPOINT.lock().unwrap().as_mut() {
+        let key = relative_key(checkpoint, path);
+        checkpoint.files.insert(key, FileProgress { file_id: file_id.to_string(), complete: false });
+    }
+    save(false);
+}
+
+// All of a file's entities are created and its embedding jobs queued
+pub fn file_processed(path: &Path, file_id: &str) {
+    if let Some(checkpoint) = CHECKPOINT.lock().unwrap().as_mut() {
+        let key = relative_key(checkpoint, path);
+        checkpoint.processed.insert(file_id.to_string(), key);
+        mark_if_complete(checkpoint, file_id);
+    }
+    save(false);
+}
+
+pub fn job_queued(file_id: &str) {
+    if let Some(checkpoint) = CHECKPOINT.lock().unwrap().as_mut() {
+        *checkpoint.pending.entry(file_id.to_string()).or_insert(0) += 1;
+    }
+}
+
+pub fn job_done(file_id: &str) {
+    if let Some(checkpoint) = CHECKPOINT.lock().unwrap().as_mut() {
+        if let Some(count) = checkpoint.pending.get_mut(file_id) {
+            *count = count.saturating_sub(1);
+        }
+        mark_if_complete(checkpoint, file_id);
+    }
+    save(false);
+}
+
+fn mark_if_complete(checkpoint: &mut Checkpoint, file_id: &str) {
+    if checkpoint.pending.get(file_id).copied().unwrap_or(0) > 0 {
+        return;
+    }
+    if let Some(key) = checkpoint.processed.remove(file_id) {
+        checkpoint.pending.remove(file_id);
+        if let Some(file) = checkpoint.files.get_mut(&key) {
+            file.complete = true;
+        }
+    }
+}
+
+// Write the checkpoint to disk, at most once per SAVE_INTERVAL unless forced
+fn save(force: bool) {
+    let mut guard = CHECKPOINT.lock().unwrap();
+    let Some(checkpoint) = guard.as_mut() else {
+        return;
+    };
+    if !force && checkpoint.last_saved.is_some_and(|t| t.elapsed() < SAVE_INTERVAL) {
+        return;
+    }
+    checkpoint.last_saved = Some(Instant::now());
+    match serde_json::to_string(&*checkpoint) {
+        Ok(contents) => {
+            if let Err(e) = fs::write(checkpoint_path(&checkpoint.root_name), contents) {
+                error!("Failed to write checkpoint: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize checkpoint: {}", e),
+    }
+}
@@ -0,0 +1,827 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::endpoints;
+use crate::error::IndexError;
+use crate::walk;
+
+// Runtime configuration, built from environment variables and command line arguments
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub path: String,
+    // Host and port of the Helix instance
+    pub helix_host: String,
+    pub port: u16,
+    // Name of the root in the index, defaults to the name of the indexed folder
+    pub root_name: Option<String>,
+    // Report what update would change without writing to Helix
+    pub dry_run: bool,
+    // Continue an interrupted ingestion from its checkpoint
+    pub resume: bool,
+    // Replace the roots named like the ingested one once it is complete
+    pub clean: bool,
+    // Embed the entities verify finds without an embedding
+    pub repair: bool,
+    // Index only the files listed in this file, one per line, "-" for stdin
+    pub files_from: Option<String>,
+    // Git ref; update then indexes only the files changed since it
+    pub since: Option<String>,
+    // File to print the entities and chunks of, instead of indexing
+    pub inspect: Option<String>,
+    // Run a single action and exit instead of showing the menu
+    pub action: Option<Action>,
+    // Text to search for with the search action, and the number of results
+    pub query: Option<String>,
+    pub top_k: usize,
+    // Write the files and entities each run creates, with their ids, to this JSON file
+    pub manifest: Option<PathBuf>,
+    // Time each file and list the slowest at the end of a run
+    pub profile: bool,
+    // Override the location of index-types.json and file_types.json
+    pub index_types_path: Option<PathBuf>,
+    pub file_types_path: Option<PathBuf>,
+    // Files read, parsed and written to Helix at the same time
+    pub concurrency: usize,
+    // Embedding requests in flight at the same time, independent of concurrency;
+    // embed_rpm still limits how often they start
+    pub concurrency_embeddings: usize,
+    // Requests to Helix in flight at the same time
+    pub helix_max_inflight: usize,
+    // Requests per minute allowed to the embedding API and to Helix
+    pub embed_rpm: NonZeroU32,
+    pub helix_rpm: NonZeroU32,
+    // Times a transiently failing embedding request is retried
+    pub embed_retries: u32,
+    // Seconds before a request to the embedding API or to Helix times out
+    pub embed_timeout: u64,
+    pub helix_timeout: u64,
+    // Gzip large request bodies sent to Helix
+    pub compress_requests: bool,
+    // Idle connections kept open per host for the embedding API and for Helix
+    pub embed_pool_size: usize,
+    pub helix_pool_size: usize,
+    // Names a Helix deployment gives its queries, by their name in db/queries.hx
+    pub helix_endpoints: HashMap<String, String>,
+    // Service embeddings are requested from
+    pub embed_provider: EmbedProvider,
+    // Embedding server of the http provider, its JSON request body with a "{text}"
+    // placeholder, and the path of the vector in its response
+    pub embed_url: Option<String>,
+    pub embed_body: Value,
+    pub embed_response_path: String,
+    // Model name recorded on roots embedded with the http provider
+    pub embed_model: Option<String>,
+    // Template for the text embedded for a chunk of code, with {code} and the
+    // {path}, {type} and {name} of the entity it is part of; the chunk alone when unset
+    pub embed_context: Option<String>,
+    // Length of the embedding vectors requested from the API, the model's full size when unset
+    pub embed_dimensions: Option<usize>,
+    // Price per million embedding tokens, used to estimate the cost of a run
+    pub embed_price_per_million: Option<f64>,
+    // What to do with chunks longer than the embedding API accepts
+    pub oversized_chunks: OversizedChunks,
+    // Whether top level entities are summarized by a language model and the summary embedded
+    pub summaries: Summaries,
+    // Gemini model that writes entity summaries
+    pub summary_model: String,
+    // Gemini task types for embedding indexed code and search queries
+    pub document_task_type: String,
+    pub query_task_type: String,
+    // Log filter such as "debug" or "codebase_index=debug", overrides RUST_LOG
+    pub log_level: Option<String>,
+    // Follow symlinks while walking; links leaving the root and cycles are still skipped
+    pub follow_symlinks: bool,
+    // Deepest level of nested entities; deeper code stays part of its parent's text
+    pub max_entity_depth: usize,
+    // Node kinds never indexed, in any language, on top of index-types.json
+    pub exclude_kinds: Vec<String>,
+    // Chunks embedded per file; the rest of a larger file, usually generated, is left out
+    pub max_chunks_per_file: usize,
+    // Directories left out of the index, by name or by path from the root
+    pub skip_dirs: Vec<String>,
+    // Directories indexed as folders and files only, without entities or embeddings
+    pub structure_only_dirs: Vec<String>,
+    // Globs over paths from the root; only matching files are indexed when include is set
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // Store entity text only in the file it is part of; entities keep their byte range into it
+    pub omit_entity_text: bool,
+    // Link files whose content was already ingested to the existing entities instead of re-embedding
+    pub dedup: bool,
+    // Scale embedding vectors to unit length, for indexes that compare vectors by dot product
+    pub normalize_embeddings: bool,
+    // Walk entries by name and create files and entities one at a time, so
+    // repeated runs write the index in the same order
+    pub deterministic: bool,
+    // Chunk and embed every file as text, without extracting entities
+    pub file_level: bool,
+    // Store the author and commit that last changed each entity, from git blame
+    pub with_blame: bool,
+    // Handling of files whose language has a grammar but no index-types.json entry
+    pub missing_index_types: MissingIndexTypes,
+}
+
+// Handling of chunks longer than the embedding API's input limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedChunks {
+    // Embed the chunk in pieces that each fit
+    Split,
+    // Embed only the start of the chunk
+    Truncate,
+    // Leave the chunk without an embedding, with a warning
+    Skip,
+}
+
+// Action run without the menu, for scripts, CI and git hooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Ingest,
+    Update,
+    Search,
+}
+
+// Service that embeds code and search queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbedProvider {
+    Gemini,
+    // Any embedding server taking a JSON request, configured by embed_url,
+    // embed_body and embed_response_path
+    Http,
+    // Vectors derived from a hash of the text, without any request; for tests
+    // and trying the pipeline without an API key
+    Mock,
+}
+
+// Handling of parsed files whose language index-types.json does not list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingIndexTypes {
+    // Create the file without entities or embeddings
+    Skip,
+    // Index every node kind, as if the language were listed with "ALL"
+    All,
+    // Chunk and embed the whole file as text, like files without a grammar
+    FileLevel,
+}
+
+// Embedding of language model summaries of top level entities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Summaries {
+    Off,
+    // Embed the summary as well as the code
+    Alongside,
+    // Embed the summary in place of the code
+    Instead,
+}
+
+// Options that take no value on the command line
+const FLAGS: &[&str] = &[
+    "dry_run", "resume", "clean", "repair", "follow_symlinks", "dedup", "normalize_embeddings", "deterministic", "omit_entity_text",
+    "compress_requests", "file_level", "profile", "with_blame",
+];
+
+// Options that can also be set through an environment variable, named as env_var says
+const ENV_KEYS: &[&str] = &[
+    "root_name", "helix_host",
+    "index_types_path", "file_types_path",
+    "concurrency", "concurrency_embeddings", "helix_max_inflight",
+    "embed_rpm", "helix_rpm", "embed_retries",
+    "embed_timeout", "helix_timeout", "embed_pool_size", "helix_pool_size", "helix_endpoints",
+    "document_task_type", "query_task_type", "embed_price_per_million", "embed_dimensions", "oversized_chunks",
+    "embed_provider", "embed_url", "embed_body", "embed_response_path", "embed_model", "embed_context",
+    "missing_index_types",
+    "summaries", "summary_model",
+    "skip_dirs", "structure_only_dirs", "include", "exclude", "max_entity_depth", "max_chunks_per_file",
+    "exclude_kinds",
+];
+
+// Environment variable of an option: its upper-case name, with a prefix for
+// names other tools set, such as INCLUDE in MSVC developer shells
+fn env_var(key: &str) -> String {
+    match key {
+        "include" | "exclude" => format!("CODEBASE_INDEX_{}", key.to_uppercase()),
+        _ => key.to_uppercase(),
+    }
+}
+
+// Folder in the indexed directory with indexing settings checked into the repository
+const REPO_CONFIG_DIR: &str = ".codebaseindex";
+
+// Options the repository config cannot set: those about a single invocation
+// rather than how a repository is indexed, those choosing where the code and the
+// EMBED_API_KEY token are sent, which an untrusted repository could point
+// elsewhere, and the query names, which it could map to queries that delete
+const RUN_ONLY_KEYS: &[&str] = &[
+    "path", "port", "dry_run", "resume", "clean", "repair", "files_from", "since", "inspect", "manifest", "action", "query", "top_k",
+    "profile",
+    "helix_host", "embed_provider", "embed_url", "embed_body", "embed_response_path", "embed_model",
+    "helix_endpoints",
+];
+
+// Build output, caches and environments that are rarely worth indexing
+const DEFAULT_SKIP_DIRS: &[&str] = &[
+    // Python
+    "__pycache__", ".venv", "venv", ".tox", ".mypy_cache", ".pytest_cache",
+    // JavaScript and TypeScript
+    "node_modules", "dist",
+    // Rust
+    "target",
+    // Zig
+    "zig-cache", ".zig-cache", "zig-out",
+    // C and C++, and Gradle for Kotlin
+    "build", "cmake-build-debug", "cmake-build-release",
+    // Swift
+    "Pods", "DerivedData",
+];
+
+// Task types accepted by the Gemini embedding API
+const TASK_TYPES: &[&str] = &[
+    "SEMANTIC_SIMILARITY", "CLASSIFICATION", "CLUSTERING",
+    "RETRIEVAL_DOCUMENT", "RETRIEVAL_QUERY", "CODE_RETRIEVAL_QUERY",
+    "QUESTION_ANSWERING", "FACT_VERIFICATION",
+];
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            path: ".".to_string(),
+            helix_host: "localhost".to_string(),
+            port: 6969,
+            root_name: None,
+            dry_run: false,
+            resume: false,
+            clean: false,
+            repair: false,
+            files_from: None,
+            since: None,
+            inspect: None,
+            action: None,
+            query: None,
+            top_k: 10,
+            manifest: None,
+            profile: false,
+            index_types_path: None,
+            file_types_path: None,
+            concurrency: 64,
+            concurrency_embeddings: 100,
+            helix_max_inflight: 100,
+            embed_rpm: NonZeroU32::new(4000).unwrap(),
+            helix_rpm: NonZeroU32::new(6000).unwrap(),
+            embed_retries: 3,
+            embed_timeout: 30,
+            helix_timeout: 90,
+            compress_requests: false,
+            embed_pool_size: 3000,
+            helix_pool_size: 500,
+            helix_endpoints: HashMap::new(),
+            embed_provider: EmbedProvider::Gemini,
+            embed_url: None,
+            embed_body: json!({ "input": "{text}" }),
+            embed_response_path: "data.0.embedding".to_string(),
+            embed_model: None,
+            embed_context: None,
+            embed_dimensions: None,
+            embed_price_per_million: None,
+            oversized_chunks: OversizedChunks::Split,
+            summaries: Summaries::Off,
+            summary_model: "gemini-2.5-flash-lite".to_string(),
+            document_task_type: "RETRIEVAL_DOCUMENT".to_string(),
+            query_task_type: "RETRIEVAL_QUERY".to_string(),
+            log_level: None,
+            follow_symlinks: false,
+            max_entity_depth: 8,
+            exclude_kinds: Vec::new(),
+            max_chunks_per_file: 1000,
+            skip_dirs: DEFAULT_SKIP_DIRS.iter().map(|dir| dir.to_string()).collect(),
+            structure_only_dirs: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            omit_entity_text: false,
+            dedup: false,
+            normalize_embeddings: false,
+            deterministic: false,
+            file_level: false,
+            with_blame: false,
+            missing_index_types: MissingIndexTypes::Skip,
+        }
+    }
+}
+
+impl Config {
+    // Build config from environment variables, then `<path> <port>` positional
+    // arguments and `--option value` flags, which take precedence. Without a
+    // path the current directory is indexed. Options set by neither are taken
+    // from the indexed folder's .codebaseindex/config.toml, if there is one.
+    pub fn load(args: &[String]) -> Result<Config, IndexError> {
+        Config::load_args(args, None).map_err(|e| IndexError::Config(e.to_string()))
+    }
+
+    // Build config from the same arguments for another folder, in place of the
+    // path given on the command line
+    pub fn load_for_path(args: &[String], path: &str) -> Result<Config, IndexError> {
+        Config::load_args(args, Some(path)).map_err(|e| IndexError::Config(e.to_string()))
+    }
+
+    fn load_args(args: &[String], path: Option<&str>) -> Result<Config> {
+        let mut config = Config::default();
+        // Options set in the environment or on the command line
+        let mut set_keys = HashSet::new();
+
+        for key in ENV_KEYS {
+            if let Ok(value) = env::var(env_var(key)) {
+                config.set_option(key, &value)?;
+                set_keys.insert(key.to_string());
+            }
+        }
+
+        let mut positional = Vec::new();
+        let mut args_iter = args.iter().skip(1);
+        while let Some(arg) = args_iter.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                positional.push(arg.clone());
+                continue;
+            };
+
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key.replace('-', "_"), value.to_string()),
+                None => {
+                    let key = option.replace('-', "_");
+                    if FLAGS.contains(&key.as_str()) {
+                        (key, "true".to_string())
+                    } else {
+                        let value = args_iter.next()
+                            .ok_or_else(|| anyhow::anyhow!("Missing value for --{}", option))?;
+                        (key, value.clone())
+                    }
+                }
+            };
+            config.set_option(&key, &value)?;
+            set_keys.insert(key);
+        }
+
+        if let Some(path) = positional.first() {
+            config.path = path.clone();
+        }
+        if let Some(path) = path {
+            config.path = path.to_string();
+        }
+        if let Some(port) = positional.get(1) {
+            config.port = port.parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid port: {}", port))?;
+        }
+
+        if config.action == Some(Action::Search) && config.query.is_none() {
+            return Err(anyhow::anyhow!("--action search needs --query <text>"));
+        }
+
+        config.apply_repo_config(&set_keys)?;
+        config.check_dimensions()?;
+        Ok(config)
+    }
+
+    // The range of vector lengths Gemini returns; other providers take any length.
+    // Checked once every option is set, since the provider may be given after the length.
+    fn check_dimensions(&self) -> Result<()> {
+        match self.embed_dimensions {
+            Some(dimensions) if self.embed_provider == EmbedProvider::Gemini && !GEMINI_DIMENSIONS.contains(&dimensions) => Err(anyhow::anyhow!(
+                "embed_dimensions must be between {} and {} with the gemini provider",
+                GEMINI_DIMENSIONS.start(), GEMINI_DIMENSIONS.end()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Apply the .codebaseindex folder of the indexed directory under options that
+    // are already set: config.toml sets options by their snake_case or kebab-case
+    // name, and index-types.json and file_types.json there are used when no other
+    // location is configured. Relative paths in config.toml are taken from the
+    // indexed directory.
+    fn apply_repo_config(&mut self, set_keys: &HashSet<String>) -> Result<()> {
+        let root = PathBuf::from(&self.path);
+        let dir = root.join(REPO_CONFIG_DIR);
+        let file = dir.join("config.toml");
+        if file.is_file() {
+            let contents = fs::read_to_string(&file)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file.display(), e))?;
+            let table: toml::Table = contents.parse()
+                .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", file.display(), e))?;
+            for (key, value) in table {
+                let key = key.replace('-', "_");
+                if RUN_ONLY_KEYS.contains(&key.as_str()) {
+                    return Err(anyhow::anyhow!("{} cannot be set in {}", key, file.display()));
+                }
+                if set_keys.contains(&key) {
+                    continue;
+                }
+                let mut value = toml_value(&key, &value)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", file.display(), e))?;
+                if matches!(key.as_str(), "index_types_path" | "file_types_path") {
+                    value = root.join(value).to_string_lossy().to_string();
+                }
+                self.set_option(&key, &value).map_err(|e| anyhow::anyhow!("{}: {}", file.display(), e))?;
+            }
+        }
+
+        if self.index_types_path.is_none() && dir.join("index-types.json").is_file() {
+            self.index_types_path = Some(dir.join("index-types.json"));
+        }
+        if self.file_types_path.is_none() && dir.join("file_types.json").is_file() {
+            self.file_types_path = Some(dir.join("file_types.json"));
+        }
+        Ok(())
+    }
+
+    // Name of a Helix query in the deployment, as mapped by helix_endpoints
+    pub fn endpoint<'a>(&'a self, name: &'a str) -> &'a str {
+        self.helix_endpoints.get(name).map_or(name, String::as_str)
+    }
+
+    // Name of the root for a folder, the configured name or else the folder name
+    pub fn root_name(&self, root_path: &Path) -> String {
+        self.root_name.clone().unwrap_or_else(|| {
+            // Resolve paths such as "." to the folder they point at
+            let root_path = root_path.canonicalize().unwrap_or_else(|_| root_path.to_path_buf());
+            root_path.file_name().unwrap_or_default().to_string_lossy().to_string()
+        })
+    }
+
+    // Check that path is a folder that can be indexed, and that the root has a name
+    pub fn check_path(&self) -> Result<(), IndexError> {
+        let path = Path::new(&self.path);
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(IndexError::Config(format!("{} is a file, not a folder; pass the folder to index", path.display()))),
+            Err(e) => return Err(IndexError::Config(format!("Cannot open {}: {}; pass the folder to index", path.display(), e))),
+        }
+        if self.root_name(path).is_empty() {
+            return Err(IndexError::Config(format!("{} has no folder name to call the root by; pass --root-name", path.display())));
+        }
+        Ok(())
+    }
+
+    // Set a single option by its snake_case name
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), IndexError> {
+        self.set_option(key, value).map_err(|e| IndexError::Config(e.to_string()))
+    }
+
+    fn set_option(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "path" => self.path = value.to_string(),
+            "helix_host" if value.trim().is_empty() => return Err(anyhow::anyhow!("helix_host must not be empty")),
+            "helix_host" => self.helix_host = value.trim().to_string(),
+            "port" => self.port = parse_value(key, value)?,
+            "root_name" if value.trim().is_empty() => return Err(anyhow::anyhow!("root_name must not be empty")),
+            "root_name" => self.root_name = Some(value.trim().to_string()),
+            "dry_run" => self.dry_run = parse_bool(key, value)?,
+            "resume" => self.resume = parse_bool(key, value)?,
+            "clean" => self.clean = parse_bool(key, value)?,
+            "repair" => self.repair = parse_bool(key, value)?,
+            "files_from" => self.files_from = Some(value.to_string()),
+            "since" => self.since = Some(value.to_string()),
+            "inspect" => self.inspect = Some(value.to_string()),
+            "action" => self.action = Some(parse_action(key, value)?),
+            "query" if value.trim().is_empty() => return Err(anyhow::anyhow!("query must not be empty")),
+            "query" => self.query = Some(value.trim().to_string()),
+            "top_k" => self.top_k = parse_limit(key, value)?,
+            "manifest" => self.manifest = Some(PathBuf::from(value)),
+            "profile" => self.profile = parse_bool(key, value)?,
+            "index_types_path" => self.index_types_path = Some(PathBuf::from(value)),
+            "file_types_path" => self.file_types_path = Some(PathBuf::from(value)),
+            "concurrency" => self.concurrency = parse_limit(key, value)?,
+            "concurrency_embeddings" => self.concurrency_embeddings = parse_limit(key, value)?,
+            "helix_max_inflight" => self.helix_max_inflight = parse_limit(key, value)?,
+            "embed_rpm" => self.embed_rpm = parse_value(key, value)?,
+            "helix_rpm" => self.helix_rpm = parse_value(key, value)?,
+            "embed_retries" => self.embed_retries = parse_retries(key, value)?,
+            "embed_timeout" => self.embed_timeout = parse_limit(key, value)? as u64,
+            "helix_timeout" => self.helix_timeout = parse_limit(key, value)? as u64,
+            "compress_requests" => self.compress_requests = parse_bool(key, value)?,
+            "embed_pool_size" => self.embed_pool_size = parse_value(key, value)?,
+            "helix_pool_size" => self.helix_pool_size = parse_value(key, value)?,
+            "helix_endpoints" => self.helix_endpoints = parse_endpoints(key, value)?,
+            "embed_provider" => self.embed_provider = parse_embed_provider(key, value)?,
+            "embed_url" => self.embed_url = Some(value.trim().to_string()),
+            "embed_body" => self.embed_body = parse_embed_body(key, value)?,
+            "embed_response_path" => self.embed_response_path = value.trim().to_string(),
+            "embed_model" => self.embed_model = Some(value.trim().to_string()).filter(|model| !model.is_empty()),
+            "embed_context" => self.embed_context = parse_embed_context(key, value)?,
+            "embed_dimensions" => self.embed_dimensions = Some(parse_limit(key, value)?),
+            "embed_price_per_million" => self.embed_price_per_million = Some(parse_price(key, value)?),
+            "oversized_chunks" => self.oversized_chunks = parse_oversized_chunks(key, value)?,
+            "summaries" => self.summaries = parse_summaries(key, value)?,
+            "summary_model" if value.trim().is_empty() => return Err(anyhow::anyhow!("summary_model must not be empty")),
+            "summary_model" => self.summary_model = value.trim().to_string(),
+            "document_task_type" => self.document_task_type = parse_task_type(key, value)?,
+            "query_task_type" => self.query_task_type = parse_task_type(key, value)?,
+            "log_level" => self.log_level = Some(value.to_string()),
+            "follow_symlinks" => self.follow_symlinks = parse_bool(key, value)?,
+            "max_entity_depth" => self.max_entity_depth = parse_limit(key, value)?,
+            "exclude_kinds" => self.exclude_kinds = parse_kinds(value),
+            "max_chunks_per_file" => self.max_chunks_per_file = parse_limit(key, value)?,
+            "skip_dirs" => self.skip_dirs = parse_dirs(value),
+            "structure_only_dirs" => self.structure_only_dirs = parse_dirs(value),
+            "include" => self.include = parse_globs(key, value)?,
+            "exclude" => self.exclude = parse_globs(key, value)?,
+            "omit_entity_text" => self.omit_entity_text = parse_bool(key, value)?,
+            "dedup" => self.dedup = parse_bool(key, value)?,
+            "normalize_embeddings" => self.normalize_embeddings = parse_bool(key, value)?,
+            "deterministic" => self.deterministic = parse_bool(key, value)?,
+            "file_level" => self.file_level = parse_bool(key, value)?,
+            "with_blame" => self.with_blame = parse_bool(key, value)?,
+            "missing_index_types" => self.missing_index_types = parse_missing_index_types(key, value)?,
+            _ => return Err(anyhow::anyhow!("Unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Option value from config.toml in the form Config::set takes; lists become
+// comma separated values
+fn toml_value(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Array(values) => values.iter()
+            .map(|value| match value {
+                toml::Value::Array(_) | toml::Value::Table(_) => Err(anyhow::anyhow!("Invalid value for {}: nested lists and tables are not supported", key)),
+                value => toml_value(key, value),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|values| values.join(",")),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}", key, value)),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.trim().parse::<T>()
+        .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", key, value))
+}
+
+// Concurrency limits must be at least 1
+fn parse_limit(key: &str, value: &str) -> Result<usize> {
+    match parse_value::<usize>(key, value)? {
+        0 => Err(anyhow::anyhow!("{} must be at least 1", key)),
+        limit => Ok(limit),
+    }
+}
+
+// Retries are capped, since each waits up to 30 seconds
+const MAX_RETRIES: u32 = 10;
+
+fn parse_retries(key: &str, value: &str) -> Result<u32> {
+    match parse_value::<u32>(key, value)? {
+        retries if retries <= MAX_RETRIES => Ok(retries),
+        _ => Err(anyhow::anyhow!("{} must be at most {}", key, MAX_RETRIES)),
+    }
+}
+
+// gemini-embedding-001 returns between 128 and 3072 dimensions
+const GEMINI_DIMENSIONS: std::ops::RangeInclusive<usize> = 128..=3072;
+
+fn parse_price(key: &str, value: &str) -> Result<f64> {
+    match parse_value::<f64>(key, value)? {
+        price if price.is_finite() && price >= 0.0 => Ok(price),
+        _ => Err(anyhow::anyhow!("{} must be a non-negative number", key)),
+    }
+}
+
+fn parse_summaries(key: &str, value: &str) -> Result<Summaries> {
+    match value.trim().to_lowercase().as_str() {
+        "off" => Ok(Summaries::Off),
+        "alongside" => Ok(Summaries::Alongside),
+        "instead" => Ok(Summaries::Instead),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}, expected off, alongside or instead", key, value)),
+    }
+}
+
+fn parse_task_type(key: &str, value: &str) -> Result<String> {
+    let task_type = value.trim().to_uppercase();
+    if !TASK_TYPES.contains(&task_type.as_str()) {
+        return Err(anyhow::anyhow!("Invalid value for {}: {}, expected one of {}", key, value, TASK_TYPES.join(", ")));
+    }
+    Ok(task_type)
+}
+
+fn parse_oversized_chunks(key: &str, value: &str) -> Result<OversizedChunks> {
+    match value.trim().to_lowercase().as_str() {
+        "split" => Ok(OversizedChunks::Split),
+        "truncate" => Ok(OversizedChunks::Truncate),
+        "skip" => Ok(OversizedChunks::Skip),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}, expected split, truncate or skip", key, value)),
+    }
+}
+
+fn parse_action(key: &str, value: &str) -> Result<Action> {
+    match value.trim().to_lowercase().as_str() {
+        "ingest" => Ok(Action::Ingest),
+        "update" => Ok(Action::Update),
+        "search" => Ok(Action::Search),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}, expected ingest, update or search", key, value)),
+    }
+}
+
+fn parse_embed_provider(key: &str, value: &str) -> Result<EmbedProvider> {
+    match value.trim().to_lowercase().as_str() {
+        "gemini" => Ok(EmbedProvider::Gemini),
+        "http" => Ok(EmbedProvider::Http),
+        "mock" => Ok(EmbedProvider::Mock),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}, expected gemini, http or mock", key, value)),
+    }
+}
+
+// JSON request body template, which must send the text somewhere
+fn parse_embed_body(key: &str, value: &str) -> Result<Value> {
+    let body: Value = serde_json::from_str(value)
+        .map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?;
+    if !body.to_string().contains("{text}") {
+        return Err(anyhow::anyhow!("{} must contain a \"{{text}}\" placeholder", key));
+    }
+    Ok(body)
+}
+
+// Template for embedded code, which must include the code; empty to embed chunks alone
+fn parse_embed_context(key: &str, value: &str) -> Result<Option<String>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    if !value.contains("{code}") {
+        return Err(anyhow::anyhow!("{} must contain a \"{{code}}\" placeholder", key));
+    }
+    // Lets `\n` be written in environment variables and on the command line
+    Ok(Some(value.replace("\\n", "\n")))
+}
+
+fn parse_missing_index_types(key: &str, value: &str) -> Result<MissingIndexTypes> {
+    match value.trim().to_lowercase().as_str() {
+        "skip" => Ok(MissingIndexTypes::Skip),
+        "all" => Ok(MissingIndexTypes::All),
+        "file-level" | "file_level" => Ok(MissingIndexTypes::FileLevel),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}, expected skip, all or file-level", key, value)),
+    }
+}
+
+// Comma separated globs; commas inside braces such as `*.{rs,py}` belong to the glob
+fn parse_globs(key: &str, value: &str) -> Result<Vec<String>> {
+    let mut globs = Vec::new();
+    let mut glob = String::new();
+    let mut depth = 0usize;
+    for c in value.chars() {
+        match c {
+            ',' if depth == 0 => globs.push(std::mem::take(&mut glob)),
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if c != ',' || depth > 0 {
+            glob.push(c);
+        }
+    }
+    globs.push(glob);
+    let globs: Vec<String> = globs.iter()
+        .map(|glob| glob.trim())
+        .filter(|glob| !glob.is_empty())
+        .map(String::from)
+        .collect();
+    walk::glob_set(&globs).map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?;
+    Ok(globs)
+}
+
+// Comma separated directory names or paths, e.g. "tests,docs/generated/"
+fn parse_dirs(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|dir| dir.trim().trim_end_matches('/'))
+        .filter(|dir| !dir.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Query names as name=deployed_name pairs, e.g. getRoot=listRoots,createRoot=addRoot
+fn parse_endpoints(key: &str, value: &str) -> Result<HashMap<String, String>> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, deployed) = pair.split_once('=')
+                .map(|(name, deployed)| (name.trim(), deployed.trim()))
+                .filter(|(_, deployed)| !deployed.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Invalid value for {}: {} is not name=query", key, pair))?;
+            if !endpoints::ALL.contains(&name) {
+                return Err(anyhow::anyhow!("Invalid value for {}: the indexer does not call a query named {}", key, name));
+            }
+            Ok((name.to_string(), deployed.to_string()))
+        })
+        .collect()
+}
+
+fn parse_kinds(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|kind| !kind.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(anyhow::anyhow!("Invalid value for {}: {}", key, value)),
+    }
+}
+
+// Global config, set once at startup
+lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+// Whether a config has been set since the process started
+static CONFIG_SET: AtomicBool = AtomicBool::new(false);
+
+pub fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+// Replace the global config, returning whether an earlier one was replaced
+pub fn set_config(config: Config) -> bool {
+    *CONFIG.write().unwrap() = Arc::new(config);
+    CONFIG_SET.swap(true, Ordering::SeqCst)
+}
+
+// index-types.json and file_types.json, loaded once on first use.
+// The copies in src/ are compiled in as defaults; an external file
+// replaces the default entry for each top-level key it defines.
+static INDEX_TYPES: OnceLock<Arc<Value>> = OnceLock::new();
+static FILE_TYPES: OnceLock<Arc<Value>> = OnceLock::new();
+
+const DEFAULT_INDEX_TYPES: &str = include_str!("index-types.json");
+const DEFAULT_FILE_TYPES: &str = include_str!("file_types.json");
+
+pub fn index_types() -> Result<Arc<Value>> {
+    load_once(&INDEX_TYPES, "index-types.json", DEFAULT_INDEX_TYPES, config().index_types_path.as_deref())
+        .map_err(|e| IndexError::Config(e.to_string()).into())
+}
+
+// file_types.json, whose "supported" and "unsupported" entries must be lists of extensions
+pub fn file_types() -> Result<Arc<Value>> {
+    let file_types = load_once(&FILE_TYPES, "file_types.json", DEFAULT_FILE_TYPES, config().file_types_path.as_deref())
+        .map_err(|e| anyhow::Error::from(IndexError::Config(e.to_string())))?;
+    for key in ["supported", "unsupported"] {
+        let listed = file_types.get(key).and_then(Value::as_array)
+            .is_some_and(|extensions| extensions.iter().all(Value::is_string));
+        if !listed {
+            return Err(IndexError::Config(format!("\"{}\" in file_types.json must be a list of extensions", key)).into());
+        }
+    }
+    Ok(file_types)
+}
+
+fn load_once(cell: &OnceLock<Arc<Value>>, file_name: &str, default: &str, configured: Option<&Path>) -> Result<Arc<Value>> {
+    if let Some(value) = cell.get() {
+        return Ok(value.clone());
+    }
+
+    let mut value: Value = serde_json::from_str(default)
+        .map_err(|e| anyhow::anyhow!("Built-in {} is invalid: {}", file_name, e))?;
+
+    let external = match configured {
+        Some(path) if !path.is_file() => {
+            return Err(anyhow::anyhow!("{} not found at {}", file_name, path.display()));
+        }
+        Some(path) => Some(path.to_path_buf()),
+        None => default_locations(file_name).into_iter().find(|path| path.is_file()),
+    };
+
+    if let Some(path) = external {
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let overrides: Value = serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+        let (Some(base), Value::Object(overrides)) = (value.as_object_mut(), overrides) else {
+            return Err(anyhow::anyhow!("{} must contain a JSON object", path.display()));
+        };
+        base.extend(overrides);
+    }
+
+    Ok(cell.get_or_init(|| Arc::new(value)).clone())
+}
+
+// Working directory, its src/ folder, then next to the executable
+fn default_locations(file_name: &str) -> Vec<PathBuf> {
+    let mut locations = vec![
+        PathBuf::from(file_name),
+        Path::new("src").join(file_name),
+    ];
+    if let Some(exe_dir) = env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)) {
+        locations.push(exe_dir.join(file_name));
+    }
+    locations
+}
@@ -0,0 +1,49 @@
+// Names of the Helix queries the indexer calls, as defined in db/queries.hx. A
+// deployment that names its queries differently maps these names to its own with
+// helix_endpoints, which helix_url applies.
+
+pub const CREATE_ROOT: &str = "createRoot";
+pub const CREATE_SUPER_FOLDER: &str = "createSuperFolder";
+pub const CREATE_SUB_FOLDER: &str = "createSubFolder";
+pub const CREATE_SUPER_FILE: &str = "createSuperFile";
+pub const CREATE_FILE: &str = "createFile";
+pub const LINK_FILE_ENTITIES: &str = "linkFileEntities";
+pub const CREATE_SUPER_ENTITY: &str = "createSuperEntity";
+pub const EMBED_SUPER_ENTITY: &str = "embedSuperEntity";
+pub const CREATE_SUB_ENTITY: &str = "createSubEntity";
+pub const GET_ROOT: &str = "getRoot";
+pub const GET_ROOT_BY_ID: &str = "getRootById";
+pub const GET_ROOT_BY_NAME: &str = "getRootByName";
+pub const GET_FOLDER: &str = "getFolder";
+pub const GET_ROOT_FOLDERS: &str = "getRootFolders";
+pub const GET_SUB_FOLDERS: &str = "getSubFolders";
+pub const GET_ROOT_FILES: &str = "getRootFiles";
+pub const GET_FOLDER_FILES: &str = "getFolderFiles";
+pub const GET_FILE_CONTENT: &str = "getFileContent";
+pub const GET_FILE_ENTITIES: &str = "getFileEntities";
+pub const GET_ENTITY_FILE: &str = "getEntityFile";
+pub const SEARCH_SUPER_ENTITY: &str = "searchSuperEntity";
+pub const GET_SUB_ENTITIES: &str = "getSubEntities";
+pub const COUNT_ENTITY_EMBEDDINGS: &str = "countEntityEmbeddings";
+pub const GET_ENTITY_EMBEDDINGS: &str = "getEntityEmbeddings";
+pub const UPDATE_ROOT_EMBEDDING: &str = "updateRootEmbedding";
+pub const UPDATE_FILE: &str = "updateFile";
+pub const UPDATE_FOLDER_HASH: &str = "updateFolderHash";
+pub const UPDATE_ENTITY_SUMMARY: &str = "updateEntitySummary";
+pub const UPDATE_ENTITY_POSITION: &str = "updateEntityPosition";
+pub const DELETE_ROOT: &str = "deleteRoot";
+pub const DELETE_FOLDER: &str = "deleteFolder";
+pub const DELETE_FILE: &str = "deleteFile";
+pub const DELETE_EMBEDDINGS: &str = "deleteEmbeddings";
+pub const DELETE_ENTITIES: &str = "deleteEntities";
+
+// Every query the indexer calls, the names helix_endpoints may map
+pub const ALL: &[&str] = &[
+    CREATE_ROOT, CREATE_SUPER_FOLDER, CREATE_SUB_FOLDER, CREATE_SUPER_FILE, CREATE_FILE,
+    LINK_FILE_ENTITIES, CREATE_SUPER_ENTITY, EMBED_SUPER_ENTITY, CREATE_SUB_ENTITY, GET_ROOT,
+    GET_ROOT_BY_ID, GET_ROOT_BY_NAME, GET_FOLDER, GET_ROOT_FOLDERS, GET_SUB_FOLDERS, GET_ROOT_FILES,
+    GET_FOLDER_FILES, GET_FILE_CONTENT, GET_FILE_ENTITIES, GET_ENTITY_FILE, SEARCH_SUPER_ENTITY,
+    GET_SUB_ENTITIES, COUNT_ENTITY_EMBEDDINGS, GET_ENTITY_EMBEDDINGS, UPDATE_ROOT_EMBEDDING, UPDATE_FILE,
+    UPDATE_FOLDER_HASH, UPDATE_ENTITY_SUMMARY, UPDATE_ENTITY_POSITION, DELETE_ROOT, DELETE_FOLDER,
+    DELETE_FILE, DELETE_EMBEDDINGS, DELETE_ENTITIES,
+];
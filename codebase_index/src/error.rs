@@ -0,0 +1,61 @@
+use std::io;
+use thiserror::Error;
+
+// Errors returned by Indexer and Config, for callers that handle failures by kind.
+// Internally errors are anyhow::Error; the kinds below are created where the
+// failure happens and recovered from the anyhow error at the API boundary.
+#[derive(Debug, Error)]
+pub enum IndexError {
+    // No response from Helix: it is not running, the host or port is wrong, or it timed out
+    #[error("{0}")]
+    HelixUnreachable(String),
+    // Helix answered with an error status, or with an error in its response body
+    #[error("Helix {endpoint} failed ({status}): {body}")]
+    HelixError { endpoint: String, status: u16, body: String },
+    // The embedding API or server failed, or returned no usable vector
+    #[error("{0}")]
+    EmbedError(String),
+    // tree-sitter could not parse a file
+    #[error("{0}")]
+    ParseError(String),
+    #[error("{message}")]
+    Io { message: String, source: io::Error },
+    // Invalid options or configuration files, or settings that do not match the index
+    #[error("{0}")]
+    Config(String),
+    // The root id passed in is not in the index
+    #[error("No root found with id {0}")]
+    RootNotFound(String),
+    // Any other failure, such as a Helix response missing what the queries return
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl IndexError {
+    // Whether the request that failed is worth retrying
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            IndexError::HelixUnreachable(_) => true,
+            IndexError::HelixError { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+// Recover the kind of an internal error: an IndexError it was created as, even
+// under added context, or an I/O error somewhere in its chain
+impl From<anyhow::Error> for IndexError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<IndexError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        match error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()) {
+            Some(cause) => IndexError::Io {
+                message: error.to_string(),
+                source: io::Error::new(cause.kind(), cause.to_string()),
+            },
+            None => IndexError::Other(error),
+        }
+    }
+}
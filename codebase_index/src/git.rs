@@ -0,0 +1,114 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Files under root that were added, changed, deleted or renamed since a git ref,
+// relative to root. Uncommitted changes and untracked files that are not ignored
+// count too. A rename lists both paths, so the old file is deleted and the new one created.
+pub fn changed_files(root: &Path, since: &str) -> Result<Vec<PathBuf>> {
+    let diff = git(root, &["diff", "--name-status", "-z", "--relative", since, "--"])?;
+    let mut files = Vec::new();
+    let mut fields = diff.split('\0').filter(|field| !field.is_empty());
+    while let Some(status) = fields.next() {
+        // Renames and copies are followed by the old and the new path
+        let paths = if status.starts_with('R') || status.starts_with('C') { 2 } else { 1 };
+        for _ in 0..paths {
+            let path = fields.next()
+                .ok_or_else(|| anyhow::anyhow!("Unexpected git diff output after status {}", status))?;
+            files.push(PathBuf::from(path));
+        }
+    }
+
+    let untracked = git(root, &["ls-files", "-z", "--others", "--exclude-standard"])?;
+    files.extend(untracked.split('\0').filter(|path| !path.is_empty()).map(PathBuf::from));
+    Ok(files)
+}
+
+// Author and commit that last changed some lines of a file
+#[derive(Debug, Clone, Default)]
+pub struct Blame {
+    pub author: String,
+    pub commit: String,
+    // Author time of the commit, in seconds since the Unix epoch
+    pub committed_at: i64,
+}
+
+// git blame of a file, with the byte offset each line starts at in its source
+pub struct FileBlame {
+    line_starts: Vec<usize>,
+    // Index into commits of the commit that last changed each line, None for uncommitted lines
+    lines: Vec<Option<usize>>,
+    commits: Vec<Blame>,
+}
+
+impl FileBlame {
+    // Blame the lines of a file as committed, mapped onto its source as read.
+    // git runs on a blocking thread, so files being blamed do not hold up the
+    // runtime's workers and the Helix and embedding requests they drive.
+    pub async fn new(path: &Path, source: &str) -> Result<FileBlame> {
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let output = tokio::task::spawn_blocking(move || git(&dir, &["blame", "--line-porcelain", "--", name.as_str()])).await??;
+
+        let mut commits: Vec<Blame> = Vec::new();
+        let mut lines = Vec::new();
+        let mut current = Blame::default();
+        for line in output.lines() {
+            // Each line of the file ends its block of commit fields
+            if line.starts_with('\t') {
+                if current.commit.bytes().all(|byte| byte == b'0') {
+                    lines.push(None);
+                    continue;
+                }
+                let index = commits.iter().position(|commit| commit.commit == current.commit).unwrap_or_else(|| {
+                    commits.push(current.clone());
+                    commits.len() - 1
+                });
+                lines.push(Some(index));
+            } else if let Some(author) = line.strip_prefix("author ") {
+                current.author = author.to_string();
+            } else if let Some(time) = line.strip_prefix("author-time ") {
+                current.committed_at = time.parse().unwrap_or_default();
+            } else if let Some(commit) = line.split(' ').next().filter(|field| is_commit_hash(field)) {
+                current.commit = commit.to_string();
+            }
+        }
+
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Ok(FileBlame { line_starts, lines, commits })
+    }
+
+    // Most recent commit among the lines of a byte range, None when none of them is committed
+    pub fn range(&self, start_byte: usize, end_byte: usize) -> Option<&Blame> {
+        let line = |byte: usize| self.line_starts.partition_point(|start| *start <= byte).saturating_sub(1);
+        let first = line(start_byte);
+        let last = line(end_byte.saturating_sub(1).max(start_byte));
+        self.lines.get(first..=last.min(self.lines.len().saturating_sub(1)))?
+            .iter()
+            .flatten()
+            .map(|index| &self.commits[*index])
+            .max_by_key(|commit| commit.committed_at)
+    }
+}
+
+// Full SHA-1 or, in repositories using SHA-256, SHA-256 commit hash
+fn is_commit_hash(field: &str) -> bool {
+    matches!(field.len(), 40 | 64) && field.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
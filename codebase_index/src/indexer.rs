@@ -0,0 +1,715 @@
+use anyhow::Result;
+use tracing::{error, warn};
+use futures::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::endpoints;
+use crate::checkpoint;
+use crate::git;
+use crate::config::{config, file_types, index_types, set_config, Config};
+use crate::error::IndexError;
+use crate::ingestion::{ingestion, inspect_file, Inspection};
+use crate::queries::get_root_id_by_name;
+use crate::report::{Report, Stage};
+use crate::reembed;
+use crate::stats::{self, RootStats};
+use crate::run::{QueuedJob, Run};
+use crate::updater::{self, DryRunCounts};
+use crate::watch;
+use crate::utils::{
+    delete_embeddings, delete_root, embed_entity_async, helix_url, post_request_async, summarize_entity_async, embedding_model, embedding_provider, ping_helix,
+    retry_transient, retry_unsent, EmbeddingJob,
+};
+
+// Seconds a file's mtime may be ahead of its extraction time before update
+// re-indexes it; only files indexed before content hashes were stored are
+// compared by mtime. Nothing waits on it between runs.
+const MTIME_TOLERANCE: u64 = 5;
+
+// Embedding jobs buffered between file processing and the embedding worker
+const CHANNEL_BUFFER_SIZE: usize = 1000;
+
+// Time queued embeddings get to finish once a run is stopped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Result of an ingest or update run, once every embedding has finished
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub root_id: String,
+    // Files walked, and entities written to Helix
+    pub files: usize,
+    pub entities: usize,
+    pub chunks: usize,
+    pub embeddings: usize,
+    pub embeddings_failed: usize,
+    // Estimated tokens sent for embedding, and their cost when a price is configured
+    pub tokens: usize,
+    pub estimated_cost: Option<f64>,
+    // Chunks over the embedding input limit, handled as set by oversized_chunks
+    pub oversized_chunks: usize,
+    // Time from the start of the run until every embedding finished
+    pub elapsed: Duration,
+    pub report: Report,
+    // Set for dry run updates
+    pub dry_run: Option<DryRunCounts>,
+    // The run was stopped before every file was indexed
+    pub interrupted: bool,
+    // Set for verify runs
+    pub verify: Option<VerifyCounts>,
+    // Set for ingests with clean that completed
+    pub clean: Option<CleanCounts>,
+}
+
+// Embedded entities of a root found by verify
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VerifyCounts {
+    // Entities with at least one embedding
+    pub ok: usize,
+    // Entities without any, and how many of them were embedded by repair
+    pub missing: usize,
+    pub repaired: usize,
+}
+
+// Roots of the same name an ingest with clean replaced, by id, with what was
+// indexed under them, and what is indexed under the new root
+#[derive(Debug, Clone, Default)]
+pub struct CleanCounts {
+    pub replaced: Vec<(String, RootStats)>,
+    pub after: RootStats,
+    // Files or embeddings of the new root failed, so the roots were not deleted
+    pub kept: bool,
+}
+
+// Root of an indexed directory tree
+#[derive(Debug, Clone)]
+pub struct Root {
+    pub id: String,
+    pub name: String,
+    // Embedding settings the root's vectors were made with, None for roots
+    // ingested before they were recorded
+    pub embedding: Option<RootEmbedding>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootEmbedding {
+    pub provider: String,
+    pub model: String,
+    pub dimensions: usize,
+}
+
+impl RootEmbedding {
+    // Settings recorded on a root node; roots from before the provider was
+    // recorded were all embedded with Gemini
+    fn from_root(root: &Value) -> Option<RootEmbedding> {
+        let model = root.get("embedding_model").and_then(|v| v.as_str()).filter(|s| !s.is_empty())?;
+        let dimensions = root.get("embedding_dimensions").and_then(|v| v.as_u64()).filter(|d| *d > 0)?;
+        let provider = root.get("embedding_provider").and_then(|v| v.as_str()).filter(|s| !s.is_empty()).unwrap_or("gemini");
+        Some(RootEmbedding { provider: provider.to_string(), model: model.to_string(), dimensions: dimensions as usize })
+    }
+}
+
+impl fmt::Display for RootEmbedding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} at {} dimensions", self.provider, self.model, self.dimensions)
+    }
+}
+
+impl RunSummary {
+    // Embeddings completed per second over the whole run
+    pub fn embeddings_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 { self.embeddings as f64 / seconds } else { 0.0 }
+    }
+}
+
+// Snapshot of the current run, for progress display
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    // Still walking the tree and writing files to Helix
+    pub walking: bool,
+    // Files found so far and files finished; discovery runs ahead of processing
+    pub files_discovered: usize,
+    pub files_processed: usize,
+    pub chunks: usize,
+    pub embeddings_pending: usize,
+    pub embeddings_completed: usize,
+    pub embeddings_failed: usize,
+}
+
+impl Progress {
+    // Embeddings the run will have queued once every file is processed. While
+    // walking, the embeddings queued per processed file so far are extrapolated
+    // to the files discovered, so an ETA can be given from the start.
+    pub fn expected_embeddings(&self) -> usize {
+        if !self.walking || self.files_processed == 0 {
+            return self.embeddings_pending;
+        }
+        let per_file = self.embeddings_pending as f64 / self.files_processed as f64;
+        ((per_file * self.files_discovered as f64).ceil() as usize).max(self.embeddings_pending)
+    }
+}
+
+// Indexes codebases into a Helix instance.
+// Only one config per process is supported: creating an Indexer replaces the
+// config of every Indexer in the process, and the HTTP clients, rate limits,
+// concurrency limits, include and exclude globs and index-types.json and
+// file_types.json are fixed by the config of the first run.
+pub struct Indexer {
+    port: u16,
+    tx: Sender<QueuedJob>,
+    walking: AtomicBool,
+    // Run in progress, or the last one, for progress display and stopping
+    run: Mutex<Option<Arc<Run>>>,
+    // Length of the vectors returned by the embedding API, once known
+    dimensions: OnceCell<usize>,
+}
+
+impl Indexer {
+    // Apply the config and start the embedding worker.
+    // Must be called from within a tokio runtime.
+    pub fn new(config: Config) -> Indexer {
+        let port = config.port;
+        let max_concurrent_embeddings = config.concurrency_embeddings;
+        let retries = config.embed_retries;
+        if set_config(config) {
+            warn!("An Indexer was already created in this process; its config is replaced, but the clients, limits, globs and type files set up by earlier runs are kept");
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<QueuedJob>(CHANNEL_BUFFER_SIZE);
+        tokio::spawn(embed_worker(rx, max_concurrent_embeddings, retries));
+
+        Indexer { port, tx, walking: AtomicBool::new(false), run: Mutex::new(None), dimensions: OnceCell::new() }
+    }
+
+    // Check that Helix is reachable
+    pub async fn ping(&self) -> Result<(), IndexError> {
+        Ok(ping_helix(self.port, 3).await?)
+    }
+
+    // Index a directory as a new root and return once it is fully embedded. With
+    // clean, roots of the same name are deleted once the new root is complete, so
+    // searches keep using them until then; if any file or embedding of the new
+    // root failed, they are kept next to it.
+    pub async fn ingest(&self, path: impl AsRef<Path>) -> Result<RunSummary, IndexError> {
+        if config().dry_run {
+            return Err(IndexError::Config("Dry run only applies to updates".to_string()));
+        }
+        if config().clean && config().resume {
+            return Err(IndexError::Config("Clean cannot be combined with resume".to_string()));
+        }
+        let root_path = canonicalize(path.as_ref())?;
+        let dimensions = self.embedding_dimensions().await?;
+        let replaced = if config().clean {
+            self.roots_named(&config().root_name(&root_path)).await?
+        } else {
+            Vec::new()
+        };
+        let started = Instant::now();
+        let run = self.start_run();
+
+        self.walking.store(true, Ordering::SeqCst);
+        let result = ingestion(root_path.clone(), self.port, run.clone(), dimensions).await;
+        self.walking.store(false, Ordering::SeqCst);
+
+        let root_id = result.as_ref().cloned().unwrap_or_default();
+        let mut summary = self.finish_run(&run, root_id, None, started).await;
+        if let Err(e) = checkpoint::finish(summary.interrupted) {
+            error!("Failed to update checkpoint: {}", e);
+        }
+        write_manifest(&run, &root_path, &summary);
+        result?;
+        if config().clean && !summary.interrupted {
+            let kept = !summary.report.failures.is_empty() || summary.embeddings_failed > 0;
+            if kept && !replaced.is_empty() {
+                warn!(
+                    "Root {} is incomplete, with {} failures, so the {} roots of the same name are kept",
+                    summary.root_id, summary.report.failures.len(), replaced.len()
+                );
+            }
+            summary.clean = Some(self.replace_roots(replaced, &summary.root_id, kept).await?);
+        }
+        Ok(summary)
+    }
+
+    // Roots with a name, by id, with what is indexed under them
+    async fn roots_named(&self, name: &str) -> Result<Vec<(String, RootStats)>> {
+        let mut roots = Vec::new();
+        for root in self.roots().await?.into_iter().filter(|root| root.name == name) {
+            let stats = stats::root_stats(root.id.clone(), self.port).await?;
+            roots.push((root.id, stats));
+        }
+        Ok(roots)
+    }
+
+    // Delete the roots a new root replaces unless they are kept, then count what
+    // is indexed under it
+    async fn replace_roots(&self, replaced: Vec<(String, RootStats)>, root_id: &str, kept: bool) -> Result<CleanCounts> {
+        if !kept {
+            for (id, _) in &replaced {
+                delete_root(id.clone(), self.port).await
+                    .map_err(|e| anyhow::anyhow!("Ingested root {} but failed to delete the root it replaces, {}: {}", root_id, id, e))?;
+            }
+        }
+        let after = stats::root_stats(root_id.to_string(), self.port).await?;
+        Ok(CleanCounts { replaced, after, kept })
+    }
+
+    // Bring an existing root up to date with the directory. With since set, only
+    // the files git reports as changed since that ref are indexed.
+    pub async fn update(&self, path: impl AsRef<Path>, root_id: &str) -> Result<RunSummary, IndexError> {
+        let root_path = canonicalize(path.as_ref())?;
+        if let Some(since) = &config().since {
+            if config().dry_run {
+                return Err(IndexError::Config("Dry run cannot be combined with since".to_string()));
+            }
+            let files = git::changed_files(&root_path, since)?;
+            return self.index_files(root_path, root_id, files).await;
+        }
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        if !config().dry_run {
+            self.check_root_embedding(root_id).await?;
+        }
+        let started = Instant::now();
+        let run = self.start_run();
+
+        self.walking.store(true, Ordering::SeqCst);
+        let result = updater::update(
+            root_path.clone(), root_id.to_string(),
+            self.port, run.clone(), MTIME_TOLERANCE
+        ).await;
+        self.walking.store(false, Ordering::SeqCst);
+
+        let dry_run = config().dry_run.then(|| run.dry_run_counts());
+        let summary = self.finish_run(&run, root_id.to_string(), dry_run, started).await;
+        if summary.dry_run.is_none() {
+            write_manifest(&run, &root_path, &summary);
+        }
+        result?;
+        Ok(summary)
+    }
+
+    // Bring a root up to date, then keep it in sync with changes to the
+    // directory until shutdown resolves
+    pub async fn watch(
+        &self,
+        path: impl AsRef<Path>,
+        root_id: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<RunSummary, IndexError> {
+        if config().dry_run {
+            return Err(IndexError::Config("Dry run only applies to updates".to_string()));
+        }
+        let root_path = canonicalize(path.as_ref())?;
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        self.check_root_embedding(root_id).await?;
+        let started = Instant::now();
+        let run = self.start_run();
+
+        let result = async {
+            updater::update(
+                root_path.clone(), root_id.to_string(),
+                self.port, run.clone(), MTIME_TOLERANCE
+            ).await?;
+            watch::watch(root_path.clone(), root_id.to_string(), self.port, run.clone(), shutdown).await
+        }.await;
+
+        let summary = self.finish_run(&run, root_id.to_string(), None, started).await;
+        write_manifest(&run, &root_path, &summary);
+        result?;
+        Ok(summary)
+    }
+
+    // Index only the listed files of a root, creating their folders as needed.
+    // Listed files that no longer exist are removed from the index.
+    pub async fn index_files(&self, path: impl AsRef<Path>, root_id: &str, files: Vec<PathBuf>) -> Result<RunSummary, IndexError> {
+        if config().dry_run {
+            return Err(IndexError::Config("Dry run only applies to updates".to_string()));
+        }
+        let root_path = canonicalize(path.as_ref())?;
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        self.check_root_embedding(root_id).await?;
+        let started = Instant::now();
+        let run = self.start_run();
+
+        self.walking.store(true, Ordering::SeqCst);
+        let result = watch::apply_paths(root_path.clone(), root_id.to_string(), self.port, run.clone(), files).await;
+        self.walking.store(false, Ordering::SeqCst);
+
+        let summary = self.finish_run(&run, root_id.to_string(), None, started).await;
+        write_manifest(&run, &root_path, &summary);
+        result?;
+        Ok(summary)
+    }
+
+    // Embed every entity of a root again with the current embedding settings,
+    // from the text stored in Helix, e.g. after changing the model or vector
+    // length. An entity's old vectors are deleted once its new ones are stored,
+    // and the root records the new model and length once every entity is
+    // embedded; after failures or a stop, what was not replaced is kept.
+    pub async fn reembed(&self, root_id: &str) -> Result<RunSummary, IndexError> {
+        if config().dry_run {
+            return Err(IndexError::Config("Dry run only applies to updates".to_string()));
+        }
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        let dimensions = self.embedding_dimensions().await?;
+        let started = Instant::now();
+        let run = self.start_run();
+
+        self.walking.store(true, Ordering::SeqCst);
+        let result = reembed::reembed(root_id.to_string(), self.port, run.clone()).await;
+        self.walking.store(false, Ordering::SeqCst);
+
+        let summary = self.finish_run(&run, root_id.to_string(), None, started).await;
+        let old = result?;
+        // A stopped run may not have stored every new vector even of entities without failures
+        if !summary.interrupted {
+            let failed: HashSet<&str> = summary.report.failures.iter().map(|failure| failure.path.as_str()).collect();
+            let replaced: Vec<String> = old.into_iter()
+                .filter(|(entity_id, _)| !failed.contains(format!("entity {}", entity_id).as_str()))
+                .flat_map(|(_, embedding_ids)| embedding_ids)
+                .collect();
+            delete_embeddings(replaced, self.port).await?;
+        }
+        if summary.interrupted || !summary.report.failures.is_empty() {
+            warn!("Root {} keeps its recorded embedding model until every entity is re-embedded", root_id);
+            return Ok(summary);
+        }
+        let url = helix_url(self.port, endpoints::UPDATE_ROOT_EMBEDDING);
+        let payload = json!({
+            "root_id": root_id,
+            "embedding_provider": embedding_provider(),
+            "embedding_model": embedding_model(),
+            "embedding_dimensions": dimensions,
+        });
+        post_request_async(&url, payload).await?;
+        Ok(summary)
+    }
+
+    // Check that every embedded entity of a root has an embedding, e.g. after an
+    // ingestion that lost embeddings. With repair set, the entities without one
+    // are embedded; they count as repaired once all of their embeddings succeed.
+    pub async fn verify(&self, root_id: &str) -> Result<RunSummary, IndexError> {
+        if config().dry_run {
+            return Err(IndexError::Config("Dry run only applies to updates".to_string()));
+        }
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        let repair = config().repair;
+        if repair {
+            self.check_root_embedding(root_id).await?;
+        }
+        let started = Instant::now();
+        let run = self.start_run();
+
+        self.walking.store(true, Ordering::SeqCst);
+        let result = reembed::verify(root_id.to_string(), self.port, run.clone(), repair).await;
+        self.walking.store(false, Ordering::SeqCst);
+
+        let mut summary = self.finish_run(&run, root_id.to_string(), None, started).await;
+        let verification = result?;
+        let repaired = if repair && !summary.interrupted {
+            let failed: HashSet<&str> = summary.report.failures.iter().map(|failure| failure.path.as_str()).collect();
+            verification.missing.iter().filter(|id| !failed.contains(format!("entity {}", id).as_str())).count()
+        } else {
+            0
+        };
+        summary.verify = Some(VerifyCounts { ok: verification.ok, missing: verification.missing.len(), repaired });
+        Ok(summary)
+    }
+
+    // Show the entities and chunks a file would be indexed into, without Helix or embeddings
+    pub fn inspect(&self, path: impl AsRef<Path>) -> Result<Inspection, IndexError> {
+        Ok(inspect_file(path.as_ref(), &*index_types()?, &*file_types()?)?)
+    }
+
+    // Find the k entities whose code is most similar to the query
+    pub async fn search(&self, query: &str, k: usize) -> Result<Vec<Value>, IndexError> {
+        let vector = embed_entity_async(query.to_string(), &config().query_task_type).await?;
+        let url = helix_url(self.port, endpoints::SEARCH_SUPER_ENTITY);
+        let response = post_request_async(&url, json!({ "vector": vector, "k": k })).await?;
+        let entities = response
+            .get("entity")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Search response missing entities: {}", response))?;
+        Ok(entities)
+    }
+
+    // Delete a root with all of its folders, files and entities
+    pub async fn delete_root(&self, root_id: &str) -> Result<(), IndexError> {
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        Ok(delete_root(root_id.to_string(), self.port).await?)
+    }
+
+    // Count what is indexed under a root, without changing anything
+    pub async fn stats(&self, root_id: &str) -> Result<RootStats, IndexError> {
+        if !self.root_ids().await?.iter().any(|id| id == root_id) {
+            return Err(IndexError::RootNotFound(root_id.to_string()));
+        }
+        Ok(stats::root_stats(root_id.to_string(), self.port).await?)
+    }
+
+    // Every root in the index
+    pub async fn roots(&self) -> Result<Vec<Root>, IndexError> {
+        let url = helix_url(self.port, endpoints::GET_ROOT);
+        let response = post_request_async(&url, json!({})).await?;
+        let roots = response
+            .get("root")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| anyhow::anyhow!("Root ID not found"))?;
+        let roots = roots.iter().filter_map(|root| {
+            Some(Root {
+                id: root.get("id")?.as_str()?.to_string(),
+                name: root.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                embedding: RootEmbedding::from_root(root),
+            })
+        }).collect();
+        Ok(roots)
+    }
+
+    // Id of the root with a name, such as the name of the indexed folder
+    pub async fn root_id_by_name(&self, name: &str) -> Result<Option<String>, IndexError> {
+        Ok(get_root_id_by_name(name, self.port).await?)
+    }
+
+    // Ids of every root in the index
+    pub async fn root_ids(&self) -> Result<Vec<String>, IndexError> {
+        Ok(self.roots().await?.into_iter().map(|root| root.id).collect())
+    }
+
+    // Stop the current ingest or update: files not started yet are left out and
+    // queued embeddings get a short while to finish. The run then returns a
+    // summary marked as interrupted; an ingestion can be continued with resume.
+    pub fn stop(&self) {
+        if let Some(run) = self.run.lock().unwrap().as_ref() {
+            run.stop();
+        }
+    }
+
+    // Wait up to timeout for the embeddings of the last run to finish, before the
+    // process exits, and return how many are left. Runs wait for their own
+    // embeddings, so only a run stopped before they drained leaves any behind.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        let Some(run) = self.run.lock().unwrap().clone() else {
+            return 0;
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let drained = run.drained();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if run.embeddings_finished() || timeout_at(deadline, drained).await.is_err() {
+                return run.unfinished_embeddings();
+            }
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let walking = self.walking.load(Ordering::SeqCst);
+        let Some(run) = self.run.lock().unwrap().clone() else {
+            return Progress {
+                walking,
+                files_discovered: 0,
+                files_processed: 0,
+                chunks: 0,
+                embeddings_pending: 0,
+                embeddings_completed: 0,
+                embeddings_failed: 0,
+            };
+        };
+        Progress {
+            walking,
+            files_discovered: run.files_discovered.load(Ordering::SeqCst),
+            files_processed: run.files_processed.load(Ordering::SeqCst),
+            chunks: run.chunks.load(Ordering::SeqCst),
+            embeddings_pending: run.embeddings_pending.load(Ordering::SeqCst),
+            embeddings_completed: run.embeddings_completed.load(Ordering::SeqCst),
+            embeddings_failed: run.embeddings_failed.load(Ordering::SeqCst),
+        }
+    }
+
+    // Start counting a new run, leaving the counts of earlier runs alone
+    fn start_run(&self) -> Arc<Run> {
+        let run = Run::new(self.tx.clone());
+        *self.run.lock().unwrap() = Some(run.clone());
+        run
+    }
+
+    // Length of the vectors the embedding API returns, found once by embedding a
+    // probe and checked against embed_dimensions when that is set
+    async fn embedding_dimensions(&self) -> Result<usize> {
+        let dimensions = self.dimensions.get_or_try_init(|| async {
+            let vector = embed_entity_async("dimension check".to_string(), &config().document_task_type).await?;
+            Ok::<_, anyhow::Error>(vector.len())
+        }).await?;
+        match config().embed_dimensions {
+            Some(expected) if expected != *dimensions => Err(IndexError::Config(format!(
+                "Embedding API returned {} dimensions but embed-dimensions is {}", dimensions, expected
+            )).into()),
+            _ => Ok(*dimensions),
+        }
+    }
+
+    // Fail if a root was embedded with another model or vector length than the
+    // embedding API now produces, since its vectors could not be compared
+    async fn check_root_embedding(&self, root_id: &str) -> Result<()> {
+        let dimensions = self.embedding_dimensions().await?;
+        let url = helix_url(self.port, endpoints::GET_ROOT_BY_ID);
+        let response = post_request_async(&url, json!({ "root_id": root_id })).await?;
+        let root = response.get("root").ok_or_else(|| anyhow::anyhow!("Root not found"))?;
+
+        // Roots ingested before the model was recorded are not checked
+        let current = RootEmbedding {
+            provider: embedding_provider().to_string(),
+            model: embedding_model(),
+            dimensions,
+        };
+        match RootEmbedding::from_root(root) {
+            Some(recorded) if recorded != current => Err(IndexError::Config(format!(
+                "Root {} was embedded with {}, but embeddings now use {}; re-embed it or change the embedding settings back",
+                root_id, recorded, current,
+            )).into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Wait until every queued embedding has completed or failed, for at most
+    // DRAIN_TIMEOUT once the run is stopped
+    async fn wait_for_embeddings(&self, run: &Run) {
+        let mut drain_deadline = None;
+        loop {
+            // Listen before checking, so a notification in between is not missed
+            let drained = run.drained();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if run.embeddings_finished() {
+                return;
+            }
+            if !run.stop_requested() {
+                drained.await;
+                continue;
+            }
+
+            let deadline = *drain_deadline.get_or_insert_with(|| Instant::now() + DRAIN_TIMEOUT);
+            if timeout_at(deadline, drained).await.is_err() {
+                warn!("Stopped waiting for {} embeddings", run.unfinished_embeddings());
+                return;
+            }
+        }
+    }
+
+    // Collect the run's counts once its embeddings are done.
+    // Called after the walk has returned, when every embedding has been queued.
+    async fn finish_run(&self, run: &Run, root_id: String, dry_run: Option<DryRunCounts>, started: Instant) -> RunSummary {
+        self.wait_for_embeddings(run).await;
+        let tokens = run.tokens.load(Ordering::SeqCst);
+        RunSummary {
+            root_id,
+            files: run.files_processed.load(Ordering::SeqCst),
+            entities: run.entities.load(Ordering::SeqCst),
+            chunks: run.chunks.load(Ordering::SeqCst),
+            embeddings: run.embeddings_completed.load(Ordering::SeqCst),
+            embeddings_failed: run.embeddings_failed.load(Ordering::SeqCst),
+            tokens,
+            estimated_cost: config().embed_price_per_million.map(|price| tokens as f64 / 1_000_000.0 * price),
+            oversized_chunks: run.oversized_chunks.load(Ordering::SeqCst),
+            elapsed: started.elapsed(),
+            report: run.report(),
+            dry_run,
+            interrupted: run.stop_requested(),
+            verify: None,
+            clean: None,
+        }
+    }
+}
+
+// Write the run's manifest when one is configured. Runs that failed before
+// creating their root have nothing to list.
+fn write_manifest(run: &Run, root_path: &Path, summary: &RunSummary) {
+    if summary.root_id.is_empty() {
+        return;
+    }
+    if let Err(e) = run.write_manifest(root_path, &summary.root_id) {
+        error!("{}", e);
+    }
+}
+
+fn canonicalize(path: &Path) -> Result<std::path::PathBuf> {
+    path.canonicalize()
+        .map_err(|e| IndexError::Io { message: format!("Failed to resolve {}: {}", path.display(), e), source: e }.into())
+}
+
+// Embed chunks from the channel and attach the vectors to their entities, with
+// up to max_concurrent_embeddings jobs in flight; the channel fills up, slowing
+// ingestion down, while all of them are taken
+async fn embed_worker(mut rx: Receiver<QueuedJob>, max_concurrent_embeddings: usize, retries: u32) {
+    let mut job_stream = stream::poll_fn(move |cx| rx.poll_recv(cx))
+        .map(|(run, job)| async move {
+            let EmbeddingJob { chunk, entity_id, file_id, port, summarize } = job;
+            match embed_job(&chunk, &entity_id, port, retries, summarize).await {
+                Ok(()) => {
+                    checkpoint::job_done(&file_id);
+                    run.embedding_done(true);
+                }
+                Err((stage, e)) => {
+                    run.record_failure(format!("entity {}", entity_id), stage, e);
+                    run.embedding_done(false);
+                }
+            }
+        })
+        .buffer_unordered(max_concurrent_embeddings);
+
+    while job_stream.next().await.is_some() {}
+}
+
+// Embed one chunk and attach the vector to its entity, retrying transient failures.
+// With summarize set, the chunk is summarized first, the summary stored on the
+// entity, and the summary embedded instead.
+async fn embed_job(chunk: &str, entity_id: &str, port: u16, retries: u32, summarize: bool) -> std::result::Result<(), (Stage, anyhow::Error)> {
+    let text = if summarize {
+        let summary = retry_transient(retries, || summarize_entity_async(chunk))
+            .await
+            .map_err(|e| (Stage::Summarize, e))?;
+        let url = helix_url(port, endpoints::UPDATE_ENTITY_SUMMARY);
+        let payload = json!({"entity_id": entity_id, "summary": summary});
+        retry_transient(retries, || post_request_async(&url, payload.clone()))
+            .await
+            .map_err(|e| (Stage::Summarize, e))?;
+        summary
+    } else {
+        chunk.to_string()
+    };
+
+    let task_type = config().document_task_type.clone();
+    let embedding = retry_transient(retries, || embed_entity_async(text.clone(), &task_type))
+        .await
+        .map_err(|e| (Stage::Embed, e))?;
+
+    // Storing a vector is not idempotent, so it is only sent again when Helix cannot have stored it
+    let url = helix_url(port, endpoints::EMBED_SUPER_ENTITY);
+    let payload = json!({"entity_id": entity_id,"vector": embedding,});
+    retry_unsent(retries, || post_request_async(&url, payload.clone()))
+        .await
+        .map_err(|e| (Stage::PostEmbedding, e))?;
+    Ok(())
+}
@@ -1,73 +1,176 @@
 use anyhow::Result;
-use ignore::WalkBuilder;
-use futures::future::join_all;
-use serde_json::json;
+use tracing::{debug, info, warn};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
 use std::fs;
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::hash_map::{Entry, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc
+    atomic::Ordering,
+    Arc, Mutex
 };
-use tokio::sync::mpsc::Sender;
-use tokio::task::JoinHandle;
+use lazy_static::lazy_static;
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+use crate::endpoints;
 use crate::utils::CodeEntity;
-use tree_sitter::{Node, Parser};
+use tree_sitter::{Node, Parser, Tree};
 #[derive(Clone)]
 pub struct OwnedNode {
     kind: String,
+    name: Option<String>,
     start_byte: usize,
     end_byte: usize,
     text: String,
     children: Vec<OwnedNode>,
+    // Comments right before the node, when comments are indexed for its language
+    docs: Vec<OwnedNode>,
+}
+
+// Entity a file would be indexed into, as shown by --inspect
+#[derive(Debug, Clone)]
+pub struct InspectedEntity {
+    pub kind: String,
+    pub name: Option<String>,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub order: usize,
+    // Chunks sent for embedding, empty for entities that are not embedded
+    pub chunks: Vec<InspectedChunk>,
+    pub children: Vec<InspectedEntity>,
+}
+
+// Chunk of an entity or file, with its byte range in the file
+#[derive(Debug, Clone)]
+pub struct InspectedChunk {
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub chars: usize,
+    pub tokens: usize,
+    // First line of the chunk
+    pub preview: String,
+}
+
+// How a single file would be parsed, chunked and embedded, without touching Helix
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub extension: String,
+    // A tree-sitter grammar is available for the extension
+    pub parsed: bool,
+    // The file types config leaves the file without entities or embeddings
+    pub skipped: bool,
+    // The file did not fully parse; its ERROR nodes are left out of the entities
+    pub parse_errors: bool,
+    pub entities: Vec<InspectedEntity>,
+    // Chunks of the whole file, for files without a grammar
+    pub chunks: Vec<InspectedChunk>,
+}
+
+// Comment or docstring indexed as its own entity, under the entity it documents
+struct Doc {
+    kind: &'static str,
+    start_byte: usize,
+    end_byte: usize,
+    text: String,
 }
 
 // Import from our modules
 use crate::utils::{
-    post_request_async, chunk_entity, get_language, EmbeddingJob, TOTAL_CHUNKS
+    helix_url, post_request_async, chunk_entity, content_hash, entity_hash, estimated_tokens, file_extension, index_type,
+    language_info, language_name,
+    delete_file, delete_super_entities, run_tasks, EmbeddingJob, embedding_model, embedding_provider, FILE_PERMITS,
 };
+use crate::queries::{get_file_entities, get_sub_entities, update_folder_hash, EntityRecord};
+use crate::checkpoint;
+use crate::manifest::ManifestEntity;
+use crate::walk;
+use crate::config::{config, index_types, file_types, MissingIndexTypes, Summaries};
+use crate::error::IndexError;
+use crate::report::Stage;
+use crate::run::Run;
 
 // Add use async_recursion::async_recursion;
 use async_recursion::async_recursion;
 
+#[tracing::instrument(skip_all, fields(path = %root_path.display()))]
 pub async fn ingestion(
     root_path: PathBuf,
     port: u16,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
+    embedding_dimensions: usize,
 ) -> Result<String> {
-    println!("Starting ingestion for directory: {}", root_path.display());
-
-    // Create a root entry in the index
-    let root_name = root_path.file_name().unwrap().to_str().unwrap();
-    let url = format!("http://localhost:{}/{}", port, "createRoot");
-    let root_response = post_request_async(&url, json!({ "name": root_name })).await?;
-    let root_id = root_response
-        .get("root")
-        .and_then(|v| v.get("id"))
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow::anyhow!("Root ID not found"))?;
+    info!("Starting ingestion for directory: {}", root_path.display());
 
-    println!("\nRoot created");
+    // Load index types before creating anything in Helix
+    let index_types = index_types()?;
+    let file_types = file_types()?;
+
+    let root_name = &config().root_name(&root_path);
+
+    // Pick up where an interrupted ingestion left off
+    let resumed = if config().resume {
+        let resumed = checkpoint::load(&root_path, root_name)?;
+        if resumed.is_none() {
+            info!("No checkpoint found for {}, starting a new ingestion", root_name);
+        }
+        resumed
+    } else {
+        None
+    };
 
-    // Load index types
-    let index_types = fs::read_to_string("index-types.json")?;
-    let index_types: serde_json::Value = serde_json::from_str(&index_types)?;
-    let index_types = Arc::new(index_types);
+    let root_id = match &resumed {
+        Some(resumed) => {
+            let completed = resumed.files.values().filter(|file| file.complete).count();
+            info!("Resuming ingestion ({} files already complete)", completed);
+            resumed.root_id.clone()
+        }
+        None => {
+            // Create a root entry in the index
+            let url = helix_url(port, endpoints::CREATE_ROOT);
+            let payload = json!({
+                "name": root_name,
+                "embedding_provider": embedding_provider(),
+                "embedding_model": embedding_model(),
+                "embedding_dimensions": embedding_dimensions,
+            });
+            let root_response = post_request_async(&url, payload).await?;
+            debug!("createRoot returned {}", root_response);
+            // Helix answered, so a response without a root means the deployed
+            // schema or queries do not match this version of the indexer
+            let root_id = root_response
+                .get("root")
+                .and_then(|v| v.get("id"))
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow::anyhow!(
+                    "Helix is running but createRoot returned no root id. Deploy the schema and queries in db/ with `helix deploy` from the repository root, or run with --log-level debug to see the response"
+                ))?;
 
-    let file_types = fs::read_to_string("file_types.json")?;
-    let file_types: serde_json::Value = serde_json::from_str(&file_types)?;
-    let file_types = Arc::new(file_types);
+            info!("Root created");
+            root_id.to_string()
+        }
+    };
+    checkpoint::start(&root_path, root_name, &root_id, resumed);
+    walk::start(&root_path);
+    reset_ingested_hashes();
 
     // Start populating the index with directory contents
     populate(
-        root_path,root_id.to_string(),port,
-        true,index_types,file_types,tx
+        root_path,root_id.clone(),port,
+        true,index_types,file_types,run
     ).await?;
 
-    Ok(root_id.to_string())
+    Ok(root_id)
 }
 
-/// Recursively populates the index with directory contents
-#[async_recursion]
+/// Populates the index with everything under a directory.
+///
+/// The tree is walked once, parents before their contents, and each folder and
+/// file becomes a task. A task first waits for the folder it belongs to to be
+/// created; tasks whose folder could not be created, or that start after a stop
+/// was requested, do nothing. FILE_PERMITS bounds how many files are processed
+/// at the same time, so only those tasks hold resources while the rest wait.
 pub async fn populate(
     current_path: PathBuf,
     parent_id: String,
@@ -75,90 +178,104 @@ pub async fn populate(
     is_super: bool,
     index_types: Arc<serde_json::Value>,
     file_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
 ) -> Result<()> {
-    // Initialize walker builder
-    let mut walker_builder = WalkBuilder::new(&current_path);
-    walker_builder.max_depth(Some(1));
+    let entries = walk::tree(&current_path);
+    run.count_discovered(&entries);
+    let fingerprints = walk::folder_fingerprints(&current_path, &entries);
+    // Folders created, or reused from an interrupted run, with their ids
+    let created = Arc::new(Mutex::new(Vec::new()));
 
-    // Add default ignore patterns
-    for pattern in &[".git/"] {
-        walker_builder.add_ignore(pattern);
-    }
+    // Ids of the folders below current_path, published once each folder exists.
+    // A folder that could not be created drops its sender, which releases its contents.
+    let mut folders: HashMap<PathBuf, watch::Receiver<Option<String>>> = HashMap::new();
+    let mut tasks = Vec::new();
+    for entry in entries {
+        let path_buf = entry.path().to_path_buf();
+        let Some(parent_path) = path_buf.parent() else {
+            continue;
+        };
+        let parent = if parent_path == current_path {
+            None
+        } else if let Some(folder) = folders.get(parent_path) {
+            Some(folder.clone())
+        } else {
+            continue;
+        };
+        let folder_sender = path_buf.is_dir().then(|| {
+            let (sender, receiver) = watch::channel(None);
+            folders.insert(path_buf.clone(), receiver);
+            sender
+        });
 
-    // Collect entries to process
-    let entries: Vec<_> = walker_builder.build()
-        .filter_map(|result| result.ok())
-        .filter(|entry| entry.path() != current_path)
-        .collect();
+        let parent_id = parent_id.clone();
+        let index_types = index_types.clone();
+        let file_types = file_types.clone();
+        let run = run.clone();
+        let created = created.clone();
+        tasks.push(async move {
+            let (parent_id, is_super) = match parent {
+                None => (parent_id, is_super),
+                Some(mut folder) => match folder.wait_for(Option::is_some).await {
+                    Ok(folder_id) => (folder_id.clone().unwrap_or_default(), false),
+                    Err(_) => return Ok(()),
+                },
+            };
+            if run.stop_requested() {
+                return Ok(());
+            }
 
-    // Process entries concurrently
-    let tasks: Vec<JoinHandle<Result<()>>> = entries.into_iter().map(|entry| {
-        let path_buf = entry.path().to_path_buf();
-        let parent_id_clone = parent_id.clone();
-        let index_types_clone = index_types.clone();
-        let file_types_clone = file_types.clone();
-        let tx_clone = tx.clone();
-
-        tokio::spawn(async move {
-            if path_buf.is_dir() {
-                // Get folder information
-                let folder_name = path_buf.file_name().unwrap().to_str().unwrap();
-                let endpoint = if is_super {"createSuperFolder"} else {"createSubFolder"};
-                let url = format!("http://localhost:{}/{}", port, endpoint);
-                let payload = if is_super {
-                    json!({ "name": folder_name, "root_id": parent_id_clone })
-                } else {
-                    json!({ "name": folder_name, "folder_id": parent_id_clone })
-                };
-
-                // Send request to create folder and get its ID
-                println!("\nSubmitting {} folder for processing", folder_name);
-                match post_request_async(&url, payload).await {
-                    Ok(res) => {
-                        if let Some(folder_id) = res
-                            .get(if is_super { "folder" } else { "subfolder" })
-                            .and_then(|v| v.get("id"))
-                            .and_then(|v| v.as_str())
-                            .map(|s| s.to_string())
-                        {
-                            let path_buf_clone = path_buf.clone();
-                            if let Err(e) = Box::pin(populate(
-                                path_buf_clone,folder_id,port,
-                                false,index_types_clone, file_types_clone, tx_clone
-                            )).await {
-                                eprintln!("Error populating folder {}: {}",folder_name, e);
-                            }
-                            Ok(())
-                        } else {
-                            eprintln!("Failed to extract folder ID from response for: {}", folder_name);
-                            Ok(())
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to create folder {}: {}", folder_name, e);
-                        Ok(())
+            if let Some(folder_sender) = folder_sender {
+                let folder_name = path_buf.file_name().unwrap_or_default().to_string_lossy();
+                // Reuse the folder created by an interrupted run
+                if let Some(folder_id) = checkpoint::folder_id(&path_buf) {
+                    created.lock().unwrap().push((path_buf.clone(), folder_id.clone()));
+                    folder_sender.send_replace(Some(folder_id));
+                    return Ok(());
+                }
+                debug!("Submitting {} folder for processing", folder_name);
+                match create_folder(&folder_name, &parent_id, is_super, port).await {
+                    Ok(folder_id) => {
+                        checkpoint::record_folder(&path_buf, &folder_id);
+                        created.lock().unwrap().push((path_buf.clone(), folder_id.clone()));
+                        folder_sender.send_replace(Some(folder_id));
                     }
+                    Err(e) => run.record_failure(path_buf.display(), Stage::CreateFolder, e),
                 }
+                Ok(())
             } else if path_buf.is_file() {
-                process_file(
-                    path_buf,parent_id_clone,is_super,
-                    port, index_types_clone,file_types_clone,tx_clone
-                ).await
+                let result = process_file(
+                    path_buf, parent_id, is_super,
+                    port, index_types, file_types, run.clone()
+                ).await;
+                run.files_processed.fetch_add(1, Ordering::SeqCst);
+                result
             } else {
                 Ok(())
             }
-        })
-    }).collect();
-
-    for task in tasks {
-        task.await??;
+        });
+    }
+    run_tasks(tasks).await?;
+    if run.stop_requested() {
+        return Ok(());
     }
 
+    // Folders whose contents were all indexed get the fingerprint update compares against
+    let created = std::mem::take(&mut *created.lock().unwrap());
+    let stores = created.into_iter()
+        .filter(|(path, _)| !run.failed_under(path))
+        .filter_map(|(path, folder_id)| Some((fingerprints.get(&path)?.clone(), path, folder_id)))
+        .map(|(hash, path, folder_id)| async move {
+            if let Err(e) = update_folder_hash(folder_id, hash, port).await {
+                warn!("Failed to store the fingerprint of {}: {}", path.display(), e);
+            }
+        });
+    futures::future::join_all(stores).await;
     Ok(())
 }
 
 /// Processes a single file and extracts entities
+#[tracing::instrument(skip_all, fields(path = %file_path.display()))]
 pub async fn process_file(
     file_path: PathBuf,
     parent_id: String,
@@ -166,122 +283,446 @@ pub async fn process_file(
     port: u16,
     index_types: Arc<serde_json::Value>,
     file_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
 ) -> Result<()> {
-    // Read file contents
-    let source_code = match fs::read_to_string(&file_path) {
-        Ok(source_code) => source_code,
-        Err(e) => {
-            eprintln!("Skipped {}: {}", file_path.file_name().unwrap().to_str().unwrap(), e);
+    let _permit = FILE_PERMITS.acquire().await?;
+    if run.stop_requested() {
+        return Ok(());
+    }
+    let _timer = run.time_file(&file_path);
+
+    // Skip files finished by an interrupted run, redo partially processed ones
+    if let Some(progress) = checkpoint::file_progress(&file_path) {
+        if progress.complete {
             return Ok(());
         }
+        delete_file(progress.file_id, port).await;
+    }
+
+    // Read file contents
+    let source_code = match read_source(&file_path, &run) {
+        Some(source_code) => source_code,
+        None => return Ok(()),
     };
 
-    let file_name = file_path.file_name().unwrap().to_str().unwrap();
-    let extension = file_path
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("txt");
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let extension = &file_extension(&file_path, &source_code);
+
+    let hash = content_hash(&source_code);
+
+    // Create file
+    let endpoint = if is_super {endpoints::CREATE_SUPER_FILE} else {endpoints::CREATE_FILE};
+    let url = helix_url(port, endpoint);
+    let payload = if is_super {
+        json!({ "name": file_name, "extension": extension, "root_id": parent_id, "text": source_code, "hash": hash })
+    } else {
+        json!({ "name": file_name, "extension": extension, "folder_id": parent_id, "text": source_code, "hash": hash })
+    };
 
+    // Parse file with Tree Sitter, unless every file is chunked as text
+    let language = file_language(extension, &index_types);
+    let has_grammar = language.is_some();
+    let tree = match language.filter(|_| extracts_entities(extension, &index_types, Some(&*run))) {
+        Some(language) => match parse_source(extension, &language, &source_code) {
+            Ok(tree) => Some(tree),
+            Err(e) => {
+                run.record_failure(file_path.display(), Stage::Parse, e);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let parse_errors = tree.as_ref().is_some_and(|tree| tree.root_node().has_error());
+    let payload = with_file_metadata(payload, &file_path, &source_code, extension, parse_errors);
+
+    // Copies of a file already ingested in this run share its entities
+    let mut hash_owner = None;
+    if config().dedup {
+        match claim_hash(&hash) {
+            HashClaim::Owner(owner) => hash_owner = Some(owner),
+            HashClaim::Duplicate(mut source) => {
+                let source_file_id = source.wait_for(Option::is_some).await.ok().and_then(|id| id.clone());
+                // Process the copy normally if the first one failed
+                if let Some(source_file_id) = source_file_id {
+                    link_duplicate(&file_path, &url, payload, &source_file_id, port, &run).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // file_types() checked that both are lists
     let supported = file_types.get("supported").unwrap().as_array().unwrap();
     let unsupported = file_types.get("unsupported").unwrap().as_array().unwrap();
 
-    // Parse file with Tree Sitter
-    if let Some(language) = get_language(&file_path) {
-        // Parse file
-        let mut parser = Parser::new();
-        parser.set_language(&language)?;
-        let tree = parser.parse(&source_code, None).unwrap();
-
-        // Create file
+    if let Some(tree) = tree {
+        if parse_errors {
+            debug!("File {} has syntax errors, its ERROR nodes are skipped", file_name);
+            run.parse_error_files.fetch_add(1, Ordering::SeqCst);
+        }
         let file_type = if is_super { "super" } else { "sub" };
-        let endpoint = if is_super {"createSuperFile"} else {"createFile"};
-        let url = format!("http://localhost:{}/{}", port, endpoint);
-        let payload = if is_super {
-            json!({ "name": file_name, "extension": extension, "root_id": parent_id, "text": source_code })
-        } else {
-            json!({ "name": file_name, "extension": extension, "folder_id": parent_id, "text": source_code })
-        };
 
         // Send request to create file
-        println!("\nProcessing {} file: {}", file_type, file_name);
-        let file_response = match post_request_async(&url, payload).await {
-            Ok(response) => response,
+        debug!("Processing {} file: {}", file_type, file_name);
+        let file_id = match create_file(&url, payload).await {
+            Ok(file_id) => file_id,
             Err(e) => {
-                eprintln!("Failed to create file {}: {}", file_name, e);
-                return Err(anyhow::anyhow!("Failed to create file: {}", e));
+                run.record_failure(file_path.display(), Stage::CreateFile, e);
+                return Ok(());
             }
         };
+        checkpoint::record_file(&file_path, &file_id);
+        run.record_file(&file_path, &file_id);
 
-        if !supported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL")){
-            println!("File {} is skipped", file_name);
+        let is_indexed = supported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL"));
+        if !is_indexed || walk::is_structure_only(&file_path) {
+            debug!("File {} is skipped", file_name);
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
+            checkpoint::file_processed(&file_path, &file_id);
             return Ok(());
         }
 
-        let file_id = file_response
-            .get("file")
-            .and_then(|v| v.get("id"))
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| {
-                eprintln!("Failed to extract file ID from response for: {}", file_name);
-                anyhow::anyhow!("File ID not found in response")
-            })?;
-
         // Process entities
+        let _blame = run.blame_file(&file_path, &file_id, &source_code).await;
         let root_node = tree.root_node();
         let owned_nodes = build_owned_nodes(root_node, &source_code);
-        ingest_entities(owned_nodes, file_id.to_string(), port, extension.to_string(), index_types, tx).await?;
+        if let Err(e) = ingest_entities(owned_nodes, file_id.clone(), port, extension.to_string(), index_types, run.clone()).await {
+            run.record_failure(file_path.display(), Stage::CreateEntity, e);
+            return Ok(());
+        }
+        if let Some(owner) = &hash_owner {
+            owner.send_replace(Some(file_id.clone()));
+        }
+        checkpoint::file_processed(&file_path, &file_id);
     } else {
         // Create file without entities
-        let endpoint = if is_super {"createSuperFile"} else {"createFile"};
-        let url = format!("http://localhost:{}/{}", port, endpoint);
-        let payload = if is_super {
-            json!({ "name": file_name, "extension": extension, "root_id": parent_id, "text": source_code })
-        } else {
-            json!({ "name": file_name, "extension": extension, "folder_id": parent_id, "text": source_code })
-        };
-
         // Send request to create file
-        println!("\nProcessing unsupported file: {}", file_name);
-        let response = post_request_async(&url, payload).await?;
+        debug!("Processing unsupported file: {}", file_name);
+        let file_id = match create_file(&url, payload).await {
+            Ok(file_id) => file_id,
+            Err(e) => {
+                run.record_failure(file_path.display(), Stage::CreateFile, e);
+                return Ok(());
+            }
+        };
+        checkpoint::record_file(&file_path, &file_id);
+        run.record_file(&file_path, &file_id);
 
-        if !unsupported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL")){
-            println!("File {} is skipped", file_name);
+        // With --file-level, files that have a grammar still follow the supported list
+        let listed = if has_grammar { supported } else { unsupported };
+        let is_indexed = listed.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL"));
+        if !is_indexed || walk::is_structure_only(&file_path) {
+            debug!("File {} is skipped", file_name);
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
+            checkpoint::file_processed(&file_path, &file_id);
             return Ok(());
         }
+        if !has_grammar {
+            run.unsupported_files.fetch_add(1, Ordering::SeqCst);
+        }
 
-        let file_id = response.get("file").and_then(|v| v.get("id")).and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File ID not found"))?;
-
-        let chunks = chunk_entity(&source_code).unwrap();
-        let order_counter = Arc::new(AtomicUsize::new(1));
-        TOTAL_CHUNKS.fetch_add(chunks.len(), Ordering::SeqCst);
+        let _blame = run.blame_file(&file_path, &file_id, &source_code).await;
+        let chunks = run.limit_chunks(&file_id, chunk_entity(&source_code, language_name(extension)).unwrap());
 
-        process_unsupported_file(chunks, file_id.to_string(), port, order_counter, tx).await?;
+        process_unsupported_file(&source_code, chunks, file_id.clone(), port, &index_types, run).await?;
+        if let Some(owner) = &hash_owner {
+            owner.send_replace(Some(file_id.clone()));
+        }
+        checkpoint::file_processed(&file_path, &file_id);
     }
     Ok(())
 }
 
+// Read a file as UTF-8, counting undecodable files as skipped and other errors as
+// failures. Files deleted since the walk listed them are skipped with a warning.
+pub fn read_source(file_path: &Path, run: &Run) -> Option<String> {
+    match fs::read_to_string(file_path) {
+        Ok(source_code) => Some(source_code),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            debug!("Skipped {}: {}", file_path.display(), e);
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!("Skipped {}: deleted since it was listed", file_path.display());
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+        Err(e) => {
+            run.record_failure(file_path.display(), Stage::Read, e);
+            None
+        }
+    }
+}
+
+thread_local! {
+    // Parsers reused across files on each worker thread, keyed by extension
+    static PARSERS: RefCell<HashMap<String, Parser>> = RefCell::new(HashMap::new());
+}
+
+pub fn parse_source(extension: &str, language: &tree_sitter::Language, source_code: &str) -> Result<Tree> {
+    PARSERS.with(|parsers| {
+        let mut parsers = parsers.borrow_mut();
+        let parser = match parsers.entry(extension.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut parser = Parser::new();
+                parser.set_language(language)
+                    .map_err(|e| IndexError::ParseError(format!("Cannot load the {} grammar: {}", extension, e)))?;
+                entry.insert(parser)
+            }
+        };
+        parser.parse(source_code, None).ok_or_else(|| {
+            parser.reset();
+            anyhow::Error::from(IndexError::ParseError(format!("Parser returned no tree for a {} file", extension)))
+        })
+    })
+}
+
+// Parse and chunk a file the way process_file does, without creating anything
+pub fn inspect_file(file_path: &Path, index_types: &Value, file_types: &Value) -> Result<Inspection> {
+    let source_code = fs::read_to_string(file_path)
+        .map_err(|e| IndexError::Io { message: format!("Failed to read {}: {}", file_path.display(), e), source: e })?;
+    let extension = file_extension(file_path, &source_code);
+    let language = file_language(&extension, index_types);
+    let listed = if language.is_some() { "supported" } else { "unsupported" };
+    let skipped = !file_types.get(listed).and_then(Value::as_array)
+        .is_some_and(|types| types.iter().any(|v| v.as_str().is_some_and(|s| s == extension || s == "ALL")));
+
+    let language = language.filter(|_| extracts_entities(&extension, index_types, None));
+    let mut inspection = Inspection {
+        extension: extension.clone(),
+        parsed: language.is_some(),
+        skipped,
+        parse_errors: false,
+        entities: Vec::new(),
+        chunks: Vec::new(),
+    };
+    if skipped {
+        return Ok(inspection);
+    }
+    match language {
+        Some(language) => {
+            let tree = parse_source(&extension, &language, &source_code)?;
+            inspection.parse_errors = tree.root_node().has_error();
+            let owned_nodes = build_owned_nodes(tree.root_node(), &source_code);
+            let index_type = index_type(&extension);
+            let comments = comments_indexed(indexed_kinds(index_types, index_type));
+            inspection.entities = in_source_order(owned_nodes, index_type, comments).into_iter()
+                .map(|(order, owned)| inspect_entity(owned, order, 1, &extension, index_types))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter().flatten().collect();
+        }
+        None => inspection.chunks = inspect_chunks(&source_code, 0, language_name(&extension))?,
+    }
+    Ok(inspection)
+}
+
+// Entity as process_entity would create it, or None if its kind is not indexed
+fn inspect_entity(
+    owned: OwnedNode,
+    order: usize,
+    depth: usize,
+    extension: &str,
+    index_types: &Value,
+) -> Result<Option<InspectedEntity>> {
+    let index_type = index_type(extension);
+    let types = indexed_kinds(index_types, index_type);
+    if !is_indexed_kind(types, &owned.kind) {
+        return Ok(None);
+    }
+    // Only top level entities and documentation are embedded
+    let chunks = if depth == 1 { inspect_chunks(&owned.text, owned.start_byte, language_name(extension))? } else { Vec::new() };
+    let comments = comments_indexed(types);
+    let mut children = Vec::new();
+    if comments {
+        for doc in documentation(&owned) {
+            children.push(InspectedEntity {
+                kind: doc.kind.to_string(),
+                name: owned.name.clone(),
+                start_byte: doc.start_byte,
+                end_byte: doc.end_byte,
+                order: 0,
+                chunks: inspect_chunks(&doc.text, doc.start_byte, "")?,
+                children: Vec::new(),
+            });
+        }
+    }
+    if depth < config().max_entity_depth {
+        for (order, child) in in_source_order(owned.children, index_type, comments) {
+            children.extend(inspect_entity(child, order, depth + 1, extension, index_types)?);
+        }
+    }
+    Ok(Some(InspectedEntity {
+        kind: owned.kind,
+        name: owned.name,
+        start_byte: owned.start_byte,
+        end_byte: owned.end_byte,
+        order,
+        chunks,
+        children,
+    }))
+}
+
+// Chunks of a text that starts at start_byte in its file
+fn inspect_chunks(text: &str, start_byte: usize, language: &str) -> Result<Vec<InspectedChunk>> {
+    let mut offset = 0;
+    let chunks = chunk_entity(text, language)?.into_iter().map(|chunk| {
+        // Chunks follow each other in the text, the chunker may drop whitespace between them
+        offset += text[offset..].find(chunk.as_str()).unwrap_or(0);
+        let start = start_byte + offset;
+        offset += chunk.len();
+        InspectedChunk {
+            start_byte: start,
+            end_byte: start + chunk.len(),
+            chars: chunk.chars().count(),
+            tokens: estimated_tokens(&chunk),
+            preview: chunk.lines().find(|line| !line.trim().is_empty()).unwrap_or_default().trim().to_string(),
+        }
+    }).collect();
+    Ok(chunks)
+}
+
+// Create a folder node under the root (is_super) or another folder and return its id
+pub async fn create_folder(folder_name: &str, parent_id: &str, is_super: bool, port: u16) -> Result<String> {
+    let endpoint = if is_super {endpoints::CREATE_SUPER_FOLDER} else {endpoints::CREATE_SUB_FOLDER};
+    let url = helix_url(port, endpoint);
+    let payload = if is_super {
+        json!({ "name": folder_name, "root_id": parent_id })
+    } else {
+        json!({ "name": folder_name, "folder_id": parent_id })
+    };
+    let response = post_request_async(&url, payload).await?;
+    response
+        .get(if is_super { "folder" } else { "subfolder" })
+        .and_then(|v| v.get("id"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("Folder ID not found in response"))
+}
+
+// Add a file's size in bytes and lines, modification time, language and whether it
+// parsed with errors to a createFile or updateFile payload. The language is empty
+// for files without a grammar.
+pub fn with_file_metadata(mut payload: Value, file_path: &Path, source_code: &str, extension: &str, parse_errors: bool) -> Value {
+    let modified_at = fs::metadata(file_path)
+        .and_then(|metadata| metadata.modified())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+    payload["size"] = json!(source_code.len());
+    payload["lines"] = json!(source_code.lines().count());
+    payload["modified_at"] = json!(modified_at.to_rfc3339());
+    payload["language"] = json!(language_name(extension));
+    payload["parse_errors"] = json!(parse_errors);
+    payload
+}
+
+// Create a file node and return its id
+async fn create_file(url: &str, payload: serde_json::Value) -> Result<String> {
+    let response = post_request_async(url, payload).await?;
+    response
+        .get("file")
+        .and_then(|v| v.get("id"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("File ID not found in response"))
+}
+
+// Hashes of files ingested in this run, used when deduplication is enabled.
+// The first file with a hash publishes its id once its entities are created;
+// the sender is dropped without a value if it fails.
+lazy_static! {
+    static ref INGESTED_HASHES: Mutex<HashMap<String, watch::Receiver<Option<String>>>> = Mutex::new(HashMap::new());
+}
+
+enum HashClaim {
+    Owner(watch::Sender<Option<String>>),
+    Duplicate(watch::Receiver<Option<String>>),
+}
+
+pub fn reset_ingested_hashes() {
+    INGESTED_HASHES.lock().unwrap().clear();
+}
+
+// Claim a hash for this file, or get the file that already claimed it
+fn claim_hash(hash: &str) -> HashClaim {
+    let mut hashes = INGESTED_HASHES.lock().unwrap();
+    if let Some(source) = hashes.get(hash) {
+        let failed = source.has_changed().is_err() && source.borrow().is_none();
+        if !failed {
+            return HashClaim::Duplicate(source.clone());
+        }
+    }
+    let (owner, source) = watch::channel(None);
+    hashes.insert(hash.to_string(), source);
+    HashClaim::Owner(owner)
+}
+
+// Create the file node for a copy and link it to the entities of the first file with its content
+async fn link_duplicate(file_path: &Path, url: &str, payload: serde_json::Value, source_file_id: &str, port: u16, run: &Run) {
+    let file_id = match create_file(url, payload).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            run.record_failure(file_path.display(), Stage::CreateFile, e);
+            return;
+        }
+    };
+    checkpoint::record_file(file_path, &file_id);
+    run.record_file(file_path, &file_id);
+
+    let url = helix_url(port, endpoints::LINK_FILE_ENTITIES);
+    let payload = json!({ "file_id": file_id, "source_file_id": source_file_id });
+    match post_request_async(&url, payload).await {
+        Ok(_) => {
+            debug!("File {} is a copy of {}, reusing its entities", file_path.display(), source_file_id);
+            run.deduplicated_files.fetch_add(1, Ordering::SeqCst);
+            checkpoint::file_processed(file_path, &file_id);
+        }
+        Err(e) => run.record_failure(file_path.display(), Stage::CreateEntity, e),
+    }
+}
+
 pub async fn process_unsupported_file(
+    source_code: &str,
     chunks: Vec<String>,
     file_id: String,
     port: u16,
-    order_counter: Arc<AtomicUsize>,
-    tx: Sender<EmbeddingJob>,
+    index_types: &Value,
+    run: Arc<Run>,
 ) -> Result<()> {
-    let tasks: Vec<JoinHandle<()>> = chunks.into_iter().map(|chunk| {
+    let label = entity_label(index_types, "", "chunk");
+    // Chunks follow each other through the file, so each is searched for after the previous one
+    let mut cursor = 0;
+    let offsets: Vec<usize> = chunks.iter().map(|chunk| {
+        let start = source_code.get(cursor..)
+            .and_then(|rest| rest.find(chunk.as_str()))
+            .map_or(cursor, |index| cursor + index);
+        cursor = start + chunk.len();
+        start
+    }).collect();
+
+    // Chunks are ordered by their position in the file
+    let tasks: Vec<_> = chunks.into_iter().zip(offsets).enumerate().map(|(index, (chunk, start_byte))| {
         let file_id_clone = file_id.clone();
-        let order_counter_clone = order_counter.clone();
-        let tx_clone = tx.clone();
+        let label = label.clone();
+        let run_clone = run.clone();
 
-        tokio::spawn(async move {
-            let url = format!("http://localhost:{}/{}", port, "createSuperEntity");
+        async move {
+            let url = helix_url(port, endpoints::CREATE_SUPER_ENTITY);
+            let blame = run_clone.blame(&file_id_clone, start_byte, start_byte + chunk.len());
             let payload = json!({
                     "file_id": file_id_clone,
                     "entity_type": "chunk",
-                    "text": chunk,
-                    "start_byte": 0,
-                    "end_byte": chunk.len() as i64,
-                    "order": order_counter_clone.fetch_add(1, Ordering::SeqCst),
+                    "label": label,
+                    "name": "",
+                    "hash": entity_hash("chunk", &chunk),
+                    "text": stored_text(&chunk),
+                    "start_byte": start_byte,
+                    "end_byte": start_byte + chunk.len(),
+                    "order": index + 1,
+                    "author": blame.author,
+                    "commit_hash": blame.commit,
+                    "committed_at": blame.committed_at,
                 });
 
             // Send request to create entity
@@ -292,34 +733,32 @@ pub async fn process_unsupported_file(
                     .and_then(|v| v.as_str())
                     .map(|s| s.to_string()),
                 Err(e) => {
-                    eprintln!("Failed to create entity: {}", e);
+                    run_clone.record_failure(format!("file {}", file_id_clone), Stage::CreateEntity, e);
                     None
                 }
             };
             
             // Generate embedding
             if let Some(entity_id) = entity_id {
-                let job = EmbeddingJob {chunk: chunk.clone(), entity_id, port};
-                match tx_clone.try_send(job) {
-                    Ok(_) => {},
-                    Err(err) => {
-                        // Channel is full; send asynchronously
-                        let job_back = err.into_inner();
-                        let tx_async = tx_clone.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = tx_async.send(job_back).await {
-                                eprintln!("Failed to send embedding job asynchronously: {}", e);
-                            }
-                        });
-                    }
-                }
+                run_clone.entities.fetch_add(1, Ordering::SeqCst);
+                run_clone.record_entity(ManifestEntity {
+                    entity_id: entity_id.clone(),
+                    file_id: file_id_clone.clone(),
+                    parent_id: None,
+                    entity_type: "chunk".to_string(),
+                    name: String::new(),
+                    start_byte,
+                    end_byte: start_byte + chunk.len(),
+                    order: index + 1,
+                });
+                let chunk = run_clone.embedding_text(chunk, &file_id_clone, "chunk", "");
+                let job = EmbeddingJob {chunk, entity_id, file_id: file_id_clone, port, summarize: false};
+                run_clone.queue_embedding(job).await;
             }
-        })
+            Ok(())
+        }
     }).collect();
-
-    join_all(tasks).await;
-
-    Ok(())
+    run_tasks(tasks).await
 }
 
 pub async fn ingest_entities(
@@ -328,75 +767,93 @@ pub async fn ingest_entities(
     port: u16,
     extension: String,
     index_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
 ) -> Result<()> {
-    let order_counter = Arc::new(AtomicUsize::new(1));
-    let tasks: Vec<JoinHandle<Result<()>>> = owned_nodes.into_iter().map(|owned| {
+    let index_type = index_type(&extension);
+    let comments = comments_indexed(indexed_kinds(&index_types, index_type));
+    let tasks: Vec<_> = in_source_order(owned_nodes, index_type, comments).into_iter().map(|(order, owned)| {
         let file_id_clone = file_id.clone();
         let extension_clone = extension.clone();
         let index_types_clone = index_types.clone();
-        let tx_clone = tx.clone();
-        let order_counter_clone = order_counter.clone();
-        tokio::spawn(async move {
-            let current_order = order_counter_clone.fetch_add(1, Ordering::SeqCst);
-            // Get index_types for file extension
-            if let Some(types) = index_types_clone.get(&extension_clone) {
-                if let Some(types_array) = types.as_array() {
-                    // Check if ALL is not in index_types
-                    if types_array.iter().any(|v| v.as_str().map_or(false, |s| s != "ALL")) {
-                        // Super entity type in index_types
-                        if types_array.iter().any(|v| v.as_str().map_or(false, |s| s == owned.kind)){
-                            // Has super content
-                            let entity_start_byte = owned.start_byte;
-                            let entity_end_byte = owned.end_byte;
-                            let entity_content = &owned.text;
-                            let url = format!("http://localhost:{}/{}", port, "createSuperEntity");
-                            let payload = json!({
-                                "file_id": file_id_clone.clone(),
-                                "entity_type": owned.kind,
-                                "text": entity_content,
-                                "start_byte": entity_start_byte,
-                                "end_byte": entity_end_byte,
-                                "order": current_order,
-                            });
-                            // Send request
-                            if let Ok(entity_response) = post_request_async(&url, payload).await {
-                                if let Some(entity_id) = entity_response.get("entity").and_then(|v| v.get("id")).and_then(|v| v.as_str()) {
-                                    if let Ok(chunks) = chunk_entity(entity_content) {
-                                        TOTAL_CHUNKS.fetch_add(chunks.len(), Ordering::SeqCst);
-                                        let chunk_tasks: Vec<JoinHandle<()>> = chunks.into_iter().map(|chunk| {
-                                            let chunk_clone = chunk.clone();
-                                            let entity_id_clone = entity_id.to_string();
-                                            let tx_clone_inner = tx_clone.clone();
-                                            tokio::spawn(async move {
-                                                let job = EmbeddingJob {chunk: chunk_clone, entity_id: entity_id_clone, port};
-                                                match tx_clone_inner.try_send(job) {
-                                                    Ok(_) => {},
-                                                    Err(err) => {
-                                                        let job_back = err.into_inner();
-                                                        let tx_async = tx_clone_inner.clone();
-                                                        tokio::spawn(async move {
-                                                            if let Err(e) = tx_async.send(job_back).await {
-                                                                eprintln!("Failed to send embedding job asynchronously: {}", e);
-                                                            }
-                                                        });
-                                                    }
-                                                }
-                                            })
-                                        }).collect();
-                                        join_all(chunk_tasks).await;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            process_entity(owned, file_id_clone, port, true, current_order, extension_clone, index_types_clone, tx_clone).await
-        })
+        let run_clone = run.clone();
+        async move {
+            process_entity(owned, file_id_clone.clone(), file_id_clone, port, true, order, 1, extension_clone, index_types_clone, run_clone).await
+        }
     }).collect();
-    for task in tasks {
-        task.await??;
+    run_tasks(tasks).await
+}
+
+// Bring a file's entities in line with its new contents. Entities whose type and
+// text are unchanged keep their ids and embeddings and are only moved; the rest
+// are deleted and created again.
+pub async fn update_entities(
+    owned_nodes: Vec<OwnedNode>,
+    file_id: String,
+    port: u16,
+    extension: String,
+    index_types: Arc<serde_json::Value>,
+    run: Arc<Run>,
+) -> Result<()> {
+    let mut stored: HashMap<String, Vec<EntityRecord>> = HashMap::new();
+    let mut unmatched: Vec<EntityRecord> = Vec::new();
+    for entity in get_file_entities(file_id.clone(), port).await? {
+        match entity.hash.clone() {
+            Some(hash) => stored.entry(hash).or_default().push(entity),
+            None => unmatched.push(entity),
+        }
+    }
+    // Identical entities are matched in file order
+    for entities in stored.values_mut() {
+        entities.sort_by_key(|entity| std::cmp::Reverse(entity.order));
+    }
+
+    let mut tasks: Vec<BoxFuture<'static, Result<()>>> = Vec::new();
+    let mut unchanged = 0;
+    let index_type = index_type(&extension);
+    let comments = comments_indexed(indexed_kinds(&index_types, index_type));
+    for (order, owned) in in_source_order(owned_nodes, index_type, comments) {
+        let hash = node_hash(&owned);
+        if let Some(entity) = stored.get_mut(&hash).and_then(|entities| entities.pop()) {
+            unchanged += 1;
+            let delta = owned.start_byte as i64 - entity.start_byte;
+            if delta != 0 || entity.order != order as i64 {
+                tasks.push(move_entity(entity, delta, Some(order), port));
+            }
+            continue;
+        }
+        let file_id_clone = file_id.clone();
+        let extension_clone = extension.clone();
+        let index_types_clone = index_types.clone();
+        let run_clone = run.clone();
+        tasks.push(Box::pin(async move {
+            process_entity(owned, file_id_clone.clone(), file_id_clone, port, true, order, 1, extension_clone, index_types_clone, run_clone).await
+        }));
+    }
+
+    let removed: Vec<String> = stored.into_values().flatten().chain(unmatched).map(|entity| entity.id).collect();
+    debug!("File {}: {} entities unchanged, {} removed", file_id, unchanged, removed.len());
+    run.unchanged_entities.fetch_add(unchanged, Ordering::SeqCst);
+    delete_super_entities(removed, port).await?;
+
+    run_tasks(tasks).await
+}
+
+// Shift an unchanged entity and its subentities to their position in the updated file
+#[async_recursion]
+async fn move_entity(entity: EntityRecord, delta: i64, order: Option<usize>, port: u16) -> Result<()> {
+    let url = helix_url(port, endpoints::UPDATE_ENTITY_POSITION);
+    let payload = json!({
+        "entity_id": entity.id,
+        "start_byte": entity.start_byte + delta,
+        "end_byte": entity.end_byte + delta,
+        "order": order.map_or(entity.order, |order| order as i64),
+    });
+    post_request_async(&url, payload).await?;
+
+    if delta != 0 {
+        for sub_entity in get_sub_entities(entity.id.clone(), port).await? {
+            move_entity(sub_entity, delta, None, port).await?;
+        }
     }
     Ok(())
 }
@@ -406,119 +863,418 @@ pub async fn ingest_entities(
 async fn process_entity(
     owned: OwnedNode,
     parent_id: String,
+    file_id: String,
     port: u16,
     is_super: bool,
     order: usize,
+    // Nesting level of the entity, 1 for top level entities
+    depth: usize,
     extension: String,
     index_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
 ) -> Result<()> {
     let code_entity = CodeEntity {
         entity_type: owned.kind.clone(),
+        name: owned.name.clone(),
         start_byte: owned.start_byte,
         end_byte: owned.end_byte,
         order,
         text: owned.text.clone(),
     };
-    if extension == "py" && code_entity.entity_type == "block" && !owned.children.is_empty() {
-        let mut order = 1;
-        for child in owned.children.into_iter() {
-            process_entity(child, parent_id.clone(), port, false, order, extension.clone(), index_types.clone(), tx.clone()).await?;
-            order += 1;
+    let index_type = index_type(&extension);
+    if is_indexed_kind(indexed_kinds(&index_types, index_type), &code_entity.entity_type) {
+        let endpoint = if is_super {endpoints::CREATE_SUPER_ENTITY} else {endpoints::CREATE_SUB_ENTITY};
+        let url = helix_url(port, endpoint);
+        let id_name = if is_super {"file_id"} else {"entity_id"};
+        let blame = run.blame(&file_id, code_entity.start_byte, code_entity.end_byte);
+        let payload = json!({
+            id_name: parent_id.clone(),
+            "entity_type": code_entity.entity_type,
+            "label": entity_label(&index_types, index_type, &code_entity.entity_type),
+            "name": code_entity.name.clone().unwrap_or_default(),
+            "hash": node_hash(&owned),
+            "text": stored_text(&code_entity.text),
+            "start_byte": code_entity.start_byte,
+            "end_byte": code_entity.end_byte,
+            "order": code_entity.order,
+            "author": blame.author,
+            "commit_hash": blame.commit,
+            "committed_at": blame.committed_at,
+        });
+        let entity_response = post_request_async(&url, payload).await?;
+        let entity_id = entity_response
+            .get("entity")
+            .and_then(|v| v.get("id"))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow::anyhow!("Entity ID not found"))?;
+        run.entities.fetch_add(1, Ordering::SeqCst);
+        run.record_entity(ManifestEntity {
+            entity_id: entity_id.clone(),
+            file_id: file_id.clone(),
+            parent_id: (!is_super).then(|| parent_id.clone()),
+            entity_type: code_entity.entity_type.clone(),
+            name: code_entity.name.clone().unwrap_or_default(),
+            start_byte: code_entity.start_byte,
+            end_byte: code_entity.end_byte,
+            order: code_entity.order,
+        });
+        if is_super {
+            let name = code_entity.name.as_deref().unwrap_or_default();
+            queue_entity_embeddings(&code_entity.text, language_name(&extension), &code_entity.entity_type, name, &entity_id, &file_id, port, &run).await?;
         }
-    } else {
-        // Handle special extension cases
-        let mut index_type = extension.clone();
-        if extension == "cc" || extension == "cxx" {
-            index_type = "cpp".to_string();
-        } else if extension == "h" {
-            index_type = "c".to_string();
-        } else if extension == "js" || extension == "jsx" {
-            index_type = "js".to_string();
-        }
-        if let Some(types) = index_types.get(&index_type) {
-            if let Some(types_array) = types.as_array() {
-                let entity_type = &code_entity.entity_type;
-                if types_array.iter().any(|v| v.as_str().map_or(false, |s| s == entity_type)) || 
-                types_array.iter().any(|v| v.as_str().map_or(false, |s| s == "ALL")) {
-                    let endpoint = if is_super {"createSuperEntity"} else {"createSubEntity"};
-                    let url = format!("http://localhost:{}/{}", port, endpoint);
-                    let id_name = if is_super {"file_id"} else {"entity_id"};
-                    let payload = json!({
-                        id_name: parent_id.clone(),
-                        "entity_type": code_entity.entity_type,
-                        "text": code_entity.text,
-                        "start_byte": code_entity.start_byte,
-                        "end_byte": code_entity.end_byte,
-                        "order": code_entity.order,
-                    });
-                    let entity_response = post_request_async(&url, payload).await?;
-                    let entity_id = entity_response
-                        .get("entity")
-                        .and_then(|v| v.get("id"))
-                        .and_then(|v| v.as_str())
-                        .map(|s| s.to_string())
-                        .ok_or_else(|| anyhow::anyhow!("Entity ID not found"))?;
-                    if is_super {
-                        let chunks = chunk_entity(&code_entity.text).unwrap();
-                        TOTAL_CHUNKS.fetch_add(chunks.len(), Ordering::SeqCst);
-                        let chunk_tasks: Vec<JoinHandle<()>> = chunks.into_iter().map(|chunk| {
-                            let chunk_clone = chunk.clone();
-                            let entity_id_clone = entity_id.clone();
-                            let tx_clone = tx.clone();
-                            tokio::spawn(async move {
-                                let job = EmbeddingJob {chunk: chunk_clone, entity_id: entity_id_clone, port};
-                                match tx_clone.try_send(job) {
-                                    Ok(_) => {},
-                                    Err(err) => {
-                                        let job_back = err.into_inner();
-                                        let tx_async = tx_clone.clone();
-                                        tokio::spawn(async move {
-                                            if let Err(e) = tx_async.send(job_back).await {
-                                                eprintln!("Failed to send embedding job asynchronously: {}", e);
-                                            }
-                                        });
-                                    }
-                                }
-                            })
-                        }).collect();
-                        join_all(chunk_tasks).await;
-                    }
-                    if !owned.children.is_empty() {
-                        let order_counter = Arc::new(AtomicUsize::new(1));
-                        let child_tasks: Vec<JoinHandle<Result<()>>> = owned.children.into_iter().map(|child| {
-                            let entity_id_clone = entity_id.clone();
-                            let extension_clone = extension.clone();
-                            let index_types_clone = index_types.clone();
-                            let tx_clone = tx.clone();
-                            let order_counter_clone = order_counter.clone();
-                            tokio::spawn(async move {
-                                let current_order = order_counter_clone.fetch_add(1, Ordering::SeqCst);
-                                process_entity(child, entity_id_clone, port, false, current_order, extension_clone, index_types_clone, tx_clone).await
-                            })
-                        }).collect();
-                        for task in child_tasks {
-                            task.await??;
-                        }
-                    }
+        let comments = comments_indexed(indexed_kinds(&index_types, index_type));
+        if comments {
+            for doc in documentation(&owned) {
+                let label = entity_label(&index_types, index_type, doc.kind);
+                create_doc_entity(doc, &label, &entity_id, owned.name.as_deref(), &file_id, port, &run).await?;
+            }
+        }
+        // Entities at the maximum depth keep their children as text only
+        if !owned.children.is_empty() && depth < config().max_entity_depth {
+            let children = in_source_order(owned.children, index_type, comments);
+            let child_tasks: Vec<_> = children.into_iter().map(|(order, child)| {
+                let entity_id_clone = entity_id.clone();
+                let file_id_clone = file_id.clone();
+                let extension_clone = extension.clone();
+                let index_types_clone = index_types.clone();
+                let run_clone = run.clone();
+                async move {
+                    process_entity(child, entity_id_clone, file_id_clone, port, false, order, depth + 1, extension_clone, index_types_clone, run_clone).await
+                }
+            }).collect();
+            run_tasks(child_tasks).await?;
+        }
+    }
+    Ok(())
+}
+
+// Queue the embeddings of a top level entity: its code chunk by chunk, and a
+// summary of it when summaries are enabled
+pub async fn queue_entity_embeddings(
+    text: &str,
+    language: &str,
+    entity_type: &str,
+    name: &str,
+    entity_id: &str,
+    file_id: &str,
+    port: u16,
+    run: &Arc<Run>,
+) -> Result<()> {
+    let summaries = config().summaries;
+    if summaries != Summaries::Instead {
+        let chunks = run.limit_chunks(file_id, chunk_entity(text, language)?);
+        for chunk in chunks {
+            let chunk = run.embedding_text(chunk, file_id, entity_type, name);
+            let job = EmbeddingJob {chunk, entity_id: entity_id.to_string(), file_id: file_id.to_string(), port, summarize: false};
+            run.queue_embedding(job).await;
+        }
+    }
+    if summaries != Summaries::Off {
+        run.chunks.fetch_add(1, Ordering::SeqCst);
+        let job = EmbeddingJob {chunk: text.to_string(), entity_id: entity_id.to_string(), file_id: file_id.to_string(), port, summarize: true};
+        run.queue_embedding(job).await;
+    }
+    Ok(())
+}
+
+// Grammar to parse a file with. Config files are only parsed into entities when
+// index-types.json lists their format, and are chunked as text otherwise.
+pub fn file_language(extension: &str, index_types: &Value) -> Option<tree_sitter::Language> {
+    let info = language_info(extension)?;
+    let config_file = matches!(info.index_type, "json" | "yaml" | "toml");
+    (!config_file || index_types.get(info.index_type).is_some()).then_some(info.language)
+}
+
+lazy_static! {
+    static ref ALL_KINDS: Value = json!(["ALL"]);
+}
+
+// Whether a file with a grammar is parsed into entities. With --file-level no
+// file is; a language index-types.json does not list is handled as
+// missing_index_types says, which is logged once per language and run, or every
+// time without a run.
+pub fn extracts_entities(extension: &str, index_types: &Value, run: Option<&Run>) -> bool {
+    if config().file_level {
+        return false;
+    }
+    let index_type = index_type(extension);
+    if index_types.get(index_type).is_some() {
+        return true;
+    }
+    let policy = config().missing_index_types;
+    let first = match run {
+        Some(run) => run.first_missing_index_type(index_type),
+        None => true,
+    };
+    if first {
+        match policy {
+            MissingIndexTypes::Skip => warn!(
+                "index-types.json has no entry for {}, so its files get no entities or embeddings; add one or set --missing-index-types to all or file-level",
+                index_type
+            ),
+            MissingIndexTypes::All => info!("index-types.json has no entry for {}, indexing every node kind", index_type),
+            MissingIndexTypes::FileLevel => info!("index-types.json has no entry for {}, chunking its files as text", index_type),
+        }
+    }
+    policy != MissingIndexTypes::FileLevel
+}
+
+// Node kinds indexed for a language: its index-types.json entry, or every kind
+// when it has none and missing_index_types is all
+fn indexed_kinds<'a>(index_types: &'a Value, index_type: &str) -> Option<&'a Value> {
+    match index_types.get(index_type) {
+        Some(types) => Some(types),
+        None if config().missing_index_types == MissingIndexTypes::All => Some(&*ALL_KINDS),
+        None => None,
+    }
+}
+
+// Whether entities of a kind are indexed for a language. A language maps to a list of
+// kinds to index, or to {"include": [...], "exclude": [...]} where a missing include
+// indexes every kind and excluded kinds are left out; "ALL" includes every kind.
+// Kinds in exclude_kinds are left out whatever the language lists.
+fn is_indexed_kind(types: Option<&Value>, kind: &str) -> bool {
+    if is_excluded_kind(kind) {
+        return false;
+    }
+    let listed = |kinds: Option<&Value>| kinds
+        .and_then(Value::as_array)
+        .is_some_and(|kinds| kinds.iter().any(|v| v.as_str().is_some_and(|s| s == kind || s == "ALL")));
+    match types {
+        Some(Value::Array(_)) => listed(types),
+        Some(Value::Object(types)) => {
+            let included = types.get("include").is_none() || listed(types.get("include"));
+            let excluded = types.get("exclude").and_then(Value::as_array)
+                .is_some_and(|kinds| kinds.iter().any(|v| v.as_str() == Some(kind)));
+            included && !excluded
+        }
+        _ => false,
+    }
+}
+
+// Whether a node kind is in exclude_kinds
+fn is_excluded_kind(kind: &str) -> bool {
+    config().exclude_kinds.iter().any(|excluded| excluded == kind)
+}
+
+// Number sibling nodes by their position in the source, starting at 1, so the
+// order of an entity does not depend on which task creates it first. Nodes that
+// are not indexed keep their number, leaving gaps. Containers such as Python blocks
+// are not entities: their children take the container's place among its siblings. With comments
+// indexed, each run of comments is attached to the node right after it.
+fn in_source_order(nodes: Vec<OwnedNode>, index_type: &str, comments: bool) -> Vec<(usize, OwnedNode)> {
+    let mut siblings = Vec::new();
+    for node in nodes {
+        if is_container(index_type, &node.kind) && !node.children.is_empty() {
+            siblings.extend(in_source_order(node.children, index_type, false).into_iter().map(|(_, child)| child));
+        } else {
+            siblings.push(node);
+        }
+    }
+    siblings.sort_by_key(|node| node.start_byte);
+
+    if comments {
+        let mut run = Vec::new();
+        for node in siblings.iter_mut() {
+            if is_comment(&node.kind) {
+                if !is_excluded_kind(&node.kind) {
+                    run.push(node.clone());
                 }
+            } else {
+                node.docs = std::mem::take(&mut run);
             }
         }
     }
+    siblings.into_iter().enumerate().map(|(index, node)| (index + 1, node)).collect()
+}
+
+// Nodes that only hold the entities of a language: Python blocks, Kotlin, Swift and
+// Scala class bodies, Scala blocks, the declarations of a Haskell module and its
+// where clauses, and the documents and mappings that hold the keys of config files
+fn is_container(index_type: &str, kind: &str) -> bool {
+    matches!(
+        (index_type, kind),
+        ("py", "block")
+            | ("kt", "class_body" | "enum_class_body")
+            | ("swift", "class_body" | "protocol_body" | "enum_class_body")
+            | ("scala", "template_body" | "block")
+            | ("hs", "declarations" | "local_binds")
+            | ("json", "object")
+            | ("yaml", "document" | "block_node" | "block_mapping" | "flow_node" | "flow_mapping")
+    )
+}
+
+// Comment nodes, e.g. `comment`, or `line_comment` and `block_comment` in Rust
+fn is_comment(kind: &str) -> bool {
+    kind == "comment" || kind.ends_with("_comment")
+}
+
+// Whether comments and docstrings of a language are indexed as their own entities,
+// set with "comments": true in the object form of index-types.json
+fn comments_indexed(types: Option<&Value>) -> bool {
+    types.and_then(|types| types.get("comments")).and_then(Value::as_bool).unwrap_or(false)
+}
+
+// Label stored next to the raw kind so entities can be grouped across languages, e.g.
+// function_item and function_definition as "function". A language given as an object
+// can map kinds in its own "labels", which come before the top level "labels" of
+// index-types.json; kinds in neither keep their own name.
+fn entity_label(index_types: &Value, index_type: &str, kind: &str) -> String {
+    [index_types.get(index_type), Some(index_types)].into_iter().flatten()
+        .find_map(|types| types.get("labels")?.get(kind)?.as_str())
+        .unwrap_or(kind)
+        .to_string()
+}
+
+// Text sent with an entity, empty when entity text is read from its file by byte range
+fn stored_text(text: &str) -> &str {
+    if config().omit_entity_text { "" } else { text }
+}
+
+// Hash a node is matched on between updates. Comments attached to the node count
+// towards it, so a changed comment is indexed again.
+fn node_hash(owned: &OwnedNode) -> String {
+    if owned.docs.is_empty() {
+        return entity_hash(&owned.kind, &owned.text);
+    }
+    let docs: Vec<&str> = owned.docs.iter().map(|doc| doc.text.as_str()).collect();
+    entity_hash(&owned.kind, &format!("{}\n{}", docs.join("\n"), owned.text))
+}
+
+// Comments right before a node and a Python docstring at the start of its body,
+// unless exclude_kinds lists comment or docstring
+fn documentation(owned: &OwnedNode) -> Vec<Doc> {
+    let mut docs = Vec::new();
+    if let (Some(first), Some(last)) = (owned.docs.first(), owned.docs.last()) {
+        let text: Vec<&str> = owned.docs.iter().map(|doc| doc.text.trim_end()).collect();
+        docs.push(Doc { kind: "comment", start_byte: first.start_byte, end_byte: last.end_byte, text: text.join("\n") });
+    }
+    // def and class bodies that start with a bare string
+    let docstring = owned.children.iter()
+        .filter(|_| owned.kind.ends_with("_definition"))
+        .find(|child| child.kind == "block")
+        .and_then(|block| block.children.first())
+        .filter(|statement| statement.kind == "expression_statement")
+        .filter(|statement| matches!(statement.children.as_slice(), [string] if string.kind == "string"));
+    if let Some(docstring) = docstring {
+        docs.push(Doc { kind: "docstring", start_byte: docstring.start_byte, end_byte: docstring.end_byte, text: docstring.text.clone() });
+    }
+    docs.retain(|doc| !is_excluded_kind(doc.kind));
+    docs
+}
+
+// Store a comment or docstring under the entity it documents, named after that entity,
+// and embed it so it can be searched on its own
+async fn create_doc_entity(
+    doc: Doc,
+    label: &str,
+    entity_id: &str,
+    name: Option<&str>,
+    file_id: &str,
+    port: u16,
+    run: &Arc<Run>,
+) -> Result<()> {
+    let url = helix_url(port, endpoints::CREATE_SUB_ENTITY);
+    let blame = run.blame(file_id, doc.start_byte, doc.end_byte);
+    // Order 0 puts documentation before the entity's own children
+    let payload = json!({
+        "entity_id": entity_id,
+        "entity_type": doc.kind,
+        "label": label,
+        "name": name.unwrap_or_default(),
+        "hash": entity_hash(doc.kind, &doc.text),
+        "text": stored_text(&doc.text),
+        "start_byte": doc.start_byte,
+        "end_byte": doc.end_byte,
+        "order": 0,
+        "author": blame.author,
+        "commit_hash": blame.commit,
+        "committed_at": blame.committed_at,
+    });
+    let response = post_request_async(&url, payload).await?;
+    let doc_id = response
+        .get("entity")
+        .and_then(|v| v.get("id"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("Entity ID not found"))?;
+    run.entities.fetch_add(1, Ordering::SeqCst);
+    run.record_entity(ManifestEntity {
+        entity_id: doc_id.clone(),
+        file_id: file_id.to_string(),
+        parent_id: Some(entity_id.to_string()),
+        entity_type: doc.kind.to_string(),
+        name: name.unwrap_or_default().to_string(),
+        start_byte: doc.start_byte,
+        end_byte: doc.end_byte,
+        order: 0,
+    });
+
+    let chunks = run.limit_chunks(file_id, chunk_entity(&doc.text, "")?);
+    for chunk in chunks {
+        let chunk = run.embedding_text(chunk, file_id, doc.kind, name.unwrap_or_default());
+        let job = EmbeddingJob {chunk, entity_id: doc_id.clone(), file_id: file_id.to_string(), port, summarize: false};
+        run.queue_embedding(job).await;
+    }
     Ok(())
 }
 
+// Fields grammars put a definition's name under, directly or nested: C declarators
+// wrap the identifier, Python decorators and JS exports wrap the definition, and
+// Rust impl blocks are named by their type
+const NAME_FIELDS: &[&str] = &["name", "declarator", "definition", "declaration", "type"];
+
+// Symbol name of a definition, e.g. `foo` for `fn foo()` or `class Foo`
+fn entity_name(node: Node, source: &str) -> Option<String> {
+    let kind = node.kind();
+    // Keys of config file entries: `"name":` in JSON, `name:` in YAML, `name =` and `[name]` in TOML
+    if matches!(kind, "pair" | "block_mapping_pair" | "flow_pair" | "table" | "table_array_element") {
+        let mut cursor = node.walk();
+        let key = node.child_by_field_name("key")
+            .or_else(|| node.named_children(&mut cursor).find(|child| child.kind().ends_with("_key")))?;
+        let text = source.get(key.start_byte()..key.end_byte())?;
+        return Some(text.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+    }
+    if kind == "identifier" || kind.ends_with("_identifier") || kind.ends_with("_name") {
+        return source.get(node.start_byte()..node.end_byte()).map(str::to_string);
+    }
+    if let Some(child) = NAME_FIELDS.iter().find_map(|field| node.child_by_field_name(field)) {
+        // A leaf under a name field is the name itself, such as the `word` naming a Bash function
+        if child.named_child_count() == 0 {
+            return source.get(child.start_byte()..child.end_byte()).map(str::to_string);
+        }
+        return entity_name(child, source);
+    }
+    // `const a = ...` in JavaScript and Zig names its declarators without a field,
+    // as do some Kotlin declarations
+    if kind.ends_with("declaration") {
+        let mut cursor = node.walk();
+        let child = node.named_children(&mut cursor).find(|child| {
+            matches!(child.kind(), "identifier" | "simple_identifier" | "type_identifier") || child.kind().ends_with("declarator")
+        });
+        return child.and_then(|child| entity_name(child, source));
+    }
+    None
+}
+
 pub fn build_owned_nodes(node: Node, source: &str) -> Vec<OwnedNode> {
     let mut res = Vec::new();
     let mut cursor = node.walk();
     for child in node.children(&mut cursor) {
+        // Code tree-sitter could not parse would only make garbage entities
+        if child.is_error() {
+            continue;
+        }
         let text = source[child.start_byte()..child.end_byte()].to_string();
         let owned = OwnedNode {
             kind: child.kind().to_string(),
+            name: entity_name(child, source),
             start_byte: child.start_byte(),
             end_byte: child.end_byte(),
             text,
             children: build_owned_nodes(child, source),
+            docs: Vec::new(),
         };
         res.push(owned);
     }
@@ -0,0 +1,27 @@
+// Index codebases into HelixDB: walk a directory, extract entities with
+// tree-sitter, store them in Helix and embed them for search.
+mod checkpoint;
+pub mod config;
+mod endpoints;
+pub mod error;
+mod git;
+mod manifest;
+pub mod report;
+mod run;
+mod utils;
+mod queries;
+mod reembed;
+mod stats;
+mod updater;
+mod ingestion;
+mod walk;
+mod watch;
+mod indexer;
+
+pub use config::Config;
+pub use error::IndexError;
+pub use indexer::{CleanCounts, Indexer, Progress, Root, RootEmbedding, RunSummary, VerifyCounts};
+pub use ingestion::{InspectedChunk, InspectedEntity, Inspection};
+pub use stats::RootStats;
+pub use updater::DryRunCounts;
+pub use utils::block_on;
@@ -1,200 +1,752 @@
-mod utils;
-mod queries;
-mod updater;
-mod ingestion;
-
 // External crates
 use anyhow::Result;
-use serde_json::json;
 use std::env;
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use std::io;
 use std::io::Write;
 use dotenv;
-use tokio_stream;
-use futures::StreamExt;
 use clearscreen;
-use std::sync::atomic::Ordering;
-use indicatif::{ProgressBar, ProgressStyle};
-
-// Internal utility functions
-use utils::{
-    embed_entity_async, post_request_async, EmbeddingJob,
-    TOTAL_CHUNKS,
-    PENDING_EMBEDDINGS, COMPLETED_EMBEDDINGS,
-};
-
-use updater::update;
-use ingestion::ingestion;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tokio::time::{sleep, Duration};
+use tracing_subscriber::EnvFilter;
 
-// Remove embedding_wait_thread function entirely
+use codebase_index::config::Action;
+use codebase_index::{Config, IndexError, Indexer, InspectedChunk, InspectedEntity, Root, RootStats, RunSummary};
 
-async fn async_main() {
-    clear_screen();
+async fn async_main() -> i32 {
     let args: Vec<String> = env::args().collect();
 
-    let default_port = 6969;
-    
+    dotenv::dotenv().ok();
+
     // Get arguments
-    let path: String = if args.len() > 1 { args[1].clone() } else { "sample".to_string() };
-    let port: u16 = if args.len() > 2 { args[2].parse::<u16>().unwrap() } else { default_port };
-    let channel_buffer_size = 1000;
+    let mut config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    init_logging(config.log_level.as_deref());
+    let files_from = config.files_from.clone();
+    let inspect = config.inspect.clone();
+    let action = config.action;
+    let query = config.query.clone().unwrap_or_default();
+    let top_k = config.top_k;
+    // Output of runs without the menu stays in the terminal or log
+    let interactive = action.is_none() && files_from.is_none() && inspect.is_none();
+    if interactive {
+        clear_screen();
+    }
 
-    println!("\nConnecting to Helix instance at port {}", port);
+    // The menu asks for another folder when the given one cannot be indexed,
+    // loading the config again for it so that its .codebaseindex applies
+    while inspect.is_none() {
+        let Err(e) = config.check_path() else {
+            break;
+        };
+        eprintln!("\n{}", e);
+        if !interactive {
+            return 2;
+        }
+        println!("\nEnter the folder to index, or press enter to quit");
+        let input = read_input();
+        if input.is_empty() {
+            return 2;
+        }
+        config = match Config::load_for_path(&args, &input) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        };
+    }
+    let path = config.path.clone();
+    let root_name = config.root_name(Path::new(&path));
+    let port = config.port;
+    let helix_host = config.helix_host.clone();
+    let indexer = Arc::new(Indexer::new(config));
+    tokio::spawn(handle_interrupts(indexer.clone()));
+    if let Some(file) = inspect {
+        return inspect_file(&indexer, &file);
+    }
 
-    dotenv::dotenv().ok();
-    
-    let (tx, rx) = tokio::sync::mpsc::channel::<EmbeddingJob>(channel_buffer_size);
-
-    // Spawn the async background task for embedding jobs
-    tokio::spawn(async move {
-        // Set concurrent embeddings to better utilize our rate limit
-        let max_concurrent_embeddings = 100;
-        
-        // Create a stream from the channel
-        let mut job_stream = tokio_stream::wrappers::ReceiverStream::new(rx)
-            .map(|job| {
-                async move {
-                    let EmbeddingJob { chunk, entity_id, port } = job;
-                    if !chunk.is_empty() {
-                        PENDING_EMBEDDINGS.fetch_add(1, Ordering::SeqCst);
-                        match embed_entity_async(chunk).await {
-                            Ok(embedding) => {
-                                let url = format!("http://localhost:{}/{}", port, "embedSuperEntity");
-                                let payload = json!({"entity_id": entity_id,"vector": embedding,});
-                                if let Err(e) = post_request_async(&url, payload).await {
-                                    eprintln!("Failed to post embedding: {}", e);
-                                }
-                                COMPLETED_EMBEDDINGS.fetch_add(1, Ordering::SeqCst);
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to embed chunk: {}", e);
-                            }
-                        }
-                    }
-                }
-            })
-            .buffer_unordered(max_concurrent_embeddings);
-        
-        // Process the stream
-        while let Some(_) = job_stream.next().await {}
-    });
+    println!("\nConnecting to Helix instance at {}:{}", helix_host, port);
+    if let Err(e) = indexer.ping().await {
+        eprintln!("\n{}", e);
+        return 1;
+    }
 
-    let mut root_id = String::new();
+    let mut root_id = existing_root(&indexer, &root_name).await;
+    if let Some(source) = files_from {
+        return index_files(&indexer, &root_id, &path, &root_name, &source).await;
+    }
+    if let Some(action) = action {
+        return run_action(&indexer, action, &root_id, &path, &root_name, &query, top_k).await;
+    }
+    let mut had_failures = false;
 
     loop {
-        root_id = parse_user_input(root_id.clone(), path.clone(), port, tx.clone()).await.unwrap();
-        if root_id == "EXIT" {
-            break;
+        match parse_user_input(&indexer, &root_id, &path, &root_name).await {
+            Choice::Ran(Ok(summary)) if summary.interrupted => return 130,
+            Choice::Ran(Ok(summary)) => {
+                had_failures |= !summary.report.failures.is_empty();
+                root_id = summary.root_id;
+            }
+            Choice::Ran(Err(e)) => {
+                eprintln!("\n{}", e);
+                had_failures = true;
+            }
+            Choice::Selected(id) => root_id = id,
+            Choice::Deleted(id) if id == root_id => root_id.clear(),
+            Choice::Deleted(_) => {}
+            Choice::Invalid => {}
+            Choice::Exit => break,
         }
     }
+
+    if shut_down(&indexer).await > 0 {
+        had_failures = true;
+    }
+    if had_failures { 1 } else { 0 }
 }
 
-async fn parse_user_input(root_id: String, path: String, port: u16, tx: tokio::sync::mpsc::Sender<EmbeddingJob>) -> Result<String> {
-    let path_buf = PathBuf::from(path.clone());
-    let root_name = path_buf.file_name().unwrap().to_str().unwrap();
-    println!("\nWhat would you like to do?\n");
-    println!("1 : Ingest {}", &root_name);
-    println!("2 : Update {}", &root_name);
-    println!("3 : Exit");
-    
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    let input = input.trim().to_string();
-    let start_time = Instant::now();
-    if input == "1" {
-        let root_id = ingestion(
-            path_buf.canonicalize().expect("Failed to canonicalize path"),
-            port,
-            tx.clone(),
-        ).await;
+// Time queued embeddings get to finish before the process exits
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
-        clear_screen();
+// Let the embeddings still queued finish, warning about any left behind
+async fn shut_down(indexer: &Indexer) -> usize {
+    let unfinished = indexer.shutdown(SHUTDOWN_TIMEOUT).await;
+    if unfinished > 0 {
+        eprintln!("\nExiting with {} embeddings unfinished; verify the root with --repair to embed them", unfinished);
+    }
+    unfinished
+}
+
+// What Ctrl-C does, depending on what the menu is running
+#[derive(Clone, Copy)]
+enum OnInterrupt {
+    // Exit once queued embeddings have finished
+    Exit,
+    // Exit right away
+    Quit,
+    // Stop an ingest or update, then exit once it has wrapped up
+    StopRun,
+    // Watching stops on Ctrl-C by itself
+    Ignore,
+}
+
+static ON_INTERRUPT: Mutex<OnInterrupt> = Mutex::new(OnInterrupt::Exit);
+
+fn on_interrupt(action: OnInterrupt) {
+    *ON_INTERRUPT.lock().unwrap() = action;
+}
+
+// Handle Ctrl-C for the whole session. A second Ctrl-C while a run is stopping, or while
+// queued embeddings finish before exiting, quits right away.
+async fn handle_interrupts(indexer: Arc<Indexer>) {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        let action = *ON_INTERRUPT.lock().unwrap();
+        match action {
+            OnInterrupt::Exit => {
+                on_interrupt(OnInterrupt::Quit);
+                let indexer = indexer.clone();
+                tokio::spawn(async move {
+                    shut_down(&indexer).await;
+                    std::process::exit(130);
+                });
+            }
+            OnInterrupt::Quit => std::process::exit(130),
+            OnInterrupt::StopRun => {
+                indexer.stop();
+                on_interrupt(OnInterrupt::Quit);
+                println!("\nStopping: finishing the files in progress and the queued embeddings, press Ctrl-C again to quit now");
+            }
+            OnInterrupt::Ignore => {}
+        }
+    }
+}
+
+enum Choice {
+    Ran(Result<RunSummary, IndexError>),
+    // A root was picked for the following updates
+    Selected(String),
+    Deleted(String),
+    Invalid,
+    Exit,
+}
 
-        println!("\nTotal chunks processed: {}", TOTAL_CHUNKS.load(Ordering::SeqCst));
-        println!("\nIngestion finished in {} seconds", start_time.elapsed().as_secs());
-        wait_for_embeddings(start_time).await;
-        TOTAL_CHUNKS.store(0, Ordering::SeqCst);
+// Show the menu and run the chosen action
+async fn parse_user_input(indexer: &Indexer, root_id: &str, path: &str, root_name: &str) -> Choice {
+    let path_buf = PathBuf::from(path);
+    println!("\nWhat would you like to do?\n");
+    println!("1 : Ingest {}", root_name);
+    println!("2 : Update {}", root_name);
+    println!("3 : Watch {}", root_name);
+    println!("4 : List roots");
+    println!("5 : Delete a root");
+    println!("6 : Re-embed a root");
+    println!("7 : Verify a root's embeddings");
+    println!("8 : Show a root's stats");
+    println!("9 : Exit");
 
-        return Ok(root_id.unwrap().to_string());
+    let input = read_input();
+    let start_time = Instant::now();
+    if (input == "2" || input == "3") && root_id.is_empty() {
+        clear_screen();
+        println!("No root selected, ingest {} or select a root with List roots", root_name);
+        return Choice::Invalid;
+    }
+    if input == "1" {
+        on_interrupt(OnInterrupt::StopRun);
+        let result = with_progress(indexer, indexer.ingest(&path_buf), "Ingestion", start_time).await;
+        on_interrupt(OnInterrupt::Exit);
+        if let Ok(summary) = &result {
+            print_summary(summary, start_time);
+        }
+        return Choice::Ran(result);
     } else if input == "2" {
         clear_screen();
-        let root_ids = get_root_ids(port).await?;
-        if root_ids.contains(&root_id) {
-            println!("\nUpdating index...");
-            let _ = update(
-                path_buf.canonicalize().unwrap(), root_id.clone(), 
-                port, tx.clone(), 5
-            ).await;
-            println!("\nUpdate finished in {} seconds", start_time.elapsed().as_secs());
-            wait_for_embeddings(start_time).await;
-            TOTAL_CHUNKS.store(0, Ordering::SeqCst);
-            return Ok(root_id);
-        } else {
-            println!("\nNo root found");
-            return Ok(root_id);
+        println!("\nUpdating index...");
+        on_interrupt(OnInterrupt::StopRun);
+        let result = with_progress(indexer, indexer.update(&path_buf, root_id), "Update", start_time).await;
+        on_interrupt(OnInterrupt::Exit);
+        if let Ok(summary) = &result {
+            print_summary(summary, start_time);
         }
+        return Choice::Ran(result);
     } else if input == "3" {
         clear_screen();
-        return Ok("EXIT".to_string());
+        println!("\nWatching for changes, press Ctrl-C to stop...");
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        on_interrupt(OnInterrupt::Ignore);
+        let result = indexer.watch(&path_buf, root_id, stop).await;
+        on_interrupt(OnInterrupt::Exit);
+        if let Ok(summary) = &result {
+            print_summary(summary, start_time);
+        }
+        return Choice::Ran(result);
+    } else if input == "4" {
+        clear_screen();
+        return select_root(indexer).await;
+    } else if input == "5" {
+        clear_screen();
+        return delete_root(indexer).await;
+    } else if input == "6" {
+        clear_screen();
+        return reembed_root(indexer).await;
+    } else if input == "7" {
+        clear_screen();
+        return verify_root(indexer).await;
+    } else if input == "8" {
+        clear_screen();
+        return show_stats(indexer).await;
+    } else if input == "9" {
+        clear_screen();
+        return Choice::Exit;
     }
 
     clear_screen();
     println!("Invalid input");
-    return Ok(root_id);
-}
-
-async fn wait_for_embeddings(start_time: Instant) {
-    use tokio::time::{sleep, Duration};
-    println!("Waiting for all embedding jobs to complete...");
-    if PENDING_EMBEDDINGS.load(Ordering::SeqCst) > 0 {
-        let start_amount = PENDING_EMBEDDINGS.load(Ordering::SeqCst);
-        let bar = ProgressBar::new(start_amount as u64);
-        bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {pos}/{len} ({per_sec}, ETA: {eta})").unwrap());
-        let mut last_completed = 0;
-        while COMPLETED_EMBEDDINGS.load(Ordering::SeqCst) < PENDING_EMBEDDINGS.load(Ordering::SeqCst) {
-            sleep(Duration::from_millis(100)).await;
-            let completed = COMPLETED_EMBEDDINGS.load(Ordering::SeqCst);
-            let pending = PENDING_EMBEDDINGS.load(Ordering::SeqCst);
-            if pending > start_amount {
-                bar.set_length(pending as u64);
+    Choice::Invalid
+}
+
+// Print the entities and chunks a file would be indexed into, with totals
+fn inspect_file(indexer: &Indexer, file: &str) -> i32 {
+    let inspection = match indexer.inspect(file) {
+        Ok(inspection) => inspection,
+        Err(e) => {
+            eprintln!("\n{}", e);
+            return 1;
+        }
+    };
+
+    let parsed = if inspection.parsed { "parsed with tree-sitter" } else { "no grammar, chunked as text" };
+    println!("\n{} ({}, {})", file, inspection.extension, parsed);
+    if inspection.parse_errors {
+        println!("  Syntax errors: ERROR nodes are not indexed");
+    }
+    if inspection.skipped {
+        println!("Skipped: the extension is not listed in the file types config");
+        return 0;
+    }
+    print_chunks(&inspection.chunks, 1);
+    for entity in &inspection.entities {
+        print_entity(entity, 1);
+    }
+
+    let mut chunks: Vec<&InspectedChunk> = inspection.chunks.iter().collect();
+    let mut entities = 0;
+    let mut pending: Vec<&InspectedEntity> = inspection.entities.iter().collect();
+    while let Some(entity) = pending.pop() {
+        entities += 1;
+        chunks.extend(&entity.chunks);
+        pending.extend(&entity.children);
+    }
+    let tokens: usize = chunks.iter().map(|chunk| chunk.tokens).sum();
+    println!("\nEntities: {}, chunks to embed: {}, estimated tokens: {}", entities, chunks.len(), tokens);
+    0
+}
+
+fn print_entity(entity: &InspectedEntity, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let name = entity.name.as_deref().map(|name| format!(" {}", name)).unwrap_or_default();
+    println!(
+        "{}{}{} [{}..{}] order {}",
+        indent, entity.kind, name, entity.start_byte, entity.end_byte, entity.order,
+    );
+    print_chunks(&entity.chunks, depth + 1);
+    for child in &entity.children {
+        print_entity(child, depth + 1);
+    }
+}
+
+fn print_chunks(chunks: &[InspectedChunk], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for chunk in chunks {
+        println!(
+            "{}chunk [{}..{}] {} chars, ~{} tokens: {}",
+            indent, chunk.start_byte, chunk.end_byte, chunk.chars, chunk.tokens, chunk.preview,
+        );
+    }
+}
+
+// Index the files listed in a file or on stdin into the existing root, without the menu
+async fn index_files(indexer: &Indexer, root_id: &str, path: &str, root_name: &str, source: &str) -> i32 {
+    if root_id.is_empty() {
+        eprintln!("\nNo single root named {} to add the files to, ingest it first", root_name);
+        return 1;
+    }
+    let files = match read_file_list(source) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("\n{}", e);
+            return 2;
+        }
+    };
+
+    let start_time = Instant::now();
+    on_interrupt(OnInterrupt::StopRun);
+    let result = with_progress(indexer, indexer.index_files(path, root_id, files), "Indexing", start_time).await;
+    exit_code(result, start_time)
+}
+
+// Run a single action without the menu and return the exit code
+async fn run_action(indexer: &Indexer, action: Action, root_id: &str, path: &str, root_name: &str, query: &str, top_k: usize) -> i32 {
+    let start_time = Instant::now();
+    let path_buf = PathBuf::from(path);
+    match action {
+        Action::Ingest => {
+            on_interrupt(OnInterrupt::StopRun);
+            let result = with_progress(indexer, indexer.ingest(&path_buf), "Ingestion", start_time).await;
+            exit_code(result, start_time)
+        }
+        Action::Update if root_id.is_empty() => {
+            eprintln!("\nNo single root named {} to update, ingest it first", root_name);
+            1
+        }
+        Action::Update => {
+            on_interrupt(OnInterrupt::StopRun);
+            let result = with_progress(indexer, indexer.update(&path_buf, root_id), "Update", start_time).await;
+            exit_code(result, start_time)
+        }
+        Action::Search => match indexer.search(query, top_k).await {
+            Ok(entities) => {
+                println!("\n{} results for {:?}:\n", entities.len(), query);
+                for (index, entity) in entities.iter().enumerate() {
+                    let field = |name: &str| entity.get(name).map(|value| value.as_str().map_or(value.to_string(), str::to_string)).unwrap_or_default();
+                    println!(
+                        "{} : {} {} [{}..{}] ({})",
+                        index + 1, field("entity_type"), field("name"), field("start_byte"), field("end_byte"), field("id"),
+                    );
+                }
+                0
+            }
+            Err(e) => {
+                eprintln!("\n{}", e);
+                1
             }
-            if completed > last_completed {
-                let diff = completed - last_completed;
-                bar.inc(diff as u64);
-                last_completed = completed;
+        },
+    }
+}
+
+// Print the summary of a run and map its outcome to an exit code: 0 on success,
+// 1 when it failed or any file failed, 2 for settings that keep it from running,
+// 130 when it was interrupted
+fn exit_code(result: Result<RunSummary, IndexError>, start_time: Instant) -> i32 {
+    match result {
+        Ok(summary) => {
+            print_summary(&summary, start_time);
+            if summary.interrupted { 130 } else if summary.report.failures.is_empty() { 0 } else { 1 }
+        }
+        Err(e) => {
+            eprintln!("\n{}", e);
+            if matches!(e, IndexError::Config(_)) { 2 } else { 1 }
+        }
+    }
+}
+
+// Paths listed one per line in a file, or on stdin for "-"; blank lines are skipped
+fn read_file_list(source: &str) -> Result<Vec<PathBuf>> {
+    let contents = if source == "-" {
+        io::read_to_string(io::stdin())
+            .map_err(|e| anyhow::anyhow!("Failed to read the file list from stdin: {}", e))?
+    } else {
+        std::fs::read_to_string(source)
+            .map_err(|e| anyhow::anyhow!("Failed to read the file list {}: {}", source, e))?
+    };
+    Ok(contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+// The root ingested from this folder in an earlier session, if there is exactly one
+async fn existing_root(indexer: &Indexer, root_name: &str) -> String {
+    match indexer.root_id_by_name(root_name).await {
+        Ok(Some(root_id)) => {
+            println!("Using root {} ({})", root_name, root_id);
+            root_id
+        }
+        Ok(None) => String::new(),
+        Err(e) => {
+            println!("{}, select one with List roots", e);
+            String::new()
+        }
+    }
+}
+
+// List the roots in the index and let the user pick one
+async fn select_root(indexer: &Indexer) -> Choice {
+    match pick_root(indexer, "select").await {
+        Ok(Some(root)) => {
+            println!("Selected root {} ({})", root.name, root.id);
+            Choice::Selected(root.id)
+        }
+        Ok(None) => Choice::Invalid,
+        Err(e) => Choice::Ran(Err(e)),
+    }
+}
+
+// Let the user pick a root and delete it after confirming
+async fn delete_root(indexer: &Indexer) -> Choice {
+    let root = match pick_root(indexer, "delete").await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Choice::Invalid,
+        Err(e) => return Choice::Ran(Err(e)),
+    };
+
+    println!("Delete {} ({}) and everything indexed under it? [y/N]", root.name, root.id);
+    let input = read_input();
+    clear_screen();
+    if !input.eq_ignore_ascii_case("y") {
+        println!("Nothing deleted");
+        return Choice::Invalid;
+    }
+
+    println!("\nDeleting {}...", root.name);
+    match indexer.delete_root(&root.id).await {
+        Ok(()) => {
+            println!("Deleted root {} ({})", root.name, root.id);
+            Choice::Deleted(root.id)
+        }
+        Err(e) => Choice::Ran(Err(e)),
+    }
+}
+
+// Let the user pick a root and embed its entities again with the current embedding settings
+async fn reembed_root(indexer: &Indexer) -> Choice {
+    let root = match pick_root(indexer, "re-embed").await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Choice::Invalid,
+        Err(e) => return Choice::Ran(Err(e)),
+    };
+
+    println!("\nRe-embedding {}...", root.name);
+    let start_time = Instant::now();
+    on_interrupt(OnInterrupt::StopRun);
+    let result = with_progress(indexer, indexer.reembed(&root.id), "Re-embedding", start_time).await;
+    on_interrupt(OnInterrupt::Exit);
+    if let Ok(summary) = &result {
+        print_summary(summary, start_time);
+    }
+    Choice::Ran(result)
+}
+
+// Let the user pick a root and check that its entities have embeddings,
+// embedding the ones without with --repair
+async fn verify_root(indexer: &Indexer) -> Choice {
+    let root = match pick_root(indexer, "verify").await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Choice::Invalid,
+        Err(e) => return Choice::Ran(Err(e)),
+    };
+
+    println!("\nVerifying {}...", root.name);
+    let start_time = Instant::now();
+    on_interrupt(OnInterrupt::StopRun);
+    let result = with_progress(indexer, indexer.verify(&root.id), "Verification", start_time).await;
+    on_interrupt(OnInterrupt::Exit);
+    if let Ok(summary) = &result {
+        print_summary(summary, start_time);
+    }
+    Choice::Ran(result)
+}
+
+// Let the user pick a root and print what is indexed under it
+async fn show_stats(indexer: &Indexer) -> Choice {
+    let root = match pick_root(indexer, "show the stats of").await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Choice::Invalid,
+        Err(e) => return Choice::Ran(Err(e)),
+    };
+
+    println!("\nCounting {}...", root.name);
+    let stats = match indexer.stats(&root.id).await {
+        Ok(stats) => stats,
+        Err(e) => return Choice::Ran(Err(e)),
+    };
+    println!("\n{} ({})", root.name, root.id);
+    match &root.embedding {
+        Some(embedding) => println!("Embedded with: {}", embedding),
+        None => println!("Embedded with: not recorded"),
+    }
+    println!("Folders: {}", stats.folders);
+    println!("Files: {}", stats.files);
+    println!("Entities: {}", stats.entities);
+    println!("Embeddings: {} (for {} entities)", stats.embeddings, stats.embedded_entities);
+    Choice::Invalid
+}
+
+// List the roots in the index and read the user's pick, if any
+async fn pick_root(indexer: &Indexer, action: &str) -> Result<Option<Root>, IndexError> {
+    let mut roots = indexer.roots().await?;
+    if roots.is_empty() {
+        println!("\nNo roots in the index");
+        return Ok(None);
+    }
+
+    println!("\nRoots in the index:\n");
+    for (index, root) in roots.iter().enumerate() {
+        match &root.embedding {
+            Some(embedding) => println!("{} : {} ({}), embedded with {}", index + 1, root.name, root.id, embedding),
+            None => println!("{} : {} ({})", index + 1, root.name, root.id),
+        }
+    }
+    println!("\nEnter a number to {} a root, or press enter to go back", action);
+
+    let input = read_input();
+    clear_screen();
+    let index = input.parse::<usize>().ok()
+        .and_then(|index| index.checked_sub(1))
+        .filter(|index| *index < roots.len());
+    Ok(index.map(|index| roots.swap_remove(index)))
+}
+
+fn read_input() -> String {
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    input.trim().to_string()
+}
+
+// Drive a run, showing an overall bar with an ETA over both files and embeddings,
+// and a bar for each of the two phases. Embedding starts while files are still
+// processed, so all three are shown from the start.
+async fn with_progress(
+    indexer: &Indexer,
+    run: impl Future<Output = Result<RunSummary, IndexError>>,
+    label: &str,
+    start_time: Instant,
+) -> Result<RunSummary, IndexError> {
+    tokio::pin!(run);
+    let bars = MultiProgress::new();
+    let total_bar = bars.add(new_bar("total"));
+    let file_bar = bars.add(new_bar("files"));
+    let embedding_bar = bars.add(new_bar("embeddings"));
+    let mut walked = false;
+
+    loop {
+        tokio::select! {
+            result = &mut run => {
+                total_bar.finish();
+                file_bar.finish();
+                embedding_bar.finish();
+                if let (false, Ok(summary)) = (walked, &result) {
+                    print_walk_finished(&bars, summary.chunks, label, start_time);
+                }
+                return result;
             }
+            _ = sleep(Duration::from_millis(100)) => {}
+        }
+
+        let progress = indexer.progress();
+        // Discovery runs ahead of processing, so the length grows as folders are walked
+        file_bar.set_length(progress.files_discovered as u64);
+        file_bar.set_position(progress.files_processed as u64);
+
+        let embedded = (progress.embeddings_completed + progress.embeddings_failed) as u64;
+        embedding_bar.set_length(progress.embeddings_pending as u64);
+        embedding_bar.set_position(embedded);
+
+        // A file and an embedding count as one step each towards the total
+        total_bar.set_length((progress.files_discovered + progress.expected_embeddings()) as u64);
+        total_bar.set_position(progress.files_processed as u64 + embedded);
+
+        if !progress.walking && !walked {
+            walked = true;
+            file_bar.finish();
+            print_walk_finished(&bars, progress.chunks, label, start_time);
+        }
+    }
+}
+
+fn new_bar(unit: &str) -> ProgressBar {
+    let bar = ProgressBar::new(0);
+    let template = format!("[{{elapsed_precise}}] {{wide_bar}} {{pos}}/{{len}} {} ({{per_sec}}, ETA: {{eta}})", unit);
+    bar.set_style(ProgressStyle::with_template(&template).unwrap());
+    bar
+}
+
+fn print_walk_finished(bars: &MultiProgress, chunks: usize, label: &str, start_time: Instant) {
+    let _ = bars.println(format!("\nTotal chunks processed: {}", chunks));
+    let _ = bars.println(format!("\n{} finished in {} seconds", label, start_time.elapsed().as_secs()));
+    let _ = bars.println("Waiting for all embedding jobs to complete...");
+}
+
+// Counts of a root on one line, to compare roots before and after a clean ingest
+fn stats_line(stats: &RootStats) -> String {
+    format!(
+        "{} folders, {} files, {} entities, {} embeddings",
+        stats.folders, stats.files, stats.entities, stats.embeddings,
+    )
+}
+
+fn print_summary(summary: &RunSummary, start_time: Instant) {
+    if let Some(dry_run) = &summary.dry_run {
+        println!(
+            "\nDry run: {} to create, {} to update, {} to delete",
+            dry_run.created, dry_run.updated, dry_run.deleted,
+        );
+        return;
+    }
+
+    if let Some(verify) = &summary.verify {
+        println!(
+            "\nEntities with embeddings: {}, missing embeddings: {}, repaired: {}",
+            verify.ok, verify.missing, verify.repaired,
+        );
+        if verify.missing > 0 && summary.chunks == 0 {
+            println!("Run again with --repair to embed the entities missing embeddings");
         }
-        bar.finish();
     }
-    println!("\nTotal embeddings completed: {}", COMPLETED_EMBEDDINGS.load(Ordering::SeqCst));
+    if summary.interrupted {
+        println!("\nStopped before every file was indexed; ingest with --resume or update the root to continue");
+    }
+    println!(
+        "\nFiles walked: {}, entities created: {}, chunks: {}",
+        summary.files, summary.entities, summary.chunks,
+    );
+    println!("Total embeddings completed: {}", summary.embeddings);
+    if summary.embeddings_failed > 0 {
+        println!("Embeddings failed after retries: {}", summary.embeddings_failed);
+    }
+    match summary.estimated_cost {
+        Some(cost) => println!("Estimated embedding tokens: {} (about ${:.4})", summary.tokens, cost),
+        None => println!("Estimated embedding tokens: {}", summary.tokens),
+    }
+    if summary.oversized_chunks > 0 {
+        println!("Chunks over the embedding input limit: {}", summary.oversized_chunks);
+    }
+    println!("Embeddings per second: {:.1}", summary.embeddings_per_second());
     println!("\nTotal time taken: {} seconds", start_time.elapsed().as_secs_f64());
-    PENDING_EMBEDDINGS.store(0, Ordering::SeqCst);
-    COMPLETED_EMBEDDINGS.store(0, Ordering::SeqCst);
+
+    if let Some(clean) = &summary.clean {
+        if clean.replaced.is_empty() {
+            println!("\nNo root of the same name to replace");
+        }
+        let action = if clean.kept { "Kept" } else { "Replaced" };
+        for (root_id, before) in &clean.replaced {
+            println!("\n{} root {}: {}", action, root_id, stats_line(before));
+        }
+        if clean.kept && !clean.replaced.is_empty() {
+            println!("Files or embeddings of the new root failed, so the roots of the same name were kept");
+        }
+        println!("New root {}: {}", summary.root_id, stats_line(&clean.after));
+    }
+
+    let report = &summary.report;
+    println!(
+        "\nSkipped files: {}, unsupported files: {}, failed files: {}",
+        report.skipped_files, report.unsupported_files, report.failed_files()
+    );
+    if report.deduplicated_files > 0 {
+        println!("Duplicate files linked to existing entities: {}", report.deduplicated_files);
+    }
+    if report.unchanged_entities > 0 {
+        println!("Unchanged entities of updated files, embeddings kept: {}", report.unchanged_entities);
+    }
+    if report.unchanged_folders > 0 {
+        println!("Unchanged folders skipped: {}", report.unchanged_folders);
+    }
+    if report.parse_error_files > 0 {
+        println!("Files with syntax errors (ERROR nodes skipped): {}", report.parse_error_files);
+    }
+    if !report.over_chunk_limit.is_empty() {
+        println!("Files over max-chunks-per-file, only their first chunks embedded: {}", report.over_chunk_limit.len());
+        for path in &report.over_chunk_limit {
+            println!("  {}", path);
+        }
+    }
+    if !report.slowest_files.is_empty() {
+        println!("\nSlowest files:");
+        for (path, time) in &report.slowest_files {
+            println!("  {:>8.2}s {}", time.as_secs_f64(), path);
+        }
+    }
+    if report.failures.is_empty() {
+        return;
+    }
+
+    println!("\nFailures:");
+    for (stage, stage_failures) in report.failures_by_stage() {
+        println!("  {} ({})", stage, stage_failures.len());
+        for failure in stage_failures {
+            println!("    {}: {}", failure.path, failure.error);
+        }
+    }
 }
 
-fn clear_screen() {
-    clearscreen::clear().expect("Failed to clear screen");
+// Log to stderr, filtered by --log-level, then RUST_LOG, defaulting to info
+fn init_logging(log_level: Option<&str>) {
+    let filter = match log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 }
 
-async fn get_root_ids(port: u16) -> Result<Vec<String>> {
-    let url = format!("http://localhost:{}/{}", port, "getRoot");
-    let response = post_request_async(&url, json!({})).await?;
-    let root_ids = response
-        .get("root")
-        .and_then(|v| v.as_array())
-        .map(|v| v.iter().map(|v| v.get("id").and_then(|v| v.as_str()).unwrap().to_string()).collect())
-        .ok_or_else(|| anyhow::anyhow!("Root ID not found"))?;
-    Ok(root_ids)
+// Not being able to clear, e.g. when output is not a terminal, is not an error
+fn clear_screen() {
+    let _ = clearscreen::clear();
 }
 
 fn main() {
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-    
-    rt.block_on(async_main());
+    let exit_code = match codebase_index::block_on(async_main()) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    };
+    std::process::exit(exit_code);
 }
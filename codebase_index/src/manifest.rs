@@ -0,0 +1,76 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Files and entities a run wrote to Helix, with the ids Helix returned for them
+#[derive(Debug, Default)]
+pub struct Manifest {
+    files: Vec<(PathBuf, String)>,
+    entities: Vec<ManifestEntity>,
+}
+
+// Entity as created in Helix; parent_id is left out for top level entities
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntity {
+    pub entity_id: String,
+    #[serde(skip)]
+    pub file_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    pub entity_type: String,
+    pub name: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub order: usize,
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    path: String,
+    file_id: &'a str,
+    entities: Vec<&'a ManifestEntity>,
+}
+
+#[derive(Serialize)]
+struct ManifestOutput<'a> {
+    root_id: &'a str,
+    files: Vec<ManifestFile<'a>>,
+}
+
+impl Manifest {
+    pub fn record_file(&mut self, path: &Path, file_id: &str) {
+        self.files.push((path.to_path_buf(), file_id.to_string()));
+    }
+
+    pub fn record_entity(&mut self, entity: ManifestEntity) {
+        self.entities.push(entity);
+    }
+
+    // Write the manifest as JSON, with file paths relative to root_path. Files are
+    // sorted by path and their entities by position, so two runs over the same tree
+    // can be diffed once the ids are ignored.
+    pub fn write(&self, path: &Path, root_path: &Path, root_id: &str) -> Result<()> {
+        let mut by_file: BTreeMap<&str, Vec<&ManifestEntity>> = BTreeMap::new();
+        for entity in &self.entities {
+            by_file.entry(entity.file_id.as_str()).or_default().push(entity);
+        }
+
+        let mut files: Vec<ManifestFile> = self.files.iter().map(|(file_path, file_id)| {
+            let mut entities = by_file.remove(file_id.as_str()).unwrap_or_default();
+            // Parents before the children that start at the same byte
+            entities.sort_by_key(|entity| (entity.start_byte, std::cmp::Reverse(entity.end_byte), entity.parent_id.is_some()));
+            ManifestFile {
+                path: file_path.strip_prefix(root_path).unwrap_or(file_path).to_string_lossy().to_string(),
+                file_id,
+                entities,
+            }
+        }).collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let output = ManifestOutput { root_id, files };
+        fs::write(path, serde_json::to_string_pretty(&output)?)
+            .map_err(|e| anyhow::anyhow!("Failed to write manifest {}: {}", path.display(), e))
+    }
+}
@@ -1,36 +1,188 @@
 use anyhow::Result;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use serde_json::json;
+use tracing::warn;
 
-use crate::utils::{post_request_async};
+use crate::endpoints;
+use crate::utils::{helix_url, post_request_async};
+
+// Folders or files under a parent, by name. Helix does not keep names unique, so
+// entries whose name is already taken go to duplicates instead of being dropped.
+#[derive(Debug, Clone)]
+pub struct Listing<T> {
+    pub by_name: HashMap<String, T>,
+    pub duplicates: Vec<(String, T)>,
+}
+
+impl<T> Listing<T> {
+    // Every entry with its name, duplicates included
+    pub fn into_named(self) -> impl Iterator<Item = (String, T)> {
+        self.by_name.into_iter().chain(self.duplicates)
+    }
+
+    // Every entry, duplicates included
+    pub fn into_all(self) -> impl Iterator<Item = T> {
+        self.into_named().map(|(_, entry)| entry)
+    }
+}
+
+// Group entries by name. When a name repeats, the entry for which prefer returns
+// true over the one kept so far takes its place.
+fn listing<T>(
+    entries: Vec<(String, T)>,
+    what: &str,
+    parent_id: &str,
+    prefer: impl Fn(&T, &T) -> bool,
+) -> Listing<T> {
+    let mut listing = Listing { by_name: HashMap::new(), duplicates: Vec::new() };
+    for (name, entry) in entries {
+        match listing.by_name.entry(name) {
+            Entry::Vacant(slot) => {
+                slot.insert(entry);
+            }
+            Entry::Occupied(mut slot) => {
+                warn!("Helix has more than one {} named {} under {}", what, slot.key(), parent_id);
+                let name = slot.key().clone();
+                if prefer(&entry, slot.get()) {
+                    listing.duplicates.push((name, slot.insert(entry)));
+                } else {
+                    listing.duplicates.push((name, entry));
+                }
+            }
+        }
+    }
+    listing
+}
+
+// Keep the first folder Helix returns under a name
+fn folder_listing(folders: &[serde_json::Value], parent_id: &str) -> Result<Listing<String>> {
+    let entries = folders.iter().map(|folder| {
+        let folder_id = folder.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder ID not found"))?;
+        let folder_name = folder.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder name not found"))?;
+        Ok((folder_name.to_string(), folder_id.to_string()))
+    }).collect::<Result<Vec<_>>>()?;
+    Ok(listing(entries, "folder", parent_id, |_, _| false))
+}
+
+// Keep the most recently extracted file under a name. extracted_at is an RFC 3339
+// UTC timestamp, so the strings order like the times.
+fn file_listing(files: &[serde_json::Value], parent_id: &str) -> Result<Listing<FileRecord>> {
+    let entries = files.iter().map(parse_file_record).collect::<Result<Vec<_>>>()?;
+    Ok(listing(entries, "file", parent_id, |file, kept| file.extracted_at > kept.extracted_at))
+}
+
+// Indexed file as returned by the file queries
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub id: String,
+    pub extracted_at: String,
+    // Content hash, missing for files indexed before hashes were stored
+    pub hash: Option<String>,
+}
+
+fn parse_file_record(file: &serde_json::Value) -> Result<(String, FileRecord)> {
+    let file_id = file.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File ID not found"))?;
+    let file_name = file.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File name not found"))?;
+    let file_extracted_at = file.get("extracted_at").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File extracted at not found"))?;
+    let file_hash = file.get("hash").and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    Ok((file_name.to_string(), FileRecord {
+        id: file_id.to_string(),
+        extracted_at: file_extracted_at.to_string(),
+        hash: file_hash.map(|s| s.to_string()),
+    }))
+}
+
+// Indexed entity as returned by the entity queries
+#[derive(Debug, Clone)]
+pub struct EntityRecord {
+    pub id: String,
+    pub entity_type: String,
+    // Label from index-types.json, the entity type for entities indexed before labels were stored
+    pub label: String,
+    pub name: String,
+    // Empty when the entity was stored with omit_entity_text
+    pub text: String,
+    // Entity hash, missing for entities indexed before hashes were stored
+    pub hash: Option<String>,
+    pub start_byte: i64,
+    pub end_byte: i64,
+    pub order: i64,
+}
+
+fn parse_entity_record(entity: &serde_json::Value) -> Result<EntityRecord> {
+    let entity_id = entity.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Entity ID not found"))?;
+    let field = |name: &str| entity.get(name).and_then(|v| v.as_i64()).ok_or_else(|| anyhow::anyhow!("Entity {} not found", name));
+    let entity_hash = entity.get("hash").and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    let text = |name: &str| entity.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let label = Some(text("label")).filter(|label| !label.is_empty()).unwrap_or_else(|| text("entity_type"));
+    Ok(EntityRecord {
+        id: entity_id.to_string(),
+        entity_type: text("entity_type"),
+        label,
+        name: text("name"),
+        text: text("text"),
+        hash: entity_hash.map(|s| s.to_string()),
+        start_byte: field("start_byte")?,
+        end_byte: field("end_byte")?,
+        order: field("order")?,
+    })
+}
+
+// Id of the root with a name, None if there is none.
+// Fails when several roots share the name, since the right one cannot be told apart.
+pub async fn get_root_id_by_name(
+    name: &str,
+    port: u16
+) -> Result<Option<String>> {
+    let url = helix_url(port, endpoints::GET_ROOT_BY_NAME);
+    let response = post_request_async(&url, json!({ "name": name })).await?;
+    let roots = response
+        .get("roots")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| anyhow::anyhow!("Roots not found in response"))?;
+
+    let mut ids = roots.iter().filter_map(|root| root.get("id").and_then(|v| v.as_str()));
+    match (ids.next(), ids.next()) {
+        (Some(id), None) => Ok(Some(id.to_string())),
+        (None, _) => Ok(None),
+        (Some(_), Some(_)) => Err(anyhow::anyhow!("{} roots are named {}", roots.len(), name)),
+    }
+}
 
 pub async fn get_root_folders (
     root_id: String,
     port: u16
-) -> Result<HashMap<String, String>> {
-    let url = format!("http://localhost:{}/{}", port, "getRootFolders");
+) -> Result<Listing<String>> {
+    let url = helix_url(port, endpoints::GET_ROOT_FOLDERS);
     let root_folder_res = post_request_async(&url, json!({ "root_id": root_id })).await?;
     let root_folders = root_folder_res
         .get("folders")
         .and_then(|v| v.as_array())
         .ok_or_else(|| anyhow::anyhow!("Root ID not found"))?;
 
-    let mut root_folder_name_ids = HashMap::new();
+    folder_listing(root_folders, &root_id)
+}
 
-    for folder in root_folders {
-        let folder_id = folder.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder ID not found"))?;
-        let folder_name = folder.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder name not found"))?;
-        root_folder_name_ids.insert(folder_name.to_string(), folder_id.to_string());
-    }
+// Fingerprint stored for a folder, missing for folders indexed before fingerprints were stored
+pub async fn get_folder_hash(folder_id: String, port: u16) -> Result<Option<String>> {
+    let url = helix_url(port, endpoints::GET_FOLDER);
+    let response = post_request_async(&url, json!({ "folder_id": folder_id })).await?;
+    let folder = response.get("folder").ok_or_else(|| anyhow::anyhow!("Folder {} not found", folder_id))?;
+    Ok(folder.get("hash").and_then(|v| v.as_str()).filter(|s| !s.is_empty()).map(|s| s.to_string()))
+}
 
-    Ok(root_folder_name_ids)
+pub async fn update_folder_hash(folder_id: String, hash: String, port: u16) -> Result<()> {
+    let url = helix_url(port, endpoints::UPDATE_FOLDER_HASH);
+    post_request_async(&url, json!({ "folder_id": folder_id, "hash": hash })).await?;
+    Ok(())
 }
 
 pub async fn get_root_files (
     root_id: String,
     port: u16
-) -> Result<HashMap<String, (String, String)>> {
-    let url = format!("http://localhost:{}/{}", port, "getRootFiles");
+) -> Result<Listing<FileRecord>> {
+    let url = helix_url(port, endpoints::GET_ROOT_FILES);
     let payload = json!({ "root_id": root_id });
     let root_file_res = post_request_async(&url, payload).await?;
     let root_files = root_file_res
@@ -38,23 +190,14 @@ pub async fn get_root_files (
         .and_then(|v| v.as_array())
         .ok_or_else(|| anyhow::anyhow!("Root ID not found"))?;
 
-    let mut root_file_name_ids: HashMap<String, (String, String)> = HashMap::new();
-
-    for file in root_files {
-        let file_id = file.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File ID not found"))?;
-        let file_name = file.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File name not found"))?;
-        let file_extracted_at = file.get("extracted_at").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File extracted at not found"))?;
-        root_file_name_ids.insert(file_name.to_string(), (file_id.to_string(), file_extracted_at.to_string()));
-    }
-
-    Ok(root_file_name_ids)
+    file_listing(root_files, &root_id)
 }
 
 pub async fn get_sub_folders (
     folder_id: String,
     port: u16
-) -> Result<HashMap<String, String>> {
-    let url = format!("http://localhost:{}/{}", port, "getSubFolders");
+) -> Result<Listing<String>> {
+    let url = helix_url(port, endpoints::GET_SUB_FOLDERS);
     let payload = json!({ "folder_id": folder_id });
     let folder_res = post_request_async(&url, payload).await?;
     let subfolders = folder_res
@@ -62,22 +205,14 @@ pub async fn get_sub_folders (
         .and_then(|v| v.as_array())
         .ok_or_else(|| anyhow::anyhow!("Folder ID not found"))?;
 
-    let mut subfolder_name_ids = HashMap::new();
-
-    for folder in subfolders {
-        let folder_id = folder.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder ID not found"))?;
-        let folder_name = folder.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("Folder name not found"))?;
-        subfolder_name_ids.insert(folder_name.to_string(), folder_id.to_string());
-    }
-
-    Ok(subfolder_name_ids)    
+    folder_listing(subfolders, &folder_id)
 }
 
 pub async fn get_folder_files (
     folder_id: String,
     port: u16
-) -> Result<HashMap<String, (String, String)>> {
-    let url = format!("http://localhost:{}/{}", port, "getFolderFiles");
+) -> Result<Listing<FileRecord>> {
+    let url = helix_url(port, endpoints::GET_FOLDER_FILES);
     let payload = json!({ "folder_id": folder_id });
     let folder_file_res = post_request_async(&url, payload).await?;
     let folder_files = folder_file_res
@@ -85,14 +220,61 @@ pub async fn get_folder_files (
         .and_then(|v| v.as_array())
         .ok_or_else(|| anyhow::anyhow!("Folder ID not found"))?;
 
-    let mut folder_file_name_ids: HashMap<String, (String, String)> = HashMap::new();
+    file_listing(folder_files, &folder_id)
+}
 
-    for file in folder_files {
-        let file_id = file.get("id").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File ID not found"))?;
-        let file_name = file.get("name").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File name not found"))?;
-        let file_extracted_at = file.get("extracted_at").and_then(|v| v.as_str()).ok_or_else(|| anyhow::anyhow!("File extracted at not found"))?;
-        folder_file_name_ids.insert(file_name.to_string(), (file_id.to_string(), file_extracted_at.to_string()));
-    }
+pub async fn get_file_entities (
+    file_id: String,
+    port: u16
+) -> Result<Vec<EntityRecord>> {
+    let url = helix_url(port, endpoints::GET_FILE_ENTITIES);
+    let payload = json!({ "file_id": file_id });
+    let file_entity_res = post_request_async(&url, payload).await?;
+    file_entity_res
+        .get("entity")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| anyhow::anyhow!("Entities not found"))?
+        .iter()
+        .map(parse_entity_record)
+        .collect()
+}
+
+pub async fn get_sub_entities (
+    entity_id: String,
+    port: u16
+) -> Result<Vec<EntityRecord>> {
+    let url = helix_url(port, endpoints::GET_SUB_ENTITIES);
+    let payload = json!({ "entity_id": entity_id });
+    let sub_entity_res = post_request_async(&url, payload).await?;
+    sub_entity_res
+        .get("entities")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| anyhow::anyhow!("Entities not found"))?
+        .iter()
+        .map(parse_entity_record)
+        .collect()
+}
 
-    Ok(folder_file_name_ids)
-}
\ No newline at end of file
+// Text of a file as stored in Helix, with the name of its language
+#[derive(Debug, Clone)]
+pub struct FileContent {
+    pub text: String,
+    // Empty for files without a grammar and files indexed before it was stored
+    pub language: String,
+}
+
+pub async fn get_file_content(
+    file_id: String,
+    port: u16
+) -> Result<FileContent> {
+    let url = helix_url(port, endpoints::GET_FILE_CONTENT);
+    let response = post_request_async(&url, json!({ "file_id": file_id })).await?;
+    // A single file may come back on its own or in a one element list
+    let file = response.get("file").map(|file| file.get(0).unwrap_or(file));
+    let text = file
+        .and_then(|file| file.get("text"))
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("File text not found"))?;
+    let language = file.and_then(|file| file.get("language")).and_then(|v| v.as_str());
+    Ok(FileContent { text: text.to_string(), language: language.unwrap_or_default().to_string() })
+}
@@ -0,0 +1,272 @@
+use anyhow::Result;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+use crate::endpoints;
+use crate::config::config;
+use crate::queries::{
+    get_file_content, get_file_entities, get_folder_files, get_root_files, get_root_folders, get_sub_entities,
+    get_sub_folders, EntityRecord,
+};
+use crate::ingestion::queue_entity_embeddings;
+use crate::report::Stage;
+use crate::run::Run;
+use crate::utils::{chunk_entity, helix_url, post_request_async, run_tasks, EmbeddingJob, FILE_PERMITS};
+
+// Sub entities embedded on their own, next to every top level entity
+pub const EMBEDDED_SUB_ENTITIES: &[&str] = &["comment", "docstring"];
+
+// Embedding ids of the entities queued by reembed, by entity id
+pub type OldEmbeddings = HashMap<String, Vec<String>>;
+
+// Embed every entity of a root again from its stored text, without reading or
+// parsing the files on disk. Returns the ids of the vectors the new ones replace,
+// which are left in place so an entity is still found if its embedding fails.
+pub async fn reembed(root_id: String, port: u16, run: Arc<Run>) -> Result<OldEmbeddings> {
+    let tree = root_tree(root_id, port).await?;
+    run.files_discovered.fetch_add(tree.file_ids.len(), Ordering::SeqCst);
+    for (file_id, path) in tree.file_ids.iter().zip(tree.file_paths) {
+        run.record_file_path(file_id, path);
+    }
+
+    // Deduplicated copies of a file share its entities, which are embedded once
+    let claimed = Arc::new(Mutex::new(HashSet::new()));
+    let old = Arc::new(Mutex::new(OldEmbeddings::new()));
+    let tasks: Vec<_> = tree.file_ids.into_iter().map(|file_id| {
+        let run = run.clone();
+        let claimed = claimed.clone();
+        let old = old.clone();
+        async move {
+            if run.stop_requested() {
+                return Ok(());
+            }
+            let _permit = FILE_PERMITS.acquire().await?;
+            if let Err(e) = reembed_file(&file_id, port, &run, &claimed, &old).await {
+                run.record_failure(format!("file {}", file_id), Stage::Reembed, e);
+            }
+            run.files_processed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }).collect();
+    run_tasks(tasks).await?;
+    let old = std::mem::take(&mut *old.lock().unwrap());
+    Ok(old)
+}
+
+// Folders and files under a root
+pub struct RootTree {
+    pub folders: usize,
+    pub file_ids: Vec<String>,
+    // Path from the root of each file, in the order of file_ids
+    pub file_paths: Vec<PathBuf>,
+}
+
+// Number of folders and ids and paths of every file under a root, fetched one folder level at a time
+pub async fn root_tree(root_id: String, port: u16) -> Result<RootTree> {
+    let mut files: Vec<(String, PathBuf)> = get_root_files(root_id.clone(), port).await?
+        .into_named()
+        .map(|(name, record)| (record.id, PathBuf::from(name)))
+        .collect();
+    let mut level: Vec<(String, PathBuf)> = get_root_folders(root_id, port).await?
+        .into_named()
+        .map(|(name, folder_id)| (folder_id, PathBuf::from(name)))
+        .collect();
+    let mut folders = 0;
+    while !level.is_empty() {
+        folders += level.len();
+        let folders: Vec<(Vec<(String, PathBuf)>, Vec<(String, PathBuf)>)> = stream::iter(level)
+            .map(|(folder_id, path)| async move {
+                let files = get_folder_files(folder_id.clone(), port).await?;
+                let subfolders = get_sub_folders(folder_id, port).await?;
+                Ok::<_, anyhow::Error>((
+                    files.into_named().map(|(name, record)| (record.id, path.join(name))).collect(),
+                    subfolders.into_named().map(|(name, folder_id)| (folder_id, path.join(name))).collect(),
+                ))
+            })
+            .buffer_unordered(config().helix_max_inflight)
+            .try_collect()
+            .await?;
+        level = Vec::new();
+        for (folder_files, subfolders) in folders {
+            files.extend(folder_files);
+            level.extend(subfolders);
+        }
+    }
+    let (file_ids, file_paths) = files.into_iter().unzip();
+    Ok(RootTree { folders, file_ids, file_paths })
+}
+
+// Queue new embeddings for the embedded entities of a file: its top level
+// entities and the comments and docstrings under them. The ids of their
+// current embeddings are added to old.
+async fn reembed_file(
+    file_id: &str,
+    port: u16,
+    run: &Arc<Run>,
+    claimed: &Mutex<HashSet<String>>,
+    old: &Mutex<OldEmbeddings>,
+) -> Result<()> {
+    let file = get_file_content(file_id.to_string(), port).await?;
+    for (entity, top_level) in embedded_entities(file_id, port).await? {
+        if !claimed.lock().unwrap().insert(entity.id.clone()) {
+            continue;
+        }
+        let Some(text) = entity_text(&entity, &file.text, run) else {
+            continue;
+        };
+        let embedding_ids = embedding_ids(&entity.id, port).await?;
+        old.lock().unwrap().insert(entity.id.clone(), embedding_ids);
+        queue_entity(&entity, top_level, &text, &file.language, file_id, port, run).await?;
+    }
+    Ok(())
+}
+
+// Ids of the embeddings stored for an entity
+async fn embedding_ids(entity_id: &str, port: u16) -> Result<Vec<String>> {
+    let url = helix_url(port, endpoints::GET_ENTITY_EMBEDDINGS);
+    let response = post_request_async(&url, json!({ "entity_id": entity_id })).await?;
+    let embeddings = response.get("embeddings").and_then(Value::as_array)
+        .ok_or_else(|| anyhow::anyhow!("Embeddings not found in response"))?;
+    embeddings.iter()
+        .map(|embedding| embedding.as_str().or_else(|| embedding.get("id")?.as_str()).map(str::to_string))
+        .collect::<Option<Vec<String>>>()
+        .ok_or_else(|| anyhow::anyhow!("Embedding without an id in response: {}", response))
+}
+
+// Entities of a root found by verify: those with embeddings, and the ids of those without
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub ok: usize,
+    pub missing: Vec<String>,
+}
+
+// Check that every embedded entity of a root has at least one embedding. With
+// repair set, the entities without one are queued for embedding.
+pub async fn verify(root_id: String, port: u16, run: Arc<Run>, repair: bool) -> Result<Verification> {
+    let tree = root_tree(root_id, port).await?;
+    run.files_discovered.fetch_add(tree.file_ids.len(), Ordering::SeqCst);
+    for (file_id, path) in tree.file_ids.iter().zip(tree.file_paths) {
+        run.record_file_path(file_id, path);
+    }
+
+    let claimed = Arc::new(Mutex::new(HashSet::new()));
+    let verification = Arc::new(Mutex::new(Verification::default()));
+    let tasks: Vec<_> = tree.file_ids.into_iter().map(|file_id| {
+        let run = run.clone();
+        let claimed = claimed.clone();
+        let verification = verification.clone();
+        async move {
+            if run.stop_requested() {
+                return Ok(());
+            }
+            let _permit = FILE_PERMITS.acquire().await?;
+            if let Err(e) = verify_file(&file_id, port, &run, &claimed, &verification, repair).await {
+                run.record_failure(format!("file {}", file_id), Stage::Verify, e);
+            }
+            run.files_processed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }).collect();
+    run_tasks(tasks).await?;
+    let verification = std::mem::take(&mut *verification.lock().unwrap());
+    Ok(verification)
+}
+
+async fn verify_file(
+    file_id: &str,
+    port: u16,
+    run: &Arc<Run>,
+    claimed: &Mutex<HashSet<String>>,
+    verification: &Mutex<Verification>,
+    repair: bool,
+) -> Result<()> {
+    let file = get_file_content(file_id.to_string(), port).await?;
+    for (entity, top_level) in embedded_entities(file_id, port).await? {
+        if !claimed.lock().unwrap().insert(entity.id.clone()) {
+            continue;
+        }
+        // Blank entities are never embedded
+        let Some(text) = entity_text(&entity, &file.text, run).filter(|text| !text.trim().is_empty()) else {
+            continue;
+        };
+        if count_embeddings(&entity.id, port).await? > 0 {
+            verification.lock().unwrap().ok += 1;
+            continue;
+        }
+        debug!("Entity {} of file {} has no embedding", entity.id, file_id);
+        verification.lock().unwrap().missing.push(entity.id.clone());
+        if repair {
+            queue_entity(&entity, top_level, &text, &file.language, file_id, port, run).await?;
+        }
+    }
+    Ok(())
+}
+
+// Number of embeddings stored for an entity
+pub async fn count_embeddings(entity_id: &str, port: u16) -> Result<usize> {
+    let url = helix_url(port, endpoints::COUNT_ENTITY_EMBEDDINGS);
+    let response = post_request_async(&url, json!({ "entity_id": entity_id })).await?;
+    match response.get("count") {
+        Some(Value::Array(embeddings)) => Ok(embeddings.len()),
+        Some(count) => Ok(count.as_u64().unwrap_or(0) as usize),
+        None => Err(anyhow::anyhow!("Embedding count not found in response")),
+    }
+}
+
+// Entities of a file that are embedded, with whether they are top level: its
+// top level entities and the comments and docstrings under them
+async fn embedded_entities(file_id: &str, port: u16) -> Result<Vec<(EntityRecord, bool)>> {
+    let mut embedded = Vec::new();
+    let mut level = get_file_entities(file_id.to_string(), port).await?;
+    embedded.extend(level.iter().cloned().map(|entity| (entity, true)));
+    while !level.is_empty() {
+        let children: Vec<Vec<EntityRecord>> = stream::iter(level)
+            .map(|entity| get_sub_entities(entity.id, port))
+            .buffer_unordered(config().helix_max_inflight)
+            .try_collect()
+            .await?;
+        level = children.into_iter().flatten().collect();
+        embedded.extend(level.iter()
+            .filter(|entity| EMBEDDED_SUB_ENTITIES.contains(&entity.entity_type.as_str()))
+            .cloned()
+            .map(|entity| (entity, false)));
+    }
+    Ok(embedded)
+}
+
+// Text of an entity, read from its file's text for entities stored with
+// omit_entity_text. Records a failure if its byte range is outside the file.
+fn entity_text(entity: &EntityRecord, source: &str, run: &Run) -> Option<String> {
+    if !entity.text.is_empty() {
+        return Some(entity.text.clone());
+    }
+    let range = entity.start_byte.max(0) as usize..entity.end_byte.max(0) as usize;
+    let text = source.get(range).map(str::to_string);
+    if text.is_none() {
+        run.record_failure(format!("entity {}", entity.id), Stage::Reembed, "byte range outside its file's text");
+    }
+    text
+}
+
+// Queue the embeddings of an entity from its text. language is the name of the
+// language of its file, used to chunk top level code entities.
+async fn queue_entity(entity: &EntityRecord, top_level: bool, text: &str, language: &str, file_id: &str, port: u16, run: &Arc<Run>) -> Result<()> {
+    run.entities.fetch_add(1, Ordering::SeqCst);
+    // Chunks of files without a grammar are never summarized
+    if top_level && entity.entity_type != "chunk" {
+        return queue_entity_embeddings(text, language, &entity.entity_type, &entity.name, &entity.id, file_id, port, run).await;
+    }
+    // Documentation is chunked as text, and file chunks already fit in one chunk
+    let chunks = run.limit_chunks(file_id, chunk_entity(text, "")?);
+    for chunk in chunks {
+        let chunk = run.embedding_text(chunk, file_id, &entity.entity_type, &entity.name);
+        let job = EmbeddingJob {chunk, entity_id: entity.id.clone(), file_id: file_id.to_string(), port, summarize: false};
+        run.queue_embedding(job).await;
+    }
+    Ok(())
+}
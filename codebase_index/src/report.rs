@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+// Pipeline stage a failure happened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Read,
+    Parse,
+    CreateFolder,
+    CreateFile,
+    CreateEntity,
+    UpdateFile,
+    UpdateFolder,
+    Reembed,
+    Verify,
+    Summarize,
+    Embed,
+    PostEmbedding,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Read => "read",
+            Stage::Parse => "parse",
+            Stage::CreateFolder => "create folder",
+            Stage::CreateFile => "create file",
+            Stage::CreateEntity => "create entity",
+            Stage::UpdateFile => "update file",
+            Stage::UpdateFolder => "update folder",
+            Stage::Reembed => "re-embed",
+            Stage::Verify => "verify",
+            Stage::Summarize => "summarize",
+            Stage::Embed => "embed",
+            Stage::PostEmbedding => "post embedding",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    // File path, or the entity/file id when no path is known
+    pub path: String,
+    pub stage: Stage,
+    pub error: String,
+}
+
+// Counts and failures of a single run
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub skipped_files: usize,
+    pub unsupported_files: usize,
+    pub deduplicated_files: usize,
+    pub parse_error_files: usize,
+    pub unchanged_entities: usize,
+    pub unchanged_folders: usize,
+    // Paths of the files whose chunks beyond max_chunks_per_file were not embedded
+    pub over_chunk_limit: Vec<String>,
+    // Files that took longest to parse, chunk and write to Helix, slowest first; with profile only
+    pub slowest_files: Vec<(String, Duration)>,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    // Number of distinct files (or entities) with at least one failure
+    pub fn failed_files(&self) -> usize {
+        self.failures.iter().map(|f| f.path.as_str()).collect::<HashSet<_>>().len()
+    }
+
+    // Failures grouped by stage, in pipeline order
+    pub fn failures_by_stage(&self) -> BTreeMap<Stage, Vec<&Failure>> {
+        let mut by_stage: BTreeMap<Stage, Vec<&Failure>> = BTreeMap::new();
+        for failure in &self.failures {
+            by_stage.entry(failure.stage).or_default().push(failure);
+        }
+        by_stage
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+use crate::checkpoint;
+use crate::config::{config, OversizedChunks};
+use crate::git::{Blame, FileBlame};
+use crate::manifest::{Manifest, ManifestEntity};
+use crate::report::{Failure, Report, Stage};
+use crate::updater::DryRunCounts;
+use crate::utils::{estimated_tokens, EmbeddingJob, EMBEDDING_MAX_CHARS, SUMMARY_MAX_CHARS};
+use crate::walk;
+
+// Embedding job on its way to the worker, with the run that queued it
+pub type QueuedJob = (Arc<Run>, EmbeddingJob);
+
+// Files listed in the report of a run with profile set
+const SLOWEST_FILES: usize = 10;
+
+// State of a single ingest, update or watch run. Every task of a run and every
+// embedding it queues holds the same Run, so embeddings still finishing after a
+// run has returned are counted towards it rather than towards the next one.
+pub struct Run {
+    tx: Sender<QueuedJob>,
+    // Chunks produced for embedding
+    pub chunks: AtomicUsize,
+    // Embeddings queued, counted when queued so that none is missed by a wait
+    // that starts once the walk has returned
+    pub embeddings_pending: AtomicUsize,
+    pub embeddings_completed: AtomicUsize,
+    // Embeddings given up on after their retries ran out
+    pub embeddings_failed: AtomicUsize,
+    // Files found and finished by the walk, for progress display
+    pub files_discovered: AtomicUsize,
+    pub files_processed: AtomicUsize,
+    // Entities written to Helix, chunks of unsupported files included
+    pub entities: AtomicUsize,
+    // Estimated tokens of the chunks queued for embedding
+    pub tokens: AtomicUsize,
+    // Chunks longer than the embedding input limit, split, truncated or skipped
+    pub oversized_chunks: AtomicUsize,
+    // Files skipped because they could not be decoded or are not listed in file_types.json
+    pub skipped_files: AtomicUsize,
+    // Files without a tree-sitter grammar, indexed as plain chunks
+    pub unsupported_files: AtomicUsize,
+    // Copies of an already ingested file, linked to its entities instead of re-embedded
+    pub deduplicated_files: AtomicUsize,
+    // Files tree-sitter parsed with syntax errors
+    pub parse_error_files: AtomicUsize,
+    // Entities of updated files left as they were, keeping their embeddings
+    pub unchanged_entities: AtomicUsize,
+    // Folders update left alone because nothing below them changed
+    pub unchanged_folders: AtomicUsize,
+    // Changes found by a dry run update
+    pub dry_run_created: AtomicUsize,
+    pub dry_run_updated: AtomicUsize,
+    pub dry_run_deleted: AtomicUsize,
+    failures: Mutex<Vec<Failure>>,
+    // Chunks produced so far for each file, by id, checked against max_chunks_per_file
+    file_chunks: Mutex<HashMap<String, usize>>,
+    // Paths of the files that went over max_chunks_per_file
+    over_chunk_limit: Mutex<Vec<String>>,
+    // Time each file took, kept when profile is set
+    file_times: Mutex<Vec<(String, Duration)>>,
+    // Files and entities written, kept when a manifest is to be written
+    manifest: Mutex<Manifest>,
+    // Paths from the root of the files written, by id, for embed_context and warnings
+    file_paths: Mutex<HashMap<String, PathBuf>>,
+    // git blame of the files whose entities are being written, by id, when with_blame is set
+    blames: Mutex<HashMap<String, Arc<FileBlame>>>,
+    // Set once a file could not be blamed, so that it is warned about once
+    blame_failed: AtomicBool,
+    // Languages without an index-types.json entry that were logged
+    missing_index_types: Mutex<HashSet<String>>,
+    // Set when the run is asked to stop; files not started yet are left out
+    stopped: AtomicBool,
+    // Notified when the last pending embedding finishes, and when a stop is requested
+    drained: Notify,
+}
+
+impl Run {
+    pub fn new(tx: Sender<QueuedJob>) -> Arc<Run> {
+        Arc::new(Run {
+            tx,
+            chunks: AtomicUsize::new(0),
+            embeddings_pending: AtomicUsize::new(0),
+            embeddings_completed: AtomicUsize::new(0),
+            embeddings_failed: AtomicUsize::new(0),
+            files_discovered: AtomicUsize::new(0),
+            files_processed: AtomicUsize::new(0),
+            entities: AtomicUsize::new(0),
+            tokens: AtomicUsize::new(0),
+            oversized_chunks: AtomicUsize::new(0),
+            skipped_files: AtomicUsize::new(0),
+            unsupported_files: AtomicUsize::new(0),
+            deduplicated_files: AtomicUsize::new(0),
+            parse_error_files: AtomicUsize::new(0),
+            unchanged_entities: AtomicUsize::new(0),
+            unchanged_folders: AtomicUsize::new(0),
+            dry_run_created: AtomicUsize::new(0),
+            dry_run_updated: AtomicUsize::new(0),
+            dry_run_deleted: AtomicUsize::new(0),
+            failures: Mutex::new(Vec::new()),
+            file_chunks: Mutex::new(HashMap::new()),
+            over_chunk_limit: Mutex::new(Vec::new()),
+            file_times: Mutex::new(Vec::new()),
+            manifest: Mutex::new(Manifest::default()),
+            file_paths: Mutex::new(HashMap::new()),
+            blames: Mutex::new(HashMap::new()),
+            blame_failed: AtomicBool::new(false),
+            missing_index_types: Mutex::new(HashSet::new()),
+            stopped: AtomicBool::new(false),
+            drained: Notify::new(),
+        })
+    }
+
+    // Changes a dry run update found
+    pub fn dry_run_counts(&self) -> DryRunCounts {
+        DryRunCounts {
+            created: self.dry_run_created.load(Ordering::SeqCst),
+            updated: self.dry_run_updated.load(Ordering::SeqCst),
+            deleted: self.dry_run_deleted.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    // Whether every queued embedding has completed or failed
+    pub fn embeddings_finished(&self) -> bool {
+        self.unfinished_embeddings() == 0
+    }
+
+    // Queued embeddings that have neither completed nor failed yet
+    pub fn unfinished_embeddings(&self) -> usize {
+        let finished = self.embeddings_completed.load(Ordering::SeqCst) + self.embeddings_failed.load(Ordering::SeqCst);
+        self.embeddings_pending.load(Ordering::SeqCst).saturating_sub(finished)
+    }
+
+    // Notified when the run's embeddings finish or a stop is requested
+    pub fn drained(&self) -> tokio::sync::futures::Notified<'_> {
+        self.drained.notified()
+    }
+
+    // Count an embedding as completed or failed, waking waiters on the last one
+    pub fn embedding_done(&self, completed: bool) {
+        let counter = if completed { &self.embeddings_completed } else { &self.embeddings_failed };
+        counter.fetch_add(1, Ordering::SeqCst);
+        if self.embeddings_finished() {
+            self.drained.notify_waiters();
+        }
+    }
+
+    // Count the files among a folder's entries as discovered
+    pub fn count_discovered(&self, entries: &[ignore::DirEntry]) {
+        let files = entries.iter()
+            .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+            .count();
+        self.files_discovered.fetch_add(files, Ordering::SeqCst);
+    }
+
+    // Queue an embedding job, waiting while the channel is full so that
+    // ingestion slows down to the pace of the embedding worker. Chunks over the
+    // embedding input limit are handled as set by oversized_chunks.
+    pub async fn queue_embedding(self: &Arc<Self>, mut job: EmbeddingJob) {
+        if job.chunk.is_empty() {
+            return;
+        }
+        // Only the summary is embedded, which is always short
+        if job.summarize {
+            job.chunk = job.chunk.chars().take(SUMMARY_MAX_CHARS).collect();
+            return self.send(job).await;
+        }
+        if job.chunk.chars().count() <= EMBEDDING_MAX_CHARS {
+            return self.send(job).await;
+        }
+
+        self.oversized_chunks.fetch_add(1, Ordering::SeqCst);
+        match config().oversized_chunks {
+            OversizedChunks::Split => {
+                let chars: Vec<char> = job.chunk.chars().collect();
+                for piece in chars.chunks(EMBEDDING_MAX_CHARS) {
+                    self.send(EmbeddingJob { chunk: piece.iter().collect(), ..job.clone() }).await;
+                }
+            }
+            OversizedChunks::Truncate => {
+                job.chunk = job.chunk.chars().take(EMBEDDING_MAX_CHARS).collect();
+                self.send(job).await;
+            }
+            OversizedChunks::Skip => {
+                warn!("Skipping a chunk of entity {} over the embedding input limit", job.entity_id);
+            }
+        }
+    }
+
+    async fn send(self: &Arc<Self>, job: EmbeddingJob) {
+        checkpoint::job_queued(&job.file_id);
+        // Code sent for a summary is not embedded, so it does not count towards embedding tokens
+        if !job.summarize {
+            self.tokens.fetch_add(estimated_tokens(&job.chunk), Ordering::SeqCst);
+        }
+        self.embeddings_pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self.tx.send((self.clone(), job)).await {
+            error!("Failed to queue embedding job, the embedding worker has stopped: {}", e);
+            self.embedding_done(false);
+        }
+    }
+
+    // Record a failure for a file path (or "entity <id>" when no path is known)
+    pub fn record_failure(&self, target: impl fmt::Display, stage: Stage, error: impl fmt::Display) {
+        let path = target.to_string();
+        let error = error.to_string();
+        error!("Failed to {} {}: {}", stage, path, error);
+        self.failures.lock().unwrap().push(Failure { path, stage, error });
+    }
+
+    // Whether a failure may have left something below a folder out of date. Failures
+    // recorded by entity or file id rather than path count for every folder.
+    pub fn failed_under(&self, folder: &Path) -> bool {
+        self.failures.lock().unwrap().iter()
+            .map(|failure| Path::new(&failure.path))
+            .any(|path| !path.is_absolute() || path.starts_with(folder))
+    }
+
+    // Record a file created or updated in Helix, for the manifest and embed_context
+    pub fn record_file(&self, path: &Path, file_id: &str) {
+        if config().manifest.is_some() {
+            self.manifest.lock().unwrap().record_file(path, file_id);
+        }
+        self.record_file_path(file_id, walk::relative_path(path));
+    }
+
+    // Record the path from the root of a file, for embed_context and warnings
+    pub fn record_file_path(&self, file_id: &str, path: PathBuf) {
+        self.file_paths.lock().unwrap().insert(file_id.to_string(), path);
+    }
+
+    // Path from the root of a recorded file, or its id when no path is known
+    fn file_path(&self, file_id: &str) -> String {
+        match self.file_paths.lock().unwrap().get(file_id) {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => format!("file {}", file_id),
+        }
+    }
+
+    // Count chunks produced for a file, keeping only those within what is left
+    // of its max_chunks_per_file. A file going over is warned about once, and
+    // its first chunks stay embedded.
+    pub fn limit_chunks(&self, file_id: &str, mut chunks: Vec<String>) -> Vec<String> {
+        let limit = config().max_chunks_per_file;
+        let mut file_chunks = self.file_chunks.lock().unwrap();
+        let count = file_chunks.entry(file_id.to_string()).or_default();
+        let before = *count;
+        *count += chunks.len();
+        if *count > limit {
+            chunks.truncate(limit.saturating_sub(before));
+            if before <= limit {
+                let path = self.file_path(file_id);
+                warn!("{} has more than {} chunks, only the first are embedded", path, limit);
+                self.over_chunk_limit.lock().unwrap().push(path);
+            }
+        }
+        drop(file_chunks);
+        self.chunks.fetch_add(chunks.len(), Ordering::SeqCst);
+        chunks
+    }
+
+    // Text to embed for a chunk of code: the chunk itself, or the embed_context
+    // template filled in with it and the entity and file it is part of
+    pub fn embedding_text(&self, chunk: String, file_id: &str, entity_type: &str, name: &str) -> String {
+        let config = config();
+        let Some(template) = &config.embed_context else {
+            return chunk;
+        };
+        let path = self.file_paths.lock().unwrap().get(file_id).map(|path| path.to_string_lossy().to_string());
+        // The code goes in last, so placeholders that happen to be in it are kept
+        template
+            .replace("{path}", &path.unwrap_or_default())
+            .replace("{type}", entity_type)
+            .replace("{name}", name)
+            .replace("{code}", &chunk)
+    }
+
+    // Start timing a file, recorded when the returned timer is dropped. Called once
+    // the file holds its permit, so time spent waiting for one is left out.
+    pub fn time_file(self: &Arc<Self>, path: &Path) -> FileTimer {
+        let path = config().profile.then(|| walk::relative_path(path).to_string_lossy().to_string());
+        FileTimer { run: self.clone(), path, started: Instant::now() }
+    }
+
+    // Blame a file with with_blame set, for the entities written while the returned
+    // guard is held. Files outside a git checkout or not committed yet get no blame.
+    pub async fn blame_file(self: &Arc<Self>, path: &Path, file_id: &str, source: &str) -> BlameGuard {
+        let file_id = config().with_blame.then(|| file_id.to_string());
+        if let Some(file_id) = &file_id {
+            match FileBlame::new(path, source).await {
+                Ok(blame) => {
+                    self.blames.lock().unwrap().insert(file_id.clone(), Arc::new(blame));
+                }
+                Err(e) if !self.blame_failed.swap(true, Ordering::SeqCst) => {
+                    warn!("Entities of {} and other files that cannot be blamed are stored without an author: {}", walk::relative_path(path).display(), e);
+                }
+                Err(e) => debug!("No blame for {}: {}", walk::relative_path(path).display(), e),
+            }
+        }
+        BlameGuard { run: self.clone(), file_id }
+    }
+
+    // Whether a language without an index-types.json entry is seen for the first
+    // time this run, so that it is logged once
+    pub fn first_missing_index_type(&self, index_type: &str) -> bool {
+        self.missing_index_types.lock().unwrap().insert(index_type.to_string())
+    }
+
+    // Commit that last changed a byte range of a file, empty when the file has no blame
+    pub fn blame(&self, file_id: &str, start_byte: usize, end_byte: usize) -> Blame {
+        let blame = self.blames.lock().unwrap().get(file_id).cloned();
+        blame.and_then(|blame| blame.range(start_byte, end_byte).cloned()).unwrap_or_default()
+    }
+
+    // Record an entity created in Helix, for the manifest
+    pub fn record_entity(&self, entity: ManifestEntity) {
+        if config().manifest.is_some() {
+            self.manifest.lock().unwrap().record_entity(entity);
+        }
+    }
+
+    // Write the files and entities recorded so far to the configured manifest
+    pub fn write_manifest(&self, root_path: &Path, root_id: &str) -> anyhow::Result<()> {
+        match &config().manifest {
+            Some(path) => self.manifest.lock().unwrap().write(path, root_path, root_id),
+            None => Ok(()),
+        }
+    }
+
+    // The SLOWEST_FILES files that took longest, slowest first
+    fn slowest_files(&self) -> Vec<(String, Duration)> {
+        let mut file_times = self.file_times.lock().unwrap().clone();
+        file_times.sort_by(|a, b| b.1.cmp(&a.1));
+        file_times.truncate(SLOWEST_FILES);
+        file_times
+    }
+
+    // Counts and failures recorded so far
+    pub fn report(&self) -> Report {
+        Report {
+            skipped_files: self.skipped_files.load(Ordering::SeqCst),
+            unsupported_files: self.unsupported_files.load(Ordering::SeqCst),
+            deduplicated_files: self.deduplicated_files.load(Ordering::SeqCst),
+            parse_error_files: self.parse_error_files.load(Ordering::SeqCst),
+            unchanged_entities: self.unchanged_entities.load(Ordering::SeqCst),
+            unchanged_folders: self.unchanged_folders.load(Ordering::SeqCst),
+            over_chunk_limit: self.over_chunk_limit.lock().unwrap().clone(),
+            slowest_files: self.slowest_files(),
+            failures: self.failures.lock().unwrap().clone(),
+        }
+    }
+}
+
+// Time a file has taken so far, recorded on the run when dropped with profile set
+pub struct FileTimer {
+    run: Arc<Run>,
+    path: Option<String>,
+    started: Instant,
+}
+
+impl Drop for FileTimer {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            self.run.file_times.lock().unwrap().push((path, self.started.elapsed()));
+        }
+    }
+}
+
+// Blame of a file kept on the run until dropped
+pub struct BlameGuard {
+    run: Arc<Run>,
+    file_id: Option<String>,
+}
+
+impl Drop for BlameGuard {
+    fn drop(&mut self) {
+        if let Some(file_id) = self.file_id.take() {
+            self.run.blames.lock().unwrap().remove(&file_id);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+module Main where
+
+import Data.List (sortOn)
+
+-- A point in the plane
+data Point = Point
+  { pointX :: Double
+  , pointY :: Double
+  } deriving (Show, Eq)
+
+data Shape
+  = Circle Point Double
+  | Rectangle Point Point
+  deriving (Show)
+
+distance :: Point -> Point -> Double
+distance (Point x1 y1) (Point x2 y2) = sqrt (dx * dx + dy * dy)
+  where
+    dx = x2 - x1
+    dy = y2 - y1
+
+area :: Shape -> Double
+area (Circle _ radius) = pi * radius * radius
+area (Rectangle (Point x1 y1) (Point x2 y2)) = abs (x2 - x1) * abs (y2 - y1)
+
+largest :: [Shape] -> Maybe Shape
+largest [] = Nothing
+largest shapes = Just (last (sortOn area shapes))
+
+contains :: Shape -> Point -> Bool
+contains shape point = case shape of
+  Circle center radius -> distance center point <= radius
+  Rectangle (Point x1 y1) (Point x2 y2) -> within x1 x2 (pointX point) && within y1 y2 (pointY point)
+  where
+    within low high value = value >= min low high && value <= max low high
+
+main :: IO ()
+main = do
+  let shapes = [Circle (Point 0 0) 1, Rectangle (Point 0 0) (Point 2 3)]
+  print (map area shapes)
+  print (largest shapes)
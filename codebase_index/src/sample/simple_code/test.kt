@@ -0,0 +1,57 @@
+package sample
+
+import kotlin.math.sqrt
+
+typealias Coordinates = Pair<Double, Double>
+
+interface Shape {
+    val name: String
+    fun area(): Double
+}
+
+data class Point(val x: Double, val y: Double) {
+    fun distanceTo(other: Point): Double {
+        val dx = x - other.x
+        val dy = y - other.y
+        return sqrt(dx * dx + dy * dy)
+    }
+
+    companion object {
+        val ORIGIN = Point(0.0, 0.0)
+
+        fun fromCoordinates(coordinates: Coordinates) = Point(coordinates.first, coordinates.second)
+    }
+}
+
+class Circle(private val center: Point, private val radius: Double) : Shape {
+    override val name = "circle"
+
+    constructor(radius: Double) : this(Point.ORIGIN, radius)
+
+    override fun area(): Double = Math.PI * radius * radius
+
+    fun contains(point: Point): Boolean = center.distanceTo(point) <= radius
+}
+
+enum class Direction {
+    NORTH, EAST, SOUTH, WEST;
+
+    fun turnRight(): Direction = values()[(ordinal + 1) % values().size]
+}
+
+object ShapeRegistry {
+    private val shapes = mutableListOf<Shape>()
+
+    fun register(shape: Shape) {
+        shapes.add(shape)
+    }
+
+    fun totalArea(): Double = shapes.sumOf { it.area() }
+}
+
+fun main() {
+    val circle = Circle(2.0)
+    ShapeRegistry.register(circle)
+    println("Total area: ${ShapeRegistry.totalArea()}")
+    println(circle.contains(Point(1.0, 1.0)))
+}
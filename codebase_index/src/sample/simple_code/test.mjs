@@ -0,0 +1,26 @@
+import { readFile } from "node:fs/promises";
+
+export async function loadConfig(path) {
+    const text = await readFile(path, "utf8");
+    return JSON.parse(text);
+}
+
+export class Counter {
+    constructor(start = 0) {
+        this.value = start;
+    }
+
+    increment() {
+        this.value += 1;
+        return this.value;
+    }
+}
+
+function* range(start, end) {
+    for (let i = start; i < end; i++) {
+        yield i;
+    }
+}
+
+const total = [...range(0, 5)].reduce((sum, n) => sum + n, 0);
+console.log(total);
@@ -0,0 +1,42 @@
+package sample
+
+import scala.math.sqrt
+
+trait Shape {
+  def name: String
+  def area: Double
+}
+
+case class Point(x: Double, y: Double) {
+  def distanceTo(other: Point): Double = {
+    val dx = x - other.x
+    val dy = y - other.y
+    sqrt(dx * dx + dy * dy)
+  }
+}
+
+object Point {
+  val Origin: Point = Point(0.0, 0.0)
+
+  def fromPair(pair: (Double, Double)): Point = Point(pair._1, pair._2)
+}
+
+class Circle(center: Point, radius: Double) extends Shape {
+  override val name = "circle"
+
+  def area: Double = math.Pi * radius * radius
+
+  def contains(point: Point): Boolean = {
+    def squared(value: Double): Double = value * value
+    squared(center.distanceTo(point)) <= squared(radius)
+  }
+}
+
+object Main {
+  def totalArea(shapes: Seq[Shape]): Double = shapes.map(_.area).sum
+
+  def main(args: Array[String]): Unit = {
+    val shapes = Seq(new Circle(Point.Origin, 1.0), new Circle(Point(2.0, 2.0), 0.5))
+    println(s"Total area: ${totalArea(shapes)}")
+  }
+}
@@ -0,0 +1,67 @@
+import Foundation
+
+typealias Coordinates = (x: Double, y: Double)
+
+protocol Shape {
+    var name: String { get }
+    func area() -> Double
+}
+
+struct Point {
+    let x: Double
+    let y: Double
+
+    static let origin = Point(x: 0, y: 0)
+
+    func distance(to other: Point) -> Double {
+        let dx = x - other.x
+        let dy = y - other.y
+        return (dx * dx + dy * dy).squareRoot()
+    }
+}
+
+class Circle: Shape {
+    let name = "circle"
+    private let center: Point
+    private let radius: Double
+
+    init(center: Point = .origin, radius: Double) {
+        self.center = center
+        self.radius = radius
+    }
+
+    deinit {
+        print("Circle released")
+    }
+
+    func area() -> Double {
+        return Double.pi * radius * radius
+    }
+
+    func contains(_ point: Point) -> Bool {
+        return center.distance(to: point) <= radius
+    }
+}
+
+enum Direction: CaseIterable {
+    case north, east, south, west
+
+    func turnedRight() -> Direction {
+        let all = Direction.allCases
+        return all[(all.firstIndex(of: self)! + 1) % all.count]
+    }
+}
+
+extension Point: CustomStringConvertible {
+    var description: String {
+        return "(\(x), \(y))"
+    }
+}
+
+func totalArea(of shapes: [Shape]) -> Double {
+    return shapes.reduce(0) { $0 + $1.area() }
+}
+
+let circle = Circle(radius: 2)
+print("Total area: \(totalArea(of: [circle]))")
+print(circle.contains(Point(x: 1, y: 1)))
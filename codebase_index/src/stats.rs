@@ -0,0 +1,71 @@
+use anyhow::Result;
+use futures::{stream, StreamExt, TryStreamExt};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::config::config;
+use crate::queries::{get_file_entities, get_sub_entities, EntityRecord};
+use crate::reembed::{count_embeddings, root_tree, EMBEDDED_SUB_ENTITIES};
+
+// What is indexed under a root, counted from Helix
+#[derive(Debug, Clone, Default)]
+pub struct RootStats {
+    pub folders: usize,
+    pub files: usize,
+    pub entities: usize,
+    // Entities with at least one embedding, and the embeddings they have
+    pub embedded_entities: usize,
+    pub embeddings: usize,
+}
+
+// Count the folders, files, entities and embeddings of a root. Entities shared
+// by deduplicated copies of a file are counted once.
+pub async fn root_stats(root_id: String, port: u16) -> Result<RootStats> {
+    let tree = root_tree(root_id, port).await?;
+    let claimed = Mutex::new(HashSet::new());
+    let files: Vec<RootStats> = stream::iter(&tree.file_ids)
+        .map(|file_id| file_stats(file_id, port, &claimed))
+        .buffer_unordered(config().concurrency)
+        .try_collect()
+        .await?;
+
+    let mut stats = RootStats { folders: tree.folders, files: tree.file_ids.len(), ..RootStats::default() };
+    for file in files {
+        stats.entities += file.entities;
+        stats.embedded_entities += file.embedded_entities;
+        stats.embeddings += file.embeddings;
+    }
+    Ok(stats)
+}
+
+// Entity and embedding counts of a file, one entity level at a time. Only top
+// level entities, comments and docstrings are embedded, so only theirs are counted.
+async fn file_stats(file_id: &str, port: u16, claimed: &Mutex<HashSet<String>>) -> Result<RootStats> {
+    let mut stats = RootStats::default();
+    let mut level = get_file_entities(file_id.to_string(), port).await?;
+    let mut top_level = true;
+    while !level.is_empty() {
+        level.retain(|entity| claimed.lock().unwrap().insert(entity.id.clone()));
+        stats.entities += level.len();
+
+        let embedded: Vec<&EntityRecord> = level.iter()
+            .filter(|entity| top_level || EMBEDDED_SUB_ENTITIES.contains(&entity.entity_type.as_str()))
+            .collect();
+        let counts: Vec<usize> = stream::iter(embedded)
+            .map(|entity| count_embeddings(&entity.id, port))
+            .buffer_unordered(config().helix_max_inflight)
+            .try_collect()
+            .await?;
+        stats.embedded_entities += counts.iter().filter(|count| **count > 0).count();
+        stats.embeddings += counts.iter().sum::<usize>();
+
+        let children: Vec<Vec<EntityRecord>> = stream::iter(level)
+            .map(|entity| get_sub_entities(entity.id, port))
+            .buffer_unordered(config().helix_max_inflight)
+            .try_collect()
+            .await?;
+        level = children.into_iter().flatten().collect();
+        top_level = false;
+    }
+    Ok(stats)
+}
@@ -1,50 +1,52 @@
 use anyhow::Result;
-use ignore::WalkBuilder;
+use tracing::{debug, info, warn};
 use serde_json::json;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::Ordering,
     Arc
 };
-use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
 use chrono::{DateTime, Utc};
 use async_recursion::async_recursion;
 
 
 // Import from our modules
-use crate::utils::{
-    post_request_async, delete_folder, delete_files, EmbeddingJob,
-    TOTAL_CHUNKS
-};
-use crate::queries::{get_root_folders, get_root_files, get_sub_folders, get_folder_files};
+use crate::endpoints;
+use crate::utils::{helix_url, post_request_async, delete_folder, delete_files, run_tasks, FILE_PERMITS};
+use crate::queries::{get_root_folders, get_root_files, get_sub_folders, get_folder_files, get_folder_hash, update_folder_hash};
 
 // Forward declarations for functions that will be moved from ingestion
-use crate::ingestion::{populate, process_file, ingest_entities, process_unsupported_file, build_owned_nodes};
-use crate::utils::{get_language, delete_entities, chunk_entity};
-use tree_sitter::Parser;
+use crate::ingestion::{
+    populate, process_file, update_entities, process_unsupported_file, build_owned_nodes,
+    read_source, parse_source, file_language, extracts_entities, reset_ingested_hashes, with_file_metadata,
+};
+use crate::utils::{file_extension, delete_file_entities, chunk_entity, content_hash, language_name};
+use crate::queries::{FileRecord, Listing};
+use crate::walk;
+use crate::config::{config, index_types, file_types};
+use crate::report::Stage;
+use crate::run::Run;
 
 #[async_recursion]
 pub async fn update(
     root_path: PathBuf,
     root_id: String,
     port: u16,
-    tx: Sender<EmbeddingJob>,
-    update_interval: u64,
+    run: Arc<Run>,
+    mtime_tolerance: u64,
 ) -> Result<()> {    
     // Load index types
-    let index_types = fs::read_to_string("index-types.json")?;
-    let index_types: serde_json::Value = serde_json::from_str(&index_types)?;
-    let index_types = Arc::new(index_types);
+    let index_types = index_types()?;
 
     // Load file types
-    let file_types = fs::read_to_string("file_types.json")?;
-    let file_types: serde_json::Value = serde_json::from_str(&file_types)?;
-    let file_types = Arc::new(file_types);
+    let file_types = file_types()?;
 
     // Check if root exists
-    let url = format!("http://localhost:{}/{}", port, "getRootById");
+    let url = helix_url(port, endpoints::GET_ROOT_BY_ID);
     let root_res = post_request_async(&url, json!({ "root_id": root_id })).await?;
     let root = root_res
         .get("root")
@@ -52,112 +54,125 @@ pub async fn update(
         .and_then(|v| v.as_str())
         .ok_or_else(|| anyhow::anyhow!("Root not found"))?;
 
-    let root_name = root_path.file_name().unwrap().to_str().unwrap();
+    let root_name = config().root_name(&root_path);
 
     if root != root_name {
-        return Err(anyhow::anyhow!("Root name does not match"));
+        return Err(anyhow::anyhow!(
+            "Root {} is named {}, not {}; pass --root-name {} to update it from this folder",
+            root_id, root, root_name, root,
+        ));
     }
 
+    let dry_run = config().dry_run;
+
     let root_folder_name_ids = get_root_folders(root_id.clone(), port).await?;
     // println!("Root folder IDs: {:#?}", root_folder_name_ids);
 
-    let root_file_name_ids = get_root_files(root_id.clone(), port).await?;
+    let mut root_file_name_ids = get_root_files(root_id.clone(), port).await?;
     // println!("Root file IDs: {:#?}", root_file_name_ids);
 
-    let mut walker_builder = WalkBuilder::new(&root_path);
-    walker_builder.max_depth(Some(1));
-
-    // Add default ignore patterns
-    for pattern in &[".git/"] {
-        walker_builder.add_ignore(pattern);
-    }
+    walk::start(&root_path);
+    reset_ingested_hashes();
+    let fingerprints = Arc::new(walk::tree_fingerprints(&root_path));
 
     // Collect entries to process
-    let entries: Vec<_> = walker_builder.build()
-        .filter_map(|result| result.ok())
-        .filter(|entry| entry.path() != root_path)
-        .collect();
+    let entries = walk::entries(&root_path);
+    run.count_discovered(&entries);
 
-    let tasks: Vec<JoinHandle<Result<()>>> = entries.clone().clone().into_iter().map(|entry| {
+    let tasks: Vec<_> = entries.clone().into_iter().map(|entry| {
         let path_buf = entry.path().to_path_buf();
         let index_types_clone = index_types.clone();
-        let root_folder_name_ids_clone = root_folder_name_ids.clone();
-        let root_file_name_ids_clone = root_file_name_ids.clone();
+        let root_folder_name_ids_clone = root_folder_name_ids.by_name.clone();
+        let root_file_name_ids_clone = root_file_name_ids.by_name.clone();
         let root_id_clone = root_id.clone();
-        let tx_clone = tx.clone();
+        let run = run.clone();
         let file_types_clone = file_types.clone();
+        let fingerprints = fingerprints.clone();
         
-        tokio::spawn(async move {
+        async move {
+            if run.stop_requested() {
+                return Ok(());
+            }
             // Folder
             if path_buf.is_dir(){
-                let folder_name = path_buf.file_name().unwrap().to_str().unwrap();
-                if root_folder_name_ids_clone.contains_key(folder_name){
+                let folder_name = path_buf.file_name().unwrap_or_default().to_string_lossy();
+                if let Some(folder_id) = root_folder_name_ids_clone.get(folder_name.as_ref()) {
                     // println!("Folder {} already exists", folder_name);
-                    let folder_id = root_folder_name_ids_clone.get(folder_name).unwrap().to_string();
-                    let _ = Box::pin(update_folder(path_buf.clone(), folder_id.clone(), port, index_types_clone, file_types_clone, tx_clone, update_interval)).await;
+                    if let Err(e) = Box::pin(update_folder(path_buf.clone(), folder_id.clone(), port, index_types_clone, file_types_clone, fingerprints, run.clone(), mtime_tolerance)).await {
+                        run.record_failure(path_buf.display(), Stage::UpdateFolder, e);
+                    }
+                } else if dry_run {
+                    info!("Would create folder {}", path_buf.display());
+                    run.dry_run_created.fetch_add(1, Ordering::SeqCst);
                 } else {
-                    println!("Folder {} does not exist", folder_name);
-                    let _ = populate(path_buf.clone(), root_id_clone, port, true, index_types_clone, file_types_clone, tx_clone).await;
+                    debug!("Folder {} does not exist", folder_name);
+                    if let Err(e) = populate(path_buf.clone(), root_id_clone, port, true, index_types_clone, file_types_clone, run.clone()).await {
+                        run.record_failure(path_buf.display(), Stage::CreateFolder, e);
+                    }
                 }
                 Ok(())
 
             // File
             } else if path_buf.is_file() {
-                let file_name = path_buf.file_name().unwrap().to_str().unwrap();
+                let file_name = path_buf.file_name().unwrap_or_default().to_string_lossy();
                 
-                if root_file_name_ids_clone.contains_key(file_name){
-                    let file_id = root_file_name_ids_clone.get(file_name).unwrap().0.to_string();
-                    let file_extracted_at = root_file_name_ids_clone.get(file_name).unwrap().1.to_string();
-                    let metadata = fs::metadata(&path_buf).expect("Failed to get metadata");
-                    if let Ok(last_modified) = metadata.modified() {
-                        let date_modified = DateTime::<Utc>::from(last_modified);
-                        
-                        let date_extracted = DateTime::parse_from_rfc3339(&file_extracted_at)
-                            .expect("Failed to parse date")
-                            .with_timezone(&Utc);
-
-                        let diff_sec = date_modified.signed_duration_since(date_extracted).num_seconds();
-                        if diff_sec > update_interval.try_into().unwrap() {
-                            println!("File {} is out of date", file_name);
-                            let _ = update_file(
-                                path_buf,file_id,port,
-                                index_types_clone,file_types_clone,tx_clone
-                            ).await;
+                if let Some(record) = root_file_name_ids_clone.get(file_name.as_ref()) {
+                    if needs_update(&path_buf, record, mtime_tolerance) {
+                        let file_id = record.id.clone();
+                        if dry_run {
+                            info!("Would update file {}", path_buf.display());
+                            run.dry_run_updated.fetch_add(1, Ordering::SeqCst);
+                        } else {
+                            debug!("File {} is out of date", file_name);
+                            if let Err(e) = update_file(
+                                path_buf.clone(),file_id,port,
+                                index_types_clone,file_types_clone,run.clone()
+                            ).await {
+                                run.record_failure(path_buf.display(), Stage::UpdateFile, e);
+                            }
                         }
-                    } else {
-                        println!("File {} last modified time not available", file_name);
-                        let _ = update_file(
-                            path_buf,file_id,port,
-                            index_types_clone,file_types_clone,tx_clone
-                        ).await;
                     }
+                } else if dry_run {
+                    info!("Would create file {}", path_buf.display());
+                    run.dry_run_created.fetch_add(1, Ordering::SeqCst);
                 } else {
-                    println!("File {} does not exist", file_name);
+                    debug!("File {} does not exist", file_name);
                     let _ = process_file(
                         path_buf, root_id_clone, true, 
-                        port, index_types_clone, file_types_clone, tx_clone
+                        port, index_types_clone, file_types_clone, run.clone()
                     ).await;
                 }
+                run.files_processed.fetch_add(1, Ordering::SeqCst);
                 Ok(())
             } else {
                 Ok(())
             }
-        })
+        }
     }).collect();
-
-    for task in tasks {
-        task.await??;
+    run_tasks(tasks).await?;
+    if run.stop_requested() {
+        return Ok(());
     }
 
     // Find folders that are not in the index
-    let unseen_folders: Vec<String> = root_folder_name_ids.keys()
-        .filter(|folder_name| !entries.clone().iter().any(|entry| entry.path().file_name().unwrap().to_str().unwrap() == **folder_name))
+    let unseen_folders: Vec<String> = root_folder_name_ids.by_name.keys()
+        .filter(|folder_name| !entries.clone().iter().any(|entry| entry.file_name().to_string_lossy() == folder_name.as_str()))
         .cloned()
         .collect();
 
-    let delete_folder_tasks: Vec<JoinHandle<Result<()>>> = unseen_folders.into_iter().map(|folder_name| {
-        let folder_id = root_folder_name_ids.get(&folder_name).unwrap().to_string().clone();
+    let unseen_files = root_file_name_ids.by_name.keys()
+        .filter(|file_name| !entries.iter().any(|entry| entry.file_name().to_string_lossy() == file_name.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let folder_ids = stale_folder_ids(root_folder_name_ids, &unseen_folders, &root_path);
+    if dry_run {
+        let duplicates = folder_ids.len() - unseen_folders.len() + root_file_name_ids.duplicates.len();
+        report_dry_run_deletes(&root_path, &unseen_folders, &unseen_files, duplicates, &run);
+        return Ok(());
+    }
 
+    let delete_folder_tasks: Vec<JoinHandle<Result<()>>> = folder_ids.into_iter().map(|folder_id| {
         tokio::spawn(async move {
             delete_folder(folder_id, port).await
         })
@@ -167,12 +182,13 @@ pub async fn update(
         task.await??;
     }
 
-    let unseen_files = root_file_name_ids.keys()
-        .filter(|file_name| !entries.iter().any(|entry| entry.path().file_name().unwrap().to_str().unwrap() == **file_name))
-        .cloned()
-        .collect::<Vec<_>>();
-
-    delete_files(unseen_files, root_file_name_ids, port).await?;
+    // Files that share a name with the most recently extracted one are stale copies of it
+    let file_ids = unseen_files.iter()
+        .filter_map(|file_name| root_file_name_ids.by_name.remove(file_name))
+        .chain(root_file_name_ids.duplicates.into_iter().map(|(_, record)| record))
+        .map(|record| record.id)
+        .collect();
+    delete_files(file_ids, port).await?;
 
     Ok(())
 }
@@ -184,110 +200,127 @@ pub async fn update_folder(
     port: u16,
     index_types: Arc<serde_json::Value>,
     file_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
-    update_interval: u64,
+    // Fingerprints of the folders in the walk, by path
+    fingerprints: Arc<HashMap<PathBuf, String>>,
+    run: Arc<Run>,
+    mtime_tolerance: u64,
 ) -> Result<()> {
-    let subfolder_name_ids  = get_sub_folders(folder_id.clone(), port).await?;
+    let dry_run = config().dry_run;
+
+    // Nothing below the folder was added, removed or written since it was last indexed
+    let fingerprint = fingerprints.get(&current_path).cloned();
+    if fingerprint.is_some() && get_folder_hash(folder_id.clone(), port).await? == fingerprint {
+        debug!("Folder {} is unchanged", current_path.display());
+        run.unchanged_folders.fetch_add(1, Ordering::SeqCst);
+        return Ok(());
+    }
+
+    let subfolder_name_ids = get_sub_folders(folder_id.clone(), port).await?;
     // println!("Subfolder IDs: {:#?}", subfolder_name_ids);
 
-    let folder_file_name_ids = get_folder_files(folder_id.clone(), port).await?;
+    let mut folder_file_name_ids = get_folder_files(folder_id.clone(), port).await?;
     // println!("Subfolder file IDs: {:#?}", folder_file_name_ids);
 
-    let mut walker_builder = WalkBuilder::new(&current_path);
-    walker_builder.max_depth(Some(1));
-
-    // Add default ignore patterns
-    for pattern in &[".git/"] {
-        walker_builder.add_ignore(pattern);
-    }
-
     // Collect entries to process
-    let entries: Vec<_> = walker_builder.build()
-        .filter_map(|result| result.ok())
-        .filter(|entry| entry.path() != current_path)
-        .collect();
+    let entries = walk::entries(&current_path);
+    run.count_discovered(&entries);
 
-    let tasks: Vec<JoinHandle<Result<()>>> = entries.clone().clone().into_iter().map(|entry| {
+    let tasks: Vec<_> = entries.clone().into_iter().map(|entry| {
         let path_buf = entry.path().to_path_buf();
         let index_types_clone = index_types.clone();
-        let subfolder_name_ids_clone = subfolder_name_ids.clone();
-        let folder_file_name_ids_clone = folder_file_name_ids.clone();
+        let subfolder_name_ids_clone = subfolder_name_ids.by_name.clone();
+        let folder_file_name_ids_clone = folder_file_name_ids.by_name.clone();
         let folder_id_clone = folder_id.clone();
         let file_types_clone = file_types.clone();
-        let tx_clone = tx.clone();
+        let fingerprints = fingerprints.clone();
+        let run = run.clone();
 
-        tokio::spawn(async move {
+        async move {
+            if run.stop_requested() {
+                return Ok(());
+            }
             // Folder
             if path_buf.is_dir(){
-                let folder_name = path_buf.file_name().unwrap().to_str().unwrap();
-                if subfolder_name_ids_clone.contains_key(folder_name){
+                let folder_name = path_buf.file_name().unwrap_or_default().to_string_lossy();
+                if let Some(folder_id) = subfolder_name_ids_clone.get(folder_name.as_ref()) {
                     // println!("Folder {} already exists", folder_name);
-                    let sub_folder_id = subfolder_name_ids_clone.get(folder_name).unwrap().to_string();
-                    let _ = Box::pin(update_folder(path_buf.clone(), sub_folder_id, port, index_types_clone, file_types_clone, tx_clone, update_interval)).await;
+                    if let Err(e) = Box::pin(update_folder(path_buf.clone(), folder_id.clone(), port, index_types_clone, file_types_clone, fingerprints, run.clone(), mtime_tolerance)).await {
+                        run.record_failure(path_buf.display(), Stage::UpdateFolder, e);
+                    }
+                } else if dry_run {
+                    info!("Would create folder {}", path_buf.display());
+                    run.dry_run_created.fetch_add(1, Ordering::SeqCst);
                 } else {
-                    println!("Folder {} does not exist", folder_name);
-                    let _ = populate(path_buf.clone(), folder_id_clone, port, false, index_types_clone, file_types_clone, tx_clone).await;
+                    debug!("Folder {} does not exist", folder_name);
+                    if let Err(e) = populate(path_buf.clone(), folder_id_clone, port, false, index_types_clone, file_types_clone, run.clone()).await {
+                        run.record_failure(path_buf.display(), Stage::CreateFolder, e);
+                    }
                 }
                 Ok(())
 
             // File
             } else if path_buf.is_file() {
-                let file_name = path_buf.file_name().unwrap().to_str().unwrap();
+                let file_name = path_buf.file_name().unwrap_or_default().to_string_lossy();
                 
-                if folder_file_name_ids_clone.contains_key(file_name){
-                    let file_id = folder_file_name_ids_clone.get(file_name).unwrap().0.to_string();
-                    let file_extracted_at = folder_file_name_ids_clone.get(file_name).unwrap().1.to_string();
-                    let metadata = fs::metadata(&path_buf).expect("Failed to get metadata");
-                    if let Ok(last_modified) = metadata.modified() {
-                        let date_modified = DateTime::<Utc>::from(last_modified);
-                        let date_extracted = DateTime::parse_from_rfc3339(&file_extracted_at)
-                            .expect("Failed to parse date")
-                            .with_timezone(&Utc);
-
-                        let diff_sec = date_modified.signed_duration_since(date_extracted).num_seconds();
-                        if diff_sec > update_interval.try_into().unwrap() {
-                            println!("File {} is out of date", file_name);
-                            let _ = update_file(
-                                path_buf, file_id, port,
-                                index_types_clone, file_types_clone, tx_clone,
-                            ).await;
+                if let Some(record) = folder_file_name_ids_clone.get(file_name.as_ref()) {
+                    if needs_update(&path_buf, record, mtime_tolerance) {
+                        let file_id = record.id.clone();
+                        if dry_run {
+                            info!("Would update file {}", path_buf.display());
+                            run.dry_run_updated.fetch_add(1, Ordering::SeqCst);
+                        } else {
+                            debug!("File {} is out of date", file_name);
+                            if let Err(e) = update_file(
+                                path_buf.clone(), file_id, port,
+                                index_types_clone, file_types_clone, run.clone(),
+                            ).await {
+                                run.record_failure(path_buf.display(), Stage::UpdateFile, e);
+                            }
                         }
-                    } else {
-                        println!("File {} last modified time not available", file_name);
-                        let _ = update_file(
-                            path_buf, file_id, port,
-                            index_types_clone, file_types_clone, tx_clone,
-                        ).await;
                     }
+                } else if dry_run {
+                    info!("Would create file {}", path_buf.display());
+                    run.dry_run_created.fetch_add(1, Ordering::SeqCst);
                 } else {
-                    println!("File {} does not exist", file_name);
+                    debug!("File {} does not exist", file_name);
                     let _ = process_file(
                         path_buf, folder_id_clone, false, port,
-                        index_types_clone, file_types_clone, tx_clone
+                        index_types_clone, file_types_clone, run.clone()
                     ).await;
                 }
+                run.files_processed.fetch_add(1, Ordering::SeqCst);
                 Ok(())
             } else {
                 Ok(())
             }
-        })
+        }
     }).collect();
-
-    for task in tasks {
-        task.await??;
+    run_tasks(tasks).await?;
+    if run.stop_requested() {
+        return Ok(());
     }
 
     // Find folders that are not in the index
-    let unseen_folders: Vec<String> = subfolder_name_ids.keys()
-        .filter(|folder_name| !entries.clone().iter().any(|entry| entry.path().file_name().unwrap().to_str().unwrap() == **folder_name))
+    let unseen_folders: Vec<String> = subfolder_name_ids.by_name.keys()
+        .filter(|folder_name| !entries.clone().iter().any(|entry| entry.file_name().to_string_lossy() == folder_name.as_str()))
         .cloned()
         .collect();
 
-    let delete_folder_tasks: Vec<JoinHandle<Result<()>>> = unseen_folders.into_iter().map(|folder_name| {
-        let folder_id_clone = subfolder_name_ids.get(&folder_name).unwrap().to_string().clone();
+    let unseen_files = folder_file_name_ids.by_name.keys()
+        .filter(|file_name| !entries.iter().any(|entry| entry.file_name().to_string_lossy() == file_name.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let folder_ids = stale_folder_ids(subfolder_name_ids, &unseen_folders, &current_path);
+    if dry_run {
+        let duplicates = folder_ids.len() - unseen_folders.len() + folder_file_name_ids.duplicates.len();
+        report_dry_run_deletes(&current_path, &unseen_folders, &unseen_files, duplicates, &run);
+        return Ok(());
+    }
 
+    let delete_folder_tasks: Vec<JoinHandle<Result<()>>> = folder_ids.into_iter().map(|folder_id| {
         tokio::spawn(async move {
-            delete_folder(folder_id_clone, port).await
+            delete_folder(folder_id, port).await
         })
     }).collect();
 
@@ -295,89 +328,191 @@ pub async fn update_folder(
         task.await??;
     }
 
-    let unseen_files = folder_file_name_ids.keys()
-        .filter(|file_name| !entries.iter().any(|entry| entry.path().file_name().unwrap().to_str().unwrap() == **file_name))
-        .cloned()
-        .collect::<Vec<_>>();
-
-    delete_files(unseen_files, folder_file_name_ids, port).await?;
+    // Files that share a name with the most recently extracted one are stale copies of it
+    let file_ids = unseen_files.iter()
+        .filter_map(|file_name| folder_file_name_ids.by_name.remove(file_name))
+        .chain(folder_file_name_ids.duplicates.into_iter().map(|(_, record)| record))
+        .map(|record| record.id)
+        .collect();
+    delete_files(file_ids, port).await?;
 
+    // Taken before the folder was walked, so changes made since are found by the next update
+    if let Some(fingerprint) = fingerprint.filter(|_| !run.failed_under(&current_path)) {
+        update_folder_hash(folder_id, fingerprint, port).await?;
+    }
     Ok(())
 }
 
+// Ids of the indexed folders to delete: every copy of the folders no longer on
+// disk. Copies of a folder that is still there are left alone, since nothing
+// tells which of them is current, and only the one listed by name is updated.
+fn stale_folder_ids(listing: Listing<String>, unseen_folders: &[String], current_path: &Path) -> Vec<String> {
+    let Listing { mut by_name, duplicates } = listing;
+    let mut folder_ids: Vec<String> = unseen_folders.iter()
+        .filter_map(|folder_name| by_name.remove(folder_name))
+        .collect();
+    for (folder_name, folder_id) in duplicates {
+        if unseen_folders.contains(&folder_name) {
+            folder_ids.push(folder_id);
+        } else {
+            warn!("Skipping folder {} indexed twice as {}; re-ingest the root to remove the copy", folder_id, current_path.join(&folder_name).display());
+        }
+    }
+    folder_ids
+}
+
+fn report_dry_run_deletes(current_path: &Path, unseen_folders: &[String], unseen_files: &[String], duplicates: usize, run: &Run) {
+    for folder_name in unseen_folders {
+        info!("Would delete folder {}", current_path.join(folder_name).display());
+    }
+    for file_name in unseen_files {
+        info!("Would delete file {}", current_path.join(file_name).display());
+    }
+    if duplicates > 0 {
+        info!("Would delete {} duplicate entries in {}", duplicates, current_path.display());
+    }
+    run.dry_run_deleted.fetch_add(unseen_folders.len() + unseen_files.len() + duplicates, Ordering::SeqCst);
+}
+
+// Changes a dry run update would make
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRunCounts {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+// Whether an indexed file has changed since it was last extracted.
+// Compares content hashes, falling back to mtime_tolerance for files
+// indexed before hashes were stored. Files that vanished or cannot be read
+// since the walk listed them are left as they are.
+pub fn needs_update(path_buf: &Path, record: &FileRecord, mtime_tolerance: u64) -> bool {
+    if let Some(hash) = &record.hash {
+        return match fs::read_to_string(path_buf) {
+            Ok(source_code) => &content_hash(&source_code) != hash,
+            // No longer UTF-8, so update counts it as skipped
+            Err(e) if e.kind() == ErrorKind::InvalidData => true,
+            Err(e) => {
+                warn!("Skipping {}: {}", path_buf.display(), e);
+                false
+            }
+        };
+    }
+
+    let metadata = match fs::metadata(path_buf) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!("Skipping {}: {}", path_buf.display(), e);
+            return false;
+        }
+    };
+    if let Ok(last_modified) = metadata.modified() {
+        let date_modified = DateTime::<Utc>::from(last_modified);
+        let date_extracted = DateTime::parse_from_rfc3339(&record.extracted_at)
+            .expect("Failed to parse date")
+            .with_timezone(&Utc);
+
+        let diff_sec = date_modified.signed_duration_since(date_extracted).num_seconds();
+        diff_sec > mtime_tolerance.try_into().unwrap()
+    } else {
+        warn!("File {} last modified time not available", path_buf.display());
+        true
+    }
+}
+
+#[tracing::instrument(skip_all, fields(path = %file_path.display()))]
 pub async fn update_file(
     file_path: PathBuf,
     file_id: String,
     port: u16,
     index_types: Arc<serde_json::Value>,
     file_types: Arc<serde_json::Value>,
-    tx: Sender<EmbeddingJob>,
+    run: Arc<Run>,
 ) -> Result<()> {
-    let source_code = match fs::read_to_string(&file_path) {
-        Ok(source_code) => source_code,
-        Err(e) => {
-            eprintln!("Skipped {}: {}", file_path.file_name().unwrap().to_str().unwrap(), e);
-            return Ok(());
-        }
+    let _permit = FILE_PERMITS.acquire().await?;
+    if run.stop_requested() {
+        return Ok(());
+    }
+    let _timer = run.time_file(&file_path);
+
+    let source_code = match read_source(&file_path, &run) {
+        Some(source_code) => source_code,
+        None => return Ok(()),
     };
-    let file_name = file_path.file_name().unwrap().to_str().unwrap();
-    let extension = file_path
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("txt");
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let extension = &file_extension(&file_path, &source_code);
+    run.record_file(&file_path, &file_id);
+
+    let hash = content_hash(&source_code);
 
+    // file_types() checked that both are lists
     let supported = file_types.get("supported").unwrap().as_array().unwrap();
     let unsupported = file_types.get("unsupported").unwrap().as_array().unwrap();
 
-    if let Some(language) = get_language(&file_path) {
+    // With --file-level, or no index-types.json entry, files may be chunked as text
+    let language = file_language(extension, &index_types);
+    let has_grammar = language.is_some();
+    if let Some(language) = language.filter(|_| extracts_entities(extension, &index_types, Some(&*run))) {
         // Parse file
-        let mut parser = Parser::new();
-        parser.set_language(&language)?;
-        let tree = parser.parse(&source_code, None).unwrap();
+        let tree = parse_source(extension, &language, &source_code)?;
+        let parse_errors = tree.root_node().has_error();
+        if parse_errors {
+            debug!("File {} has syntax errors, its ERROR nodes are skipped", file_name);
+            run.parse_error_files.fetch_add(1, Ordering::SeqCst);
+        }
 
         // Update file
         let time_now = Utc::now().to_rfc3339();
-        let url = format!("http://localhost:{}/{}", port, "updateFile");
-        let payload = json!({ "file_id": file_id, "text": source_code, "extracted_at": time_now });
+        let url = helix_url(port, endpoints::UPDATE_FILE);
+        let payload = json!({ "file_id": file_id, "text": source_code, "hash": hash, "extracted_at": time_now });
+        let payload = with_file_metadata(payload, &file_path, &source_code, extension, parse_errors);
 
         // Send request to update file
-        println!("\nUpdating file: {}", file_name);
+        debug!("Updating file: {}", file_name);
         let _ = post_request_async(&url, payload).await;
 
-        if !supported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL")){
-            println!("File {} is skipped", file_name);
+        let is_indexed = supported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL"));
+        if !is_indexed || walk::is_structure_only(&file_path) {
+            debug!("File {} is skipped", file_name);
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
             return Ok(());
         }
 
-        let _ = delete_entities(file_id.to_string(), true, port).await;
-
-        // Process entities
+        // Only entities that changed are recreated and embedded again
+        let _blame = run.blame_file(&file_path, &file_id, &source_code).await;
         let root_node = tree.root_node();
         let owned_nodes = build_owned_nodes(root_node, &source_code);
-        ingest_entities(owned_nodes, file_id.to_string(), port, extension.to_string(), index_types, tx).await?;
+        update_entities(owned_nodes, file_id.to_string(), port, extension.to_string(), index_types, run).await?;
     // File is not supported by Tree Sitter
     } else {
         // Create file without entities
-        let endpoint =  "updateFile";
-        let url = format!("http://localhost:{}/{}", port, endpoint);
-        let payload = json!({ "file_id": file_id, "text": source_code });
+        let time_now = Utc::now().to_rfc3339();
+        let endpoint = endpoints::UPDATE_FILE;
+        let url = helix_url(port, endpoint);
+        let payload = json!({ "file_id": file_id, "text": source_code, "hash": hash, "extracted_at": time_now });
+        let payload = with_file_metadata(payload, &file_path, &source_code, extension, false);
 
         // Send request to update file
-        println!("\nUpdating unsupported file: {}", file_name);
+        debug!("Updating unsupported file: {}", file_name);
         post_request_async(&url, payload).await?;
 
-        if !unsupported.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL")){
-            println!("File {} is skipped", file_name);
+        let listed = if has_grammar { supported } else { unsupported };
+        let is_indexed = listed.iter().any(|v| v.as_str().map_or(false, |s| s == extension || s == "ALL"));
+        if !is_indexed || walk::is_structure_only(&file_path) {
+            debug!("File {} is skipped", file_name);
+            run.skipped_files.fetch_add(1, Ordering::SeqCst);
             return Ok(());
         }
+        if !has_grammar {
+            run.unsupported_files.fetch_add(1, Ordering::SeqCst);
+        }
 
-        let _ = delete_entities(file_id.to_string(), true, port).await;
+        let _ = delete_file_entities(file_id.to_string(), port).await;
 
-        let chunks = chunk_entity(&source_code).unwrap();
-        let order_counter = Arc::new(AtomicUsize::new(1));
-        TOTAL_CHUNKS.fetch_add(chunks.len(), Ordering::SeqCst);
+        let _blame = run.blame_file(&file_path, &file_id, &source_code).await;
+        let chunks = run.limit_chunks(&file_id, chunk_entity(&source_code, language_name(extension)).unwrap());
 
-        process_unsupported_file(chunks, file_id.to_string(), port, order_counter, tx).await?;
+        process_unsupported_file(&source_code, chunks, file_id.to_string(), port, &index_types, run).await?;
     }
     
     Ok(())
@@ -1,105 +1,279 @@
 use anyhow::Result;
+use tracing::{debug, warn};
 use chonkier::types::{RecursiveChunk, RecursiveRules};
 use chonkier::CharacterTokenizer;
 use chonkier::RecursiveChunker;
 use lazy_static::lazy_static;
 use serde_json::{json, Value};
 use std::env;
+use std::future::Future;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::path::Path;
 use std::time::{Duration};
-use governor::{Quota, RateLimiter};
-use std::num::NonZeroU32;
+use governor::{Jitter, Quota, RateLimiter};
 use governor::state::direct::NotKeyed;
 use governor::state::InMemoryState;
 use governor::clock::DefaultClock;
-use std::sync::{
-    atomic::{AtomicUsize},
-};
-use std::collections::HashMap;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tokio::sync::Semaphore;
 use tokio::task::JoinHandle;
-use crate::queries::{get_sub_folders, get_folder_files};
+use crate::endpoints;
+use crate::config::{config, EmbedProvider};
+use crate::error::IndexError;
+use crate::queries::{
+    get_root_folders, get_root_files, get_sub_folders, get_folder_files, get_file_entities, get_sub_entities, EntityRecord,
+};
+use sha2::{Digest, Sha256};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use async_recursion::async_recursion;
 
-// Global counter to track total number of chunks processed
-pub static TOTAL_CHUNKS: AtomicUsize = AtomicUsize::new(0);
-pub static PENDING_EMBEDDINGS: AtomicUsize = AtomicUsize::new(0);
-pub static COMPLETED_EMBEDDINGS: AtomicUsize = AtomicUsize::new(0);
+// Gemini model used for embeddings with the gemini provider
+pub const EMBEDDING_MODEL: &str = "gemini-embedding-001";
+
+// Provider embeddings are made with, recorded on roots so drift can be detected
+pub fn embedding_provider() -> &'static str {
+    match config().embed_provider {
+        EmbedProvider::Gemini => "gemini",
+        EmbedProvider::Http => "http",
+        EmbedProvider::Mock => "mock",
+    }
+}
+
+// Model recorded on roots; for the http provider the configured name, or the
+// server's URL when none is set
+pub fn embedding_model() -> String {
+    let config = config();
+    match config.embed_provider {
+        EmbedProvider::Gemini => EMBEDDING_MODEL.to_string(),
+        EmbedProvider::Http => config.embed_model.clone()
+            .or_else(|| config.embed_url.clone())
+            .unwrap_or_default(),
+        EmbedProvider::Mock => "mock".to_string(),
+    }
+}
+
+// Gemini counts roughly four characters per token
+const CHARS_PER_TOKEN: usize = 4;
+
+// Longest input the embedding model accepts, in tokens, and in characters by the
+// same estimate as estimated_tokens
+pub const EMBEDDING_MAX_TOKENS: usize = 2048;
+pub const EMBEDDING_MAX_CHARS: usize = EMBEDDING_MAX_TOKENS * CHARS_PER_TOKEN;
 
 // Job type for embedding work
 #[derive(Debug, Clone)]
 pub struct EmbeddingJob {
     pub chunk: String,
     pub entity_id: String,
+    // File the entity belongs to, used to track checkpoint progress
+    pub file_id: String,
     pub port: u16,
+    // Embed a language model summary of the chunk rather than the chunk itself
+    pub summarize: bool,
 }
 
-// Global HTTP client with connection pooling
+// Longest input given to the summary model, in characters; longer entities are
+// summarized from their start
+pub const SUMMARY_MAX_CHARS: usize = 32_000;
+
+// Instruction sent to the summary model ahead of an entity's code
+const SUMMARY_PROMPT: &str = "Summarize what the following code does in two or three sentences, \
+for someone searching a codebase for it. Mention the names of the functions, types or settings it defines. \
+Reply with the summary only.";
+
+// Longest summary the model may write, in tokens
+const SUMMARY_MAX_OUTPUT_TOKENS: u32 = 256;
+
+// Run tasks concurrently, or one after another in the given order for deterministic
+// runs, stopping at the first error
+pub async fn run_tasks<F>(tasks: Vec<F>) -> Result<()>
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    if config().deterministic {
+        for task in tasks {
+            task.await?;
+        }
+        return Ok(());
+    }
+    let handles: Vec<JoinHandle<Result<()>>> = tasks.into_iter().map(tokio::spawn).collect();
+    for handle in handles {
+        handle.await??;
+    }
+    Ok(())
+}
+
+// Global HTTP clients with connection pooling, sized from the config on first use
 lazy_static! {
     static ref embedding_client: reqwest::Client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .pool_max_idle_per_host(3000)
+        .timeout(Duration::from_secs(config().embed_timeout))
+        .pool_max_idle_per_host(config().embed_pool_size)
         .pool_idle_timeout(Duration::from_secs(30))
         .build()
         .expect("Failed to create HTTP client");
 
     static ref helix_client: reqwest::Client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(90))
-        .pool_max_idle_per_host(500)
+        .timeout(Duration::from_secs(config().helix_timeout))
+        .pool_max_idle_per_host(config().helix_pool_size)
         .pool_idle_timeout(Duration::from_secs(90))
         .build()
         .expect("Failed to create HTTP client");
 
+    // Rate and concurrency limits, sized from the config on first use
     static ref EMBEDDING_LIMITER: RateLimiter<NotKeyed, InMemoryState, DefaultClock> =
-        RateLimiter::direct(Quota::per_minute(NonZeroU32::new(4000).unwrap()));
+        RateLimiter::direct(Quota::per_minute(config().embed_rpm));
 
     static ref HELIX_LIMITER: RateLimiter<NotKeyed, InMemoryState, DefaultClock> =
-        RateLimiter::direct(Quota::per_second(NonZeroU32::new(100).unwrap()));
+        RateLimiter::direct(helix_quota(config().helix_rpm));
+
+    pub static ref FILE_PERMITS: Semaphore = Semaphore::new(config().concurrency);
+    static ref HELIX_PERMITS: Semaphore = Semaphore::new(config().helix_max_inflight);
+}
+
+// Quota of rpm requests per minute that lets at most a second's worth through at
+// once, so a run does not start with a burst of a whole minute's requests
+fn helix_quota(rpm: NonZeroU32) -> Quota {
+    let burst = NonZeroU32::new(rpm.get() / 60).unwrap_or(NonZeroU32::MIN);
+    Quota::per_minute(rpm).allow_burst(burst)
+}
+
+// Rough number of tokens the embedding API counts for a text
+pub fn estimated_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+// Separators tried before the default paragraph, sentence and word levels when
+// chunking code of a language, so that long code breaks between definitions
+fn code_delimiters(language: &str) -> &'static [&'static str] {
+    match language {
+        "python" => &["\nclass ", "\ndef ", "\nasync def ", "\n    def ", "\n    async def "],
+        "rust" => &["\nimpl ", "\nfn ", "\npub fn ", "\n    fn ", "\n    pub fn "],
+        "javascript" | "typescript" => &["\nclass ", "\nfunction ", "\nexport ", "\n  async ", "\n  static "],
+        "kotlin" => &["\nclass ", "\nfun ", "\n    fun "],
+        "swift" => &["\nclass ", "\nstruct ", "\nfunc ", "\n    func "],
+        "zig" => &["\npub fn ", "\nfn ", "\n    pub fn ", "\n    fn "],
+        "scala" => &["\nclass ", "\nobject ", "\ntrait ", "\ncase class ", "\ndef ", "\n  def "],
+        // Top level Haskell declarations start at the beginning of a line
+        "haskell" => &["\ndata ", "\nnewtype ", "\nclass ", "\ninstance ", "\n\n"],
+        "c" | "cpp" | "bash" => &["\n}\n"],
+        _ => &[],
+    }
+}
+
+// Chunking rules for code of a language (a name as returned by language_name);
+// other languages and plain text get the default rules
+fn chunk_rules(language: &str) -> RecursiveRules {
+    let mut rules = RecursiveRules::default();
+    let delimiters = code_delimiters(language);
+    if !delimiters.is_empty() {
+        let mut level = rules.levels[0].clone();
+        level.delimiters = Some(delimiters.iter().map(|delimiter| delimiter.to_string()).collect());
+        rules.levels.insert(0, level);
+    }
+    rules
 }
 
-// Chunk entity text
-pub fn chunk_entity(text: &str) -> Result<Vec<String>> {
+// Chunk entity text. language is the name of the language the text is code in,
+// empty for documentation and plain text.
+pub fn chunk_entity(text: &str, language: &str) -> Result<Vec<String>> {
     let tokenizer = CharacterTokenizer::new();
-    let chunker = RecursiveChunker::new(tokenizer, 2048, RecursiveRules::default());
+    let chunker = RecursiveChunker::new(tokenizer, 2048, chunk_rules(language));
     let chunks: Vec<RecursiveChunk> = chunker.chunk(&text.to_string());
     let chunks_str: Vec<String> = chunks.into_iter().map(|chunk| chunk.text).collect();
     Ok(chunks_str)
 }
 
-// Async version of embed_entity with rate limiting
-pub async fn embed_entity_async(text: String) -> Result<Vec<f64>> {
+// Hex encoded SHA-256 of file contents, used to detect changed files on update
+pub fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+// Hash an entity is matched on between updates; trailing whitespace is ignored
+pub fn entity_hash(entity_type: &str, text: &str) -> String {
+    let lines: Vec<&str> = text.trim().lines().map(str::trim_end).collect();
+    content_hash(&format!("{}\n{}", entity_type, lines.join("\n")))
+}
+
+// Longest random delay added when waiting for rate limit quota, so that requests
+// waiting at the same time do not all retry at the same instant
+const QUOTA_JITTER: Duration = Duration::from_millis(50);
+
+// Wait until embed_rpm allows a request with this many inputs. Quota is counted
+// per input, so a batch of inputs uses as much of it as the same inputs sent one by one.
+async fn wait_for_embedding_quota(inputs: usize) -> Result<()> {
+    let n = u32::try_from(inputs).ok().and_then(NonZeroU32::new)
+        .ok_or_else(|| anyhow::anyhow!("Invalid number of embedding inputs: {}", inputs))?;
+    EMBEDDING_LIMITER.until_n_ready_with_jitter(n, Jitter::up_to(QUOTA_JITTER)).await
+        .map_err(|_| anyhow::anyhow!("A request with {} inputs exceeds embed-rpm of {}", inputs, config().embed_rpm))
+}
+
+// Async version of embed_entity with rate limiting.
+// task_type tells Gemini what the vector is for, e.g. RETRIEVAL_DOCUMENT or RETRIEVAL_QUERY.
+#[tracing::instrument(skip_all, fields(len = text.len()))]
+pub async fn embed_entity_async(text: String, task_type: &str) -> Result<Vec<f64>> {
     // Handle empty text case to avoid API errors
     if text.trim().is_empty() {
-        return Err(anyhow::anyhow!("Cannot embed empty text"));
+        return Err(IndexError::EmbedError("Cannot embed empty text".to_string()).into());
+    }
+
+    // Mock vectors cost nothing, so they are not rate limited
+    if config().embed_provider != EmbedProvider::Mock {
+        wait_for_embedding_quota(1).await?;
     }
 
-    EMBEDDING_LIMITER.until_ready().await;
+    let result = match config().embed_provider {
+        EmbedProvider::Gemini => embed_gemini(&text, task_type).await,
+        EmbedProvider::Http => embed_http(&text, task_type).await,
+        EmbedProvider::Mock => Ok(embed_mock(&text)),
+    };
+    // Added as context, so transient failures are still retried
+    let mut result = result.map_err(|e| {
+        let message = e.to_string();
+        e.context(IndexError::EmbedError(message))
+    })?;
+    if config().normalize_embeddings {
+        l2_normalize(&mut result);
+    }
+    Ok(result)
+}
 
-    // Use gemini api to embed text with the global HTTP client
+// Embed text with the Gemini API using the global HTTP client
+async fn embed_gemini(text: &str, task_type: &str) -> Result<Vec<f64>> {
     let api_key = match env::var("GEMINI_API_KEY") {
         Ok(key) => key,
         Err(_) => return Err(anyhow::anyhow!("GEMINI_API_KEY environment variable not set"))
     };
 
-    let res = embedding_client.post("https://generativelanguage.googleapis.com/v1beta/models/gemini-embedding-001:embedContent")
+    let mut request = json!({
+        "model": format!("models/{}", EMBEDDING_MODEL),
+        "content": {
+            "parts": [{
+                "text": text,
+            }]
+        },
+        "task_type": task_type
+    });
+    if let Some(dimensions) = config().embed_dimensions {
+        request["output_dimensionality"] = json!(dimensions);
+    }
+
+    let url = format!("https://generativelanguage.googleapis.com/v1beta/models/{}:embedContent", EMBEDDING_MODEL);
+    let res = embedding_client.post(&url)
         .header("x-goog-api-key", api_key)
         .header("Content-Type", "application/json")
-        .json(&json!({
-            "model": "models/gemini-embedding-001",
-            "content": {
-                "parts": [{
-                    "text": text,
-                }]
-            },
-            "task_type": "SEMANTIC_SIMILARITY"
-        }))
+        .json(&request)
         .send()
-        .await?;
+        .await
+        .map_err(|e| send_error("Embedding request", "embed-timeout", config().embed_timeout, e))?;
 
     // Check response status
     if !res.status().is_success() {
         let status = res.status();
         let error_text = res.text().await.unwrap_or_else(|_| "<could not read response body>".to_string());
-        return Err(anyhow::anyhow!("API returned error status {}: {}", status, error_text));
+        return Err(status_error(status, format!("API returned error status {}: {}", status, error_text)));
     }
 
     let body = res.json::<Value>().await?;
@@ -116,7 +290,66 @@ pub async fn embed_entity_async(text: String) -> Result<Vec<f64>> {
     let embedding = body["embedding"]["values"].as_array()
         .ok_or_else(|| anyhow::anyhow!("Invalid embedding response format, missing 'values' array: {:?}", body))?;
 
-    // Convert values to f64, with better error handling
+    embedding_values(embedding)
+}
+
+// Embed text with a self-hosted embedding server, such as text-embeddings-inference
+// or infinity. The request body is embed_body with "{text}", "{task_type}" and
+// "{model}" filled in, and the vector is read from the response at embed_response_path.
+async fn embed_http(text: &str, task_type: &str) -> Result<Vec<f64>> {
+    let config = config();
+    let url = config.embed_url.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("embed-url must be set for the http embedding provider"))?;
+    let model = embedding_model();
+    let url = url.replace("{model}", &model);
+    // The text goes in last, so placeholders that happen to be in it are kept
+    let request = fill_template(&config.embed_body, &[("{task_type}", task_type), ("{model}", &model), ("{text}", text)]);
+
+    let mut builder = embedding_client.post(&url).json(&request);
+    if let Ok(api_key) = env::var("EMBED_API_KEY") {
+        builder = builder.bearer_auth(api_key);
+    }
+    let res = builder.send()
+        .await
+        .map_err(|e| send_error("Embedding request", "embed-timeout", config.embed_timeout, e))?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let error_text = res.text().await.unwrap_or_else(|_| "<could not read response body>".to_string());
+        return Err(status_error(status, format!("Embedding server returned error status {}: {}", status, error_text)));
+    }
+
+    let body = res.json::<Value>().await?;
+    let embedding = select_json(&body, &config.embed_response_path)
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow::anyhow!(
+            "Embedding server response has no array at embed-response-path {}: {:?}", config.embed_response_path, body
+        ))?;
+    embedding_values(embedding)
+}
+
+// Length of mock vectors when embed_dimensions is not set
+const MOCK_DIMENSIONS: usize = 768;
+
+// Vector with values in [-1, 1] drawn from a generator seeded with the text's
+// hash, so the same text always gets the same vector
+fn embed_mock(text: &str) -> Vec<f64> {
+    let dimensions = config().embed_dimensions.unwrap_or(MOCK_DIMENSIONS);
+    let digest = Sha256::digest(text.as_bytes());
+    let mut state = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    (0..dimensions).map(|_| {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        z as f64 / u64::MAX as f64 * 2.0 - 1.0
+    }).collect()
+}
+
+// Vector of an embedding response, failing on values that are not numbers
+fn embedding_values(embedding: &[Value]) -> Result<Vec<f64>> {
     let mut result = Vec::with_capacity(embedding.len());
     for (i, v) in embedding.iter().enumerate() {
         match v.as_f64() {
@@ -124,50 +357,439 @@ pub async fn embed_entity_async(text: String) -> Result<Vec<f64>> {
             None => return Err(anyhow::anyhow!("Non-numeric value at position {} in embedding: {:?}", i, v))
         }
     }
-
     Ok(result)
 }
 
-// Async version of post_request
+// Copy of a JSON template with placeholders replaced in every string, keys
+// excepted. They are replaced in the order given.
+fn fill_template(template: &Value, placeholders: &[(&str, &str)]) -> Value {
+    match template {
+        Value::String(s) => Value::String(placeholders.iter().fold(s.clone(), |s, (from, to)| s.replace(from, to))),
+        Value::Array(values) => Value::Array(values.iter().map(|v| fill_template(v, placeholders)).collect()),
+        Value::Object(map) => Value::Object(map.iter().map(|(k, v)| (k.clone(), fill_template(v, placeholders))).collect()),
+        other => other.clone(),
+    }
+}
+
+// Value at a path such as "data.0.embedding" or "$.data[0].embedding"; an empty
+// path or "$" selects the whole value
+fn select_json<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim().trim_start_matches('$').replace('[', ".").replace(']', "");
+    path.split('.').filter(|segment| !segment.is_empty()).try_fold(value, |value, segment| match value {
+        Value::Array(values) => values.get(segment.parse::<usize>().ok()?),
+        _ => value.get(segment),
+    })
+}
+
+// Short natural language summary of an entity's code, written by summary_model.
+// Summary requests count towards embed_rpm like embedding requests.
+#[tracing::instrument(skip_all, fields(len = text.len()))]
+pub async fn summarize_entity_async(text: &str) -> Result<String> {
+    if text.trim().is_empty() {
+        return Err(anyhow::anyhow!("Cannot summarize empty text"));
+    }
+
+    wait_for_embedding_quota(1).await?;
+
+    let api_key = match env::var("GEMINI_API_KEY") {
+        Ok(key) => key,
+        Err(_) => return Err(anyhow::anyhow!("GEMINI_API_KEY environment variable not set"))
+    };
+
+    let request = json!({
+        "contents": [{
+            "parts": [{
+                "text": format!("{}\n\n{}", SUMMARY_PROMPT, text),
+            }]
+        }],
+        "generationConfig": {
+            "maxOutputTokens": SUMMARY_MAX_OUTPUT_TOKENS,
+        }
+    });
+
+    let url = format!("https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent", config().summary_model);
+    let res = embedding_client.post(&url)
+        .header("x-goog-api-key", api_key)
+        .header("Content-Type", "application/json")
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| send_error("Summary request", "embed-timeout", config().embed_timeout, e))?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let error_text = res.text().await.unwrap_or_else(|_| "<could not read response body>".to_string());
+        return Err(status_error(status, format!("API returned error status {}: {}", status, error_text)));
+    }
+
+    let body = res.json::<Value>().await?;
+    let summary: String = body["candidates"][0]["content"]["parts"].as_array()
+        .ok_or_else(|| anyhow::anyhow!("Summary response missing 'parts' array: {:?}", body))?
+        .iter()
+        .filter_map(|part| part.get("text").and_then(|v| v.as_str()))
+        .collect();
+    let summary = summary.trim();
+    if summary.is_empty() {
+        return Err(anyhow::anyhow!("Summary response has no text: {:?}", body));
+    }
+    Ok(summary.to_string())
+}
+
+// Scale a vector to unit length; a zero vector is left as is
+fn l2_normalize(vector: &mut [f64]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+// Request bodies at least this large are gzipped when compress_requests is set
+const COMPRESS_MIN_BYTES: usize = 64 * 1024;
+
+// Set once Helix has failed a compressed request that succeeded uncompressed
+static COMPRESSION_REJECTED: AtomicBool = AtomicBool::new(false);
+
+// Set once a Helix request has timed out, so the hint on helix_max_inflight is given once
+static HELIX_TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+// URL of a Helix query on the configured host
+pub fn helix_url(port: u16, endpoint: &str) -> String {
+    let config = config();
+    format!("http://{}:{}/{}", config.helix_host, port, config.endpoint(endpoint))
+}
+
+// Run a future to completion from synchronous code on a new multi-threaded
+// runtime. Blocking a runtime thread on it would panic, or on a worker could
+// deadlock the limiters under load, so that is refused.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output, IndexError> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(IndexError::Other(anyhow::anyhow!(
+            "block_on cannot be called from within a tokio runtime; await the future instead"
+        )));
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| IndexError::Io { message: format!("Failed to start the tokio runtime: {}", e), source: e })?;
+    Ok(runtime.block_on(future))
+}
+
+// Post a query to Helix and return its JSON response. Every Helix and embedding
+// request is async and awaited on the tokio runtime; synchronous code runs them
+// through block_on, which refuses to run on a runtime thread.
 pub async fn post_request_async(url: &str, body: Value) -> Result<Value> {
+    let _permit = HELIX_PERMITS.acquire().await?;
     HELIX_LIMITER.until_ready().await;
 
-    // Use the global HTTP client with connection pooling
-    let res = match helix_client.post(url).json(&body).send().await {
-        Ok(response) => response,
-        Err(e) => {
-            if e.is_timeout() {
-                println!("Request timed out. Check if the server is running and responding.");
-            } else if e.is_connect() {
-                println!("Connection failed. Make sure the server is running at {}",url);
+    let body = serde_json::to_vec(&body)?;
+    let compress = config().compress_requests
+        && body.len() >= COMPRESS_MIN_BYTES
+        && !COMPRESSION_REJECTED.load(Ordering::Relaxed);
+    let mut res = send_helix(url, &body, compress).await?;
+    // Helix may not accept compressed bodies; send it again as is to find out.
+    // Other errors are not retried here, since create queries are not idempotent.
+    if compress && matches!(res.status(), reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE) {
+        let retry = send_helix(url, &body, false).await?;
+        if retry.status().is_success() && !COMPRESSION_REJECTED.swap(true, Ordering::Relaxed) {
+            warn!("Helix rejected a compressed request ({}), sending requests uncompressed", res.status());
+        }
+        res = retry;
+    }
+
+    let status = res.status();
+    let text = res.text().await?;
+    let endpoint = url.rsplit('/').next().unwrap_or(url);
+    if status == reqwest::StatusCode::NOT_FOUND {
+        debug!("Helix {} returned {}: {}", endpoint, status, text.trim());
+        let body = format!("no {} query; deploy the schema and queries in db/ with `helix deploy` from the repository root", endpoint);
+        return Err(helix_failure(endpoint, status, body));
+    }
+    if !status.is_success() {
+        return Err(helix_failure(endpoint, status, text.trim().to_string()));
+    }
+
+    let value: Value = serde_json::from_str(&text)
+        .map_err(|e| helix_failure(endpoint, status, format!("invalid JSON ({}): {}", e, text.trim())))?;
+    if let Some(message) = helix_error(&value) {
+        return Err(helix_failure(endpoint, status, message));
+    }
+    Ok(value)
+}
+
+fn helix_failure(endpoint: &str, status: reqwest::StatusCode, body: String) -> anyhow::Error {
+    IndexError::HelixError { endpoint: endpoint.to_string(), status: status.as_u16(), body }.into()
+}
+
+// Send a JSON body to Helix with the global HTTP client, gzipped if compress is set
+async fn send_helix(url: &str, body: &[u8], compress: bool) -> Result<reqwest::Response> {
+    let mut request = helix_client.post(url).header("Content-Type", "application/json");
+    request = if compress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(body)?;
+        request.header("Content-Encoding", "gzip").body(encoder.finish()?)
+    } else {
+        request.body(body.to_vec())
+    };
+
+    request.send().await.map_err(|e| {
+        if e.is_connect() {
+            warn!("Connection failed. Make sure the server is running at {}",url);
+        }
+        // A Helix instance with too many requests at once answers each of them late
+        if e.is_timeout() && !HELIX_TIMED_OUT.swap(true, Ordering::Relaxed) {
+            warn!("Helix requests are timing out, lower --helix-max-inflight (now {}) if Helix is overloaded", config().helix_max_inflight);
+        }
+        let endpoint = url.rsplit('/').next().unwrap_or(url);
+        let connect = e.is_connect();
+        let unreachable = connect || e.is_timeout();
+        let error = send_error(&format!("Helix {} request", endpoint), "helix-timeout", config().helix_timeout, e);
+        if connect {
+            // No connection was made, so Helix never saw the request
+            anyhow::Error::new(NotSent).context(IndexError::HelixUnreachable(error.to_string()))
+        } else if unreachable {
+            IndexError::HelixUnreachable(error.to_string()).into()
+        } else {
+            error
+        }
+    })
+}
+
+// Error message from a Helix response body with an `error` or `errors` field
+fn helix_error(value: &Value) -> Option<String> {
+    let error = value.get("error").or_else(|| value.get("errors"))?;
+    match error {
+        Value::Null => None,
+        Value::String(message) => Some(message.clone()),
+        Value::Array(errors) if errors.is_empty() => None,
+        Value::Array(errors) => Some(
+            errors.iter()
+                .map(|e| e.as_str().map(String::from).unwrap_or_else(|| e.to_string()))
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        other => Some(other.to_string()),
+    }
+}
+
+// A failure worth retrying: timeouts, dropped connections, rate limits and server errors
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+// Also true for errors with transient ones under added context, and for Helix
+// errors whose kind is worth retrying
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<TransientError>())
+        || error.downcast_ref::<IndexError>().is_some_and(IndexError::is_transient)
+}
+
+// A Helix request that failed before reaching Helix, so that sending it again
+// cannot store anything twice
+#[derive(Debug)]
+pub struct NotSent;
+
+impl std::fmt::Display for NotSent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the request was not sent")
+    }
+}
+
+impl std::error::Error for NotSent {}
+
+// Failures of a query that creates something that are safe to retry: the request
+// never reached Helix, or Helix turned it away with a 429. A timed out request
+// may have been stored, so it is not retried.
+pub fn is_unsent(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<NotSent>())
+        || matches!(error.downcast_ref::<IndexError>(), Some(IndexError::HelixError { status: 429, .. }))
+}
+
+fn request_error(context: &str, e: reqwest::Error) -> anyhow::Error {
+    let message = format!("{}: {}", context, e);
+    if e.is_timeout() || e.is_connect() || e.is_request() {
+        anyhow::Error::new(TransientError(message))
+    } else {
+        anyhow::anyhow!(message)
+    }
+}
+
+// Error for a request that could not be sent or got no response in time,
+// naming the option that raises the timeout
+fn send_error(context: &str, timeout_option: &str, timeout_secs: u64, e: reqwest::Error) -> anyhow::Error {
+    if e.is_timeout() {
+        let context = format!("{} timed out after {}s, raise --{} if the server is slow to respond", context, timeout_secs, timeout_option);
+        return request_error(&context, e);
+    }
+    request_error(&format!("{} failed", context), e)
+}
+
+fn status_error(status: reqwest::StatusCode, message: String) -> anyhow::Error {
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        anyhow::Error::new(TransientError(message))
+    } else {
+        anyhow::anyhow!(message)
+    }
+}
+
+// Longest wait between two attempts of a request
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Run a request, retrying transient failures with exponential backoff
+pub async fn retry_transient<T, F, Fut>(retries: u32, request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    retry_when(retries, is_transient, request).await
+}
+
+// Run a query that creates something, retrying only failures where Helix cannot
+// have stored it
+pub async fn retry_unsent<T, F, Fut>(retries: u32, request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    retry_when(retries, is_unsent, request).await
+}
+
+// Run a request, retrying the failures `retryable` accepts with exponential
+// backoff from 500ms up to MAX_RETRY_DELAY
+async fn retry_when<T, F, Fut>(retries: u32, retryable: fn(&anyhow::Error) -> bool, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(e) if attempt < retries && retryable(&e) => {
+                attempt += 1;
+                let backoff = 2u64.saturating_pow(attempt - 1).saturating_mul(500);
+                let delay = Duration::from_millis(backoff).min(MAX_RETRY_DELAY);
+                warn!("Retrying in {:?} (attempt {} of {}): {}", delay, attempt, retries, e);
+                tokio::time::sleep(delay).await;
             }
-            return Err(anyhow::anyhow!("HTTP request failed: {}", e));
+            result => return result,
         }
+    }
+}
+
+// Check that Helix is accepting connections, retrying a few times.
+// Any HTTP response counts as reachable, even an error status.
+pub async fn ping_helix(port: u16, attempts: u32) -> Result<()> {
+    let url = helix_url(port, endpoints::GET_ROOT);
+    let mut last_error = None;
+    for attempt in 1..=attempts {
+        match helix_client.post(&url).json(&json!({})).timeout(Duration::from_secs(5)).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+        if attempt < attempts {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    Err(IndexError::HelixUnreachable(format!(
+        "Cannot reach Helix at {}:{} ({}). Make sure the instance is running, e.g. with `helix deploy`",
+        config().helix_host, port, last_error.map(|e| e.to_string()).unwrap_or_default()
+    )).into())
+}
+
+// File extension, or for files without one, the extension of the language named by a
+// well-known file name or a shebang line. Falls back to "txt" when unsure.
+pub fn file_extension(file_path: &Path, source_code: &str) -> String {
+    if let Some(extension) = file_path.extension().and_then(|s| s.to_str()) {
+        return extension.to_string();
+    }
+    let file_name = file_path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+    let detected = match file_name {
+        "SConstruct" | "SConscript" | "wscript" => Some("py"),
+        "Jakefile" => Some("js"),
+        _ => shebang_extension(source_code),
     };
+    detected.unwrap_or("txt").to_string()
+}
 
-    Ok(res.json::<Value>().await?)
-}
-
-// Get language from file extension
-pub fn get_language(file_path: &Path) -> Option<tree_sitter::Language> {
-    let extension = file_path.extension().and_then(|s| s.to_str());
-    match extension {
-        Some("py") => Some(tree_sitter_python::LANGUAGE.into()),
-        Some("rs") => Some(tree_sitter_rust::LANGUAGE.into()),
-        Some("zig") => Some(tree_sitter_zig::LANGUAGE.into()),
-        Some("cpp") | Some("cc") | Some("cxx") => Some(tree_sitter_cpp::LANGUAGE.into()),
-        Some("c") | Some("h") => Some(tree_sitter_c::LANGUAGE.into()),
-        Some("ts") | Some("mts") | Some("cts")=> Some(tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into()),
-        Some("tsx") => Some(tree_sitter_typescript::LANGUAGE_TSX.into()),
-        Some("js") | Some("jsx") | Some("mjs") | Some("mjsx") | Some("cjs") | Some("cjsx") => Some(tree_sitter_javascript::LANGUAGE.into()),
+// Extension for the interpreter of a `#!/usr/bin/python3`, `#!/bin/bash` or `#!/usr/bin/env -S node` line
+fn shebang_extension(source_code: &str) -> Option<&'static str> {
+    let line = source_code.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    match interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "python" | "pypy" => Some("py"),
+        "node" | "nodejs" | "bun" => Some("js"),
+        "ts-node" | "tsx" => Some("ts"),
+        "bash" | "sh" => Some("sh"),
+        "kotlin" => Some("kts"),
+        "swift" => Some("swift"),
+        "scala" => Some("scala"),
+        "runghc" | "runhaskell" => Some("hs"),
         _ => None,
     }
 }
 
+// Language a file can be parsed as
+#[derive(Clone)]
+pub struct LanguageInfo {
+    pub language: tree_sitter::Language,
+    // Key of the language in index-types.json, shared by all of its extensions
+    pub index_type: &'static str,
+    // Name stored in the file's metadata, e.g. "rust"
+    pub name: &'static str,
+}
+
+// Language of a file extension, None for files without a grammar. This is the one
+// place extensions are mapped to grammars and index-types.json keys. `.h` headers
+// are parsed as C++, whose grammar also covers C declarations.
+pub fn language_info(extension: &str) -> Option<LanguageInfo> {
+    let (language, index_type, name): (tree_sitter::Language, _, _) = match extension {
+        "py" | "pyi" => (tree_sitter_python::LANGUAGE.into(), "py", "python"),
+        "rs" => (tree_sitter_rust::LANGUAGE.into(), "rs", "rust"),
+        "zig" => (tree_sitter_zig::LANGUAGE.into(), "zig", "zig"),
+        "cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" | "h" => (tree_sitter_cpp::LANGUAGE.into(), "cpp", "cpp"),
+        "c" => (tree_sitter_c::LANGUAGE.into(), "c", "c"),
+        "ts" | "mts" | "cts" => (tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(), "ts", "typescript"),
+        "tsx" => (tree_sitter_typescript::LANGUAGE_TSX.into(), "tsx", "typescript"),
+        "js" | "jsx" | "mjs" | "mjsx" | "cjs" | "cjsx" => (tree_sitter_javascript::LANGUAGE.into(), "js", "javascript"),
+        "json" => (tree_sitter_json::LANGUAGE.into(), "json", "json"),
+        "yaml" | "yml" => (tree_sitter_yaml::LANGUAGE.into(), "yaml", "yaml"),
+        "toml" => (tree_sitter_toml_ng::LANGUAGE.into(), "toml", "toml"),
+        "kt" | "kts" => (tree_sitter_kotlin_ng::LANGUAGE.into(), "kt", "kotlin"),
+        "swift" => (tree_sitter_swift::LANGUAGE.into(), "swift", "swift"),
+        "sh" | "bash" => (tree_sitter_bash::LANGUAGE.into(), "sh", "bash"),
+        "scala" | "sc" => (tree_sitter_scala::LANGUAGE.into(), "scala", "scala"),
+        "hs" => (tree_sitter_haskell::LANGUAGE.into(), "hs", "haskell"),
+        _ => return None,
+    };
+    Some(LanguageInfo { language, index_type, name })
+}
+
+// Name of the language of an extension as stored on files, empty without a grammar
+pub fn language_name(extension: &str) -> &'static str {
+    language_info(extension).map_or("", |info| info.name)
+}
+
+// Key of an extension in index-types.json; extensions without a grammar are their own key
+pub fn index_type(extension: &str) -> &str {
+    language_info(extension).map_or(extension, |info| info.index_type)
+}
+
 // Code entity struct
 #[derive(Clone)]
 pub struct CodeEntity {
     pub entity_type: String,
+    // Symbol name for definitions, e.g. the function or class name
+    pub name: Option<String>,
     pub start_byte: usize,
     pub end_byte: usize,
     pub order: usize,
@@ -176,20 +798,18 @@ pub struct CodeEntity {
 
 // Shared delete functions used by both ingestion and update processes
 
+// Entity ids dropped per deleteEntities request
+const DELETE_BATCH_SIZE: usize = 500;
+
 #[async_recursion]
 pub async fn delete_folder(
     folder_id: String,
     port: u16,
 ) -> Result<()> {
-    let subfolder_name_ids  = get_sub_folders(folder_id.clone(), port).await?;
-    // println!("Subfolder IDs: {:#?}", subfolder_name_ids);
-
-    // Find folders that are not in the index
-    let unseen_folders = subfolder_name_ids.keys().cloned().collect::<Vec<_>>();
-
-    let tasks: Vec<JoinHandle<Result<()>>> = unseen_folders.into_iter().map(|folder_name| {
-        let sub_folder_id = subfolder_name_ids.get(&folder_name).unwrap().to_string().clone();
+    // Duplicates are deleted too, so nothing under the folder is left behind
+    let subfolder_ids = get_sub_folders(folder_id.clone(), port).await?;
 
+    let tasks: Vec<JoinHandle<Result<()>>> = subfolder_ids.into_all().map(|sub_folder_id| {
         tokio::spawn(Box::pin(async move {
             delete_folder(sub_folder_id, port).await
         }))
@@ -200,33 +820,40 @@ pub async fn delete_folder(
     }
 
     // Get folder files
-    let folder_file_name_ids = get_folder_files(folder_id.clone(), port).await?;
-    // println!("Subfolder file IDs: {:#?}", folder_file_name_ids);
-
-    let unseen_files = folder_file_name_ids.keys().cloned().collect::<Vec<_>>();
+    let folder_files = get_folder_files(folder_id.clone(), port).await?;
+    delete_files(folder_files.into_all().map(|record| record.id).collect(), port).await?;
 
-    delete_files(unseen_files.clone(), folder_file_name_ids.clone(), port).await?;
-
-    let url = format!("http://localhost:{}/{}", port, "deleteFolder");
+    let url = helix_url(port, endpoints::DELETE_FOLDER);
     let payload = json!({ "folder_id": folder_id });
     post_request_async(&url, payload).await?;
     Ok(())
 }
 
+// Delete a root with all of its folders, files and entities
+pub async fn delete_root(root_id: String, port: u16) -> Result<()> {
+    let root_folder_ids = get_root_folders(root_id.clone(), port).await?;
+    let tasks: Vec<JoinHandle<Result<()>>> = root_folder_ids.into_all().map(|folder_id| {
+        tokio::spawn(delete_folder(folder_id, port))
+    }).collect();
+    for task in tasks {
+        task.await??;
+    }
+
+    let root_files = get_root_files(root_id.clone(), port).await?;
+    delete_files(root_files.into_all().map(|record| record.id).collect(), port).await?;
+
+    let url = helix_url(port, endpoints::DELETE_ROOT);
+    post_request_async(&url, json!({ "root_id": root_id })).await?;
+    Ok(())
+}
+
 pub async fn delete_files(
-    unseen_files: Vec<String>,
-    file_name_ids: HashMap<String, (String, String)>,
+    file_ids: Vec<String>,
     port: u16
 ) -> Result<()> {
-    let tasks: Vec<JoinHandle<Result<()>>> = unseen_files.into_iter().map(|file_name| {
-        let file_id = file_name_ids.get(&file_name).unwrap().0.clone();
-
+    let tasks: Vec<JoinHandle<Result<()>>> = file_ids.into_iter().map(|file_id| {
         tokio::spawn(async move {
-            let url = format!("http://localhost:{}/{}", port, "deleteFile");
-            let payload = json!({ "file_id": file_id });
-            let _ = post_request_async(&url, payload).await;
-            
-            let _ = delete_entities(file_id, true, port).await;
+            delete_file(file_id, port).await;
             Ok(())
         })
     }).collect();
@@ -237,51 +864,80 @@ pub async fn delete_files(
     Ok(())
 }
 
-#[async_recursion]
-pub async fn delete_entities(
-    parent_id: String,
-    is_super: bool,
-    port: u16,
-) -> Result<()> {
-    let url;
-    let body;
-    let res_index;
-    let delete_url;
-    if is_super {
-        url = format!("http://localhost:{}/{}", port, "getFileEntities");
-        body = json!({ "file_id": parent_id });
-        res_index = "entity";
-        delete_url = format!("http://localhost:{}/{}", port, "deleteSuperEntity");
-    } else {
-        url = format!("http://localhost:{}/{}", port, "getSubEntities");
-        body = json!({ "entity_id": parent_id });
-        res_index = "entities";
-        delete_url = format!("http://localhost:{}/{}", port, "deleteSubEntity");
-    }
+pub async fn delete_file(file_id: String, port: u16) {
+    // Entities are looked up through the file, so delete them first
+    let _ = delete_file_entities(file_id.clone(), port).await;
 
-    let response = post_request_async(&url, body).await?;
-    let entities = response
-        .get(res_index)
-        .and_then(|v| v.as_array())
-        .ok_or_else(|| anyhow::anyhow!("Entities not found"))?;
+    let url = helix_url(port, endpoints::DELETE_FILE);
+    let payload = json!({ "file_id": file_id });
+    let _ = post_request_async(&url, payload).await;
+}
 
-    let entity_ids: Vec<String> = entities
-        .iter()
-        .map(|v| v.get("id").and_then(|v| v.as_str()).unwrap().to_string())
+// Number of files linking to an entity; more than one when shared by deduplicated copies
+async fn entity_file_count(entity_id: &str, port: u16) -> Result<usize> {
+    let url = helix_url(port, endpoints::GET_ENTITY_FILE);
+    let response = post_request_async(&url, json!({ "entity_id": entity_id })).await?;
+    Ok(match response.get("file") {
+        Some(Value::Array(files)) => files.len(),
+        Some(Value::Null) | None => 0,
+        Some(_) => 1,
+    })
+}
+
+// Delete all entities of a file, with their subentities and embeddings
+pub async fn delete_file_entities(file_id: String, port: u16) -> Result<()> {
+    let entity_ids = get_file_entities(file_id, port).await?
+        .into_iter()
+        .map(|entity| entity.id)
         .collect();
+    delete_super_entities(entity_ids, port).await
+}
 
-    let tasks: Vec<JoinHandle<Result<()>>> = entity_ids.into_iter().map(|entity_id| {
-        let delete_url_clone = delete_url.clone();
-        tokio::spawn(Box::pin(async move {
-            let _ = delete_entities(entity_id.clone(), false, port).await;
-            let _ = post_request_async(&delete_url_clone, json!({ "entity_id": entity_id })).await;
-            Ok(())
-        }))
-    }).collect();
+// Delete top level entities with their subentities and embeddings.
+// Entities shared with a deduplicated copy stay until their last file is deleted.
+pub async fn delete_super_entities(entity_ids: Vec<String>, port: u16) -> Result<()> {
+    let owned: Vec<Option<String>> = stream::iter(entity_ids)
+        .map(|entity_id| async move {
+            let shared = entity_file_count(&entity_id, port).await? > 1;
+            Ok::<_, anyhow::Error>((!shared).then_some(entity_id))
+        })
+        .buffer_unordered(config().helix_max_inflight)
+        .try_collect()
+        .await?;
+    let owned: Vec<String> = owned.into_iter().flatten().collect();
+    if owned.is_empty() {
+        return Ok(());
+    }
 
-    for task in tasks {
-        task.await??;
+    let entity_ids = entity_tree(owned, port).await?;
+    let url = helix_url(port, endpoints::DELETE_ENTITIES);
+    for batch in entity_ids.chunks(DELETE_BATCH_SIZE) {
+        post_request_async(&url, json!({ "entity_ids": batch })).await?;
     }
+    Ok(())
+}
 
+// Delete embeddings by id, in batches like entities
+pub async fn delete_embeddings(embedding_ids: Vec<String>, port: u16) -> Result<()> {
+    let url = helix_url(port, endpoints::DELETE_EMBEDDINGS);
+    for batch in embedding_ids.chunks(DELETE_BATCH_SIZE) {
+        post_request_async(&url, json!({ "embedding_ids": batch })).await?;
+    }
     Ok(())
 }
+
+// The given entities and all their subentities, fetched one level at a time
+async fn entity_tree(entity_ids: Vec<String>, port: u16) -> Result<Vec<String>> {
+    let mut tree = Vec::new();
+    let mut level = entity_ids;
+    while !level.is_empty() {
+        let children: Vec<Vec<EntityRecord>> = stream::iter(level.clone())
+            .map(|entity_id| get_sub_entities(entity_id, port))
+            .buffer_unordered(config().helix_max_inflight)
+            .try_collect()
+            .await?;
+        tree.append(&mut level);
+        level = children.into_iter().flatten().map(|entity| entity.id).collect();
+    }
+    Ok(tree)
+}
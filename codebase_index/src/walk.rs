@@ -0,0 +1,212 @@
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{DirEntry, WalkBuilder};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::config::config;
+use crate::utils::content_hash;
+
+// Root of the current walk and the canonical directories entered so far
+#[derive(Default)]
+struct WalkState {
+    root: PathBuf,
+    visited: HashSet<PathBuf>,
+}
+
+lazy_static! {
+    static ref WALK: Mutex<WalkState> = Mutex::new(WalkState::default());
+    // Built from the config on first use; the globs were checked when it was loaded
+    static ref INCLUDE: GlobSet = glob_set(&config().include).unwrap_or_else(|_| GlobSet::empty());
+    static ref EXCLUDE: GlobSet = glob_set(&config().exclude).unwrap_or_else(|_| GlobSet::empty());
+}
+
+// Path from the root of the current walk, or the path itself outside of it
+pub fn relative_path(path: &Path) -> PathBuf {
+    let root = WALK.lock().unwrap().root.clone();
+    path.strip_prefix(&root).unwrap_or(path).to_path_buf()
+}
+
+// Start a walk of root, forgetting directories visited by earlier runs
+pub fn start(root: &Path) {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let mut walk = WALK.lock().unwrap();
+    walk.visited.clear();
+    walk.visited.insert(root.clone());
+    walk.root = root;
+}
+
+// Direct children of a folder that should be indexed
+pub fn entries(current_path: &Path) -> Vec<DirEntry> {
+    let mut walker_builder = walker(current_path);
+    walker_builder.max_depth(Some(1));
+    walker_builder.build()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.path() != current_path)
+        .collect()
+}
+
+// Everything under a folder that should be indexed, each folder before its contents
+pub fn tree(current_path: &Path) -> Vec<DirEntry> {
+    walker(current_path).build()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.path() != current_path)
+        .collect()
+}
+
+// Fingerprint of a folder and of every folder in its tree, from the names, sizes
+// and modification times of the entries below it that should be indexed. Anything
+// added, removed, renamed or written below a folder changes its fingerprint.
+pub fn folder_fingerprints(current_path: &Path, entries: &[DirEntry]) -> HashMap<PathBuf, String> {
+    let mut lines: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    let mut fingerprints = HashMap::new();
+    let mut fingerprint = |path: &Path, lines: &mut HashMap<PathBuf, Vec<String>>| {
+        let mut children = lines.remove(path).unwrap_or_default();
+        children.sort();
+        let hash = content_hash(&children.join("\n"));
+        fingerprints.insert(path.to_path_buf(), hash.clone());
+        hash
+    };
+    // Each folder comes before its contents, so in reverse its contents are done first
+    for entry in entries.iter().rev() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy();
+        let line = if entry.file_type().is_some_and(|t| t.is_dir()) {
+            format!("d {} {}", name, fingerprint(path, &mut lines))
+        } else {
+            let metadata = entry.metadata().ok();
+            let modified = metadata.as_ref()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_nanos());
+            format!("f {} {} {}", name, metadata.map_or(0, |metadata| metadata.len()), modified)
+        };
+        if let Some(parent) = path.parent() {
+            lines.entry(parent.to_path_buf()).or_default().push(line);
+        }
+    }
+    fingerprint(current_path, &mut lines);
+    fingerprints
+}
+
+// Fingerprints of a folder's tree, walked on its own. Directories it enters are
+// not counted as visited, so the walk that indexes them still enters links to them.
+pub fn tree_fingerprints(current_path: &Path) -> HashMap<PathBuf, String> {
+    let visited = WALK.lock().unwrap().visited.clone();
+    let entries = tree(current_path);
+    WALK.lock().unwrap().visited = visited;
+    folder_fingerprints(current_path, &entries)
+}
+
+fn walker(current_path: &Path) -> WalkBuilder {
+    let mut walker_builder = WalkBuilder::new(current_path);
+    walker_builder.follow_links(config().follow_symlinks);
+    if config().deterministic {
+        walker_builder.sort_by_file_name(|a, b| a.cmp(b));
+    }
+
+    // Leave out skipped directories, and links that should not be followed before
+    // the walker descends into them; hidden ones such as .git are skipped by the walker
+    walker_builder.filter_entry(|entry| {
+        let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+        !(is_dir && is_skipped(entry.path())) && is_selected(entry.path(), is_dir) && should_visit(entry)
+    });
+    walker_builder
+}
+
+// Whether a path is, or is inside, a directory left out by skip_dirs
+pub fn is_skipped(path: &Path) -> bool {
+    in_dirs(path, &config().skip_dirs)
+}
+
+// Whether a path passes the include and exclude globs, matched against its path
+// from the walk's root. Directories are only checked against exclude, since files
+// below them may still be included.
+pub fn is_selected(path: &Path, is_dir: bool) -> bool {
+    if INCLUDE.is_empty() && EXCLUDE.is_empty() {
+        return true;
+    }
+    let relative = relative_path(path);
+    if EXCLUDE.is_match(&relative) {
+        debug!("Skipping {}: excluded", path.display());
+        return false;
+    }
+    is_dir || INCLUDE.is_empty() || INCLUDE.is_match(&relative)
+}
+
+// Globs matched against paths from the root. `*` stays within one folder and `**`
+// crosses folders; a glob without a slash matches a name in any folder, as in .gitignore.
+pub fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let glob = glob.trim_start_matches("./");
+        let pattern = if glob.contains('/') { glob.to_string() } else { format!("**/{}", glob) };
+        builder.add(GlobBuilder::new(&pattern).literal_separator(true).build()?);
+    }
+    Ok(builder.build()?)
+}
+
+// Whether a file is inside a directory indexed as structure only, which gets
+// folder and file nodes but no entities or embeddings
+pub fn is_structure_only(path: &Path) -> bool {
+    in_dirs(path, &config().structure_only_dirs)
+}
+
+// Whether a directory on the way from the walk's root to path matches dirs,
+// by name, or by path from the root for entries that contain a slash
+fn in_dirs(path: &Path, dirs: &[String]) -> bool {
+    if dirs.is_empty() {
+        return false;
+    }
+    let relative = relative_path(path);
+    let mut components: Vec<_> = relative.components().collect();
+    if !path.is_dir() {
+        components.pop();
+    }
+
+    let mut prefix = PathBuf::new();
+    components.into_iter().any(|component| {
+        prefix.push(component);
+        let name = component.as_os_str().to_string_lossy();
+        dirs.iter().any(|dir| *dir == name || Path::new(dir) == prefix)
+    })
+}
+
+// Skip symlinks unless following them is enabled, symlinks that leave the root,
+// and directories already entered through another path
+fn should_visit(entry: &DirEntry) -> bool {
+    let path = entry.path();
+    if entry.path_is_symlink() && !config().follow_symlinks {
+        debug!("Skipping symlink {}", path.display());
+        return false;
+    }
+
+    let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+    if !entry.path_is_symlink() && !is_dir {
+        return true;
+    }
+
+    let Ok(canonical) = path.canonicalize() else {
+        warn!("Skipping {}: could not resolve path", path.display());
+        return false;
+    };
+
+    let mut walk = WALK.lock().unwrap();
+    if !entry.path_is_symlink() {
+        // Real directories are always entered, and recorded so links to them can be skipped
+        walk.visited.insert(canonical);
+        return true;
+    }
+    if !canonical.starts_with(&walk.root) {
+        warn!("Skipping {}: points outside the root at {}", path.display(), canonical.display());
+        return false;
+    }
+    if is_dir && !walk.visited.insert(canonical) {
+        warn!("Skipping {}: directory already visited, possible symlink cycle", path.display());
+        return false;
+    }
+    true
+}
@@ -0,0 +1,324 @@
+use anyhow::Result;
+use ignore::gitignore::Gitignore;
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::{index_types, file_types};
+use crate::ingestion::{create_folder, populate, process_file, reset_ingested_hashes};
+use crate::queries::{get_root_folders, get_root_files, get_sub_folders, get_folder_files, FileRecord};
+use crate::report::Stage;
+use crate::run::Run;
+use crate::updater::{needs_update, update_file};
+use crate::utils::{delete_file, delete_folder};
+use crate::walk;
+
+// Quiet period after the last change before a batch of changes is indexed
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Folder (or the root itself) as stored in the index
+struct IndexedFolder {
+    id: String,
+    is_root: bool,
+    folders: HashMap<String, String>,
+    files: HashMap<String, FileRecord>,
+}
+
+// Result of looking up a directory in the index
+enum Lookup {
+    Found(IndexedFolder),
+    // The first directory on the way that is not indexed yet, and its indexed parent
+    Missing(PathBuf, IndexedFolder),
+}
+
+// Shared state for applying a batch of changes
+struct WatchedRoot {
+    root_path: PathBuf,
+    root_id: String,
+    port: u16,
+    index_types: Arc<Value>,
+    file_types: Arc<Value>,
+    run: Arc<Run>,
+    // Index everything in a folder that is new to the index, rather than only the
+    // changed paths in it
+    populate_new_folders: bool,
+}
+
+// Keep a root in sync with changes under root_path until shutdown resolves
+pub async fn watch(
+    root_path: PathBuf,
+    root_id: String,
+    port: u16,
+    run: Arc<Run>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let root = WatchedRoot::new(root_path, root_id, port, run, true)?;
+    walk::start(&root.root_path);
+
+    let (event_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| {
+        let _ = event_tx.send(result);
+    })?;
+    debouncer.watcher().watch(&root.root_path, RecursiveMode::Recursive)?;
+    let (gitignore, _) = Gitignore::new(root.root_path.join(".gitignore"));
+
+    info!("Watching {} for changes", root.root_path.display());
+    tokio::pin!(shutdown);
+    loop {
+        let result = tokio::select! {
+            _ = &mut shutdown => break,
+            result = events.recv() => result,
+        };
+        let Some(result) = result else {
+            break;
+        };
+
+        match result {
+            Ok(events) => {
+                let paths: BTreeSet<PathBuf> = events.into_iter()
+                    .map(|event| event.path)
+                    .filter(|path| !root.is_ignored(&gitignore, path))
+                    .collect();
+                if paths.is_empty() {
+                    continue;
+                }
+                info!("Indexing {} changed paths", paths.len());
+                reset_ingested_hashes();
+                if let Err(e) = root.apply(paths).await {
+                    warn!("Failed to apply changes: {}", e);
+                }
+            }
+            Err(e) => warn!("Watch error: {}", e),
+        }
+    }
+
+    info!("Stopped watching {}", root.root_path.display());
+    Ok(())
+}
+
+// Index a given set of created, changed or deleted paths under a root, the way
+// a watch applies changes it sees. Relative paths are taken from the root.
+pub async fn apply_paths(
+    root_path: PathBuf,
+    root_id: String,
+    port: u16,
+    run: Arc<Run>,
+    paths: Vec<PathBuf>,
+) -> Result<()> {
+    let root = WatchedRoot::new(root_path, root_id, port, run, false)?;
+    walk::start(&root.root_path);
+    let (gitignore, _) = Gitignore::new(root.root_path.join(".gitignore"));
+
+    let paths: BTreeSet<PathBuf> = paths.into_iter()
+        .map(|path| root.root_path.join(path))
+        .filter(|path| {
+            let ignored = root.is_ignored(&gitignore, path);
+            if ignored {
+                debug!("Skipping {}: outside the root or ignored", path.display());
+            }
+            !ignored
+        })
+        .collect();
+    info!("Indexing {} listed paths", paths.len());
+    reset_ingested_hashes();
+    root.apply(paths).await
+}
+
+impl WatchedRoot {
+    fn new(
+        root_path: PathBuf,
+        root_id: String,
+        port: u16,
+        run: Arc<Run>,
+        populate_new_folders: bool,
+    ) -> Result<WatchedRoot> {
+        Ok(WatchedRoot {
+            index_types: index_types()?,
+            file_types: file_types()?,
+            root_path,
+            root_id,
+            port,
+            run,
+            populate_new_folders,
+        })
+    }
+
+    // Hidden paths, skipped directories, paths left out by include and exclude, and
+    // paths matched by the root .gitignore, as skipped by the walker
+    fn is_ignored(&self, gitignore: &Gitignore, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root_path) else {
+            return true;
+        };
+        let hidden = relative.components().any(|component| {
+            matches!(component, Component::Normal(name) if name.to_string_lossy().starts_with('.'))
+        });
+        hidden || walk::is_skipped(path) || !walk::is_selected(path, path.is_dir()) || gitignore.matched_path_or_any_parents(path, path.is_dir()).is_ignore()
+    }
+
+    // Index a batch of changed paths, grouped by the directory they are in
+    async fn apply(&self, paths: BTreeSet<PathBuf>) -> Result<()> {
+        let mut by_dir: BTreeMap<PathBuf, BTreeSet<PathBuf>> = BTreeMap::new();
+        for path in paths {
+            if let Some(dir) = path.parent() {
+                by_dir.entry(dir.to_path_buf()).or_default().insert(path);
+            }
+        }
+
+        // Parents sort before their children, so new folders are created before
+        // changes inside them are seen; populating a new folder covers those changes
+        let mut created: Vec<PathBuf> = Vec::new();
+        for (dir, paths) in by_dir {
+            if created.iter().any(|folder| dir.starts_with(folder)) {
+                continue;
+            }
+            match self.lookup(&dir).await? {
+                Lookup::Found(folder) => {
+                    for path in paths {
+                        if self.apply_path(&path, &folder).await {
+                            created.push(path);
+                        }
+                    }
+                }
+                // Nothing to do for changes in a new folder that is already gone again
+                Lookup::Missing(path, parent) if path.is_dir() && self.populate_new_folders => {
+                    self.create_folder(&path, &parent).await;
+                    created.push(path);
+                }
+                Lookup::Missing(..) if dir.is_dir() => match self.create_folders(&dir).await {
+                    Ok(folder) => {
+                        for path in paths {
+                            self.apply_path(&path, &folder).await;
+                        }
+                    }
+                    Err(e) => self.run.record_failure(dir.display(), Stage::CreateFolder, e),
+                },
+                Lookup::Missing(..) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Find a directory in the index by following folder names from the root
+    async fn lookup(&self, dir: &Path) -> Result<Lookup> {
+        let relative = dir.strip_prefix(&self.root_path)?;
+        let mut folder = IndexedFolder {
+            id: self.root_id.clone(),
+            is_root: true,
+            folders: get_root_folders(self.root_id.clone(), self.port).await?.by_name,
+            files: HashMap::new(),
+        };
+        let mut path = self.root_path.clone();
+
+        for component in relative.components() {
+            let name = component.as_os_str().to_string_lossy().to_string();
+            path.push(&name);
+            let Some(folder_id) = folder.folders.get(&name).cloned() else {
+                return Ok(Lookup::Missing(path, folder));
+            };
+            folder = IndexedFolder {
+                folders: get_sub_folders(folder_id.clone(), self.port).await?.by_name,
+                id: folder_id,
+                is_root: false,
+                files: HashMap::new(),
+            };
+        }
+
+        folder.files = if folder.is_root {
+            get_root_files(folder.id.clone(), self.port).await?.by_name
+        } else {
+            get_folder_files(folder.id.clone(), self.port).await?.by_name
+        };
+        Ok(Lookup::Found(folder))
+    }
+
+    // Create the folders on the way to dir that are missing from the index, without
+    // indexing their contents, and return dir as indexed
+    async fn create_folders(&self, dir: &Path) -> Result<IndexedFolder> {
+        loop {
+            match self.lookup(dir).await? {
+                Lookup::Found(folder) => return Ok(folder),
+                Lookup::Missing(path, parent) => {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    debug!("Folder {} created", path.display());
+                    create_folder(&name, &parent.id, parent.is_root, self.port).await?;
+                }
+            }
+        }
+    }
+
+    // Create, update or delete the node for one path.
+    // Returns true if a new folder was created and populated.
+    async fn apply_path(&self, path: &Path, folder: &IndexedFolder) -> bool {
+        let Some(name) = path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+            return false;
+        };
+
+        if path.is_dir() {
+            if folder.folders.contains_key(&name) {
+                return false;
+            }
+            self.create_folder(path, folder).await;
+            return true;
+        }
+
+        if path.is_file() {
+            match folder.files.get(&name) {
+                Some(record) if needs_update(path, record, 0) => {
+                    debug!("File {} changed", path.display());
+                    if let Err(e) = update_file(
+                        path.to_path_buf(), record.id.clone(), self.port,
+                        self.index_types.clone(), self.file_types.clone(), self.run.clone(),
+                    ).await {
+                        self.run.record_failure(path.display(), Stage::UpdateFile, e);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    debug!("File {} created", path.display());
+                    let _ = process_file(
+                        path.to_path_buf(), folder.id.clone(), folder.is_root, self.port,
+                        self.index_types.clone(), self.file_types.clone(), self.run.clone(),
+                    ).await;
+                }
+            }
+            return false;
+        }
+
+        // The path no longer exists
+        if let Some(record) = folder.files.get(&name) {
+            debug!("File {} deleted", path.display());
+            delete_file(record.id.clone(), self.port).await;
+        } else if let Some(folder_id) = folder.folders.get(&name) {
+            debug!("Folder {} deleted", path.display());
+            if let Err(e) = delete_folder(folder_id.clone(), self.port).await {
+                warn!("Failed to delete folder {}: {}", path.display(), e);
+            }
+        }
+        false
+    }
+
+    // Create a folder that is new on disk and index everything in it
+    async fn create_folder(&self, path: &Path, parent: &IndexedFolder) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        debug!("Folder {} created", path.display());
+        let folder_id = match create_folder(&name, &parent.id, parent.is_root, self.port).await {
+            Ok(folder_id) => folder_id,
+            Err(e) => {
+                self.run.record_failure(path.display(), Stage::CreateFolder, e);
+                return;
+            }
+        };
+        if let Err(e) = populate(
+            path.to_path_buf(), folder_id, self.port, false,
+            self.index_types.clone(), self.file_types.clone(), self.run.clone(),
+        ).await {
+            self.run.record_failure(path.display(), Stage::CreateFolder, e);
+        }
+    }
+}
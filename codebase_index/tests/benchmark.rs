@@ -0,0 +1,56 @@
+// Time the ingestion of a 10,000 file tree against a mock Helix and an embedding
+// server that takes a while to answer, to compare changes to the embedding
+// pipeline by running it on each version. Ignored by default; run it with
+//   cargo test --release --test benchmark -- --ignored --nocapture
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, Indexer};
+use common::{mock_helix, synthetic_tree};
+use serde_json::json;
+use std::fs;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const FILES: usize = 10_000;
+
+// Time the embedding server takes per request, roughly that of a hosted API
+const EMBED_LATENCY: Duration = Duration::from_millis(50);
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn ingests_ten_thousand_files() {
+    let server = mock_helix().await;
+    Mock::given(method("POST"))
+        .and(path("/embed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "embedding": vec![0.5; 8] })).set_delay(EMBED_LATENCY))
+        .mount(&server)
+        .await;
+    let root = synthetic_tree("benchmark", FILES, 100);
+
+    // Only the pipeline is measured, so the rate limits are lifted
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("benchmark".to_string()),
+        embed_provider: EmbedProvider::Http,
+        embed_url: Some(format!("{}/embed", server.uri())),
+        embed_response_path: "embedding".to_string(),
+        embed_rpm: NonZeroU32::new(10_000_000).unwrap(),
+        helix_rpm: NonZeroU32::new(10_000_000).unwrap(),
+        ..Config::default()
+    };
+    let concurrency_embeddings = config.concurrency_embeddings;
+    let summary = Indexer::new(config).ingest(&root).await.unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert_eq!(summary.files, FILES);
+    println!(
+        "{} files, {} embeddings in {:.2}s: {:.1} embeddings per second with {} in flight and {:?} per request",
+        summary.files, summary.embeddings, summary.elapsed.as_secs_f64(), summary.embeddings_per_second(),
+        concurrency_embeddings, EMBED_LATENCY,
+    );
+}
@@ -0,0 +1,63 @@
+// Resume an interrupted ingestion from its checkpoint
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, IndexError, Indexer};
+use common::{bodies, mock_helix, temp_dir};
+use std::fs;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// A root name with a path separator, which the checkpoint file name must not keep
+const ROOT_NAME: &str = "checkpoint-test/resume";
+
+fn config(port: u16, resume: bool) -> Config {
+    Config {
+        helix_host: "127.0.0.1".to_string(),
+        port,
+        root_name: Some(ROOT_NAME.to_string()),
+        embed_provider: EmbedProvider::Mock,
+        embed_retries: 0,
+        resume,
+        ..Config::default()
+    }
+}
+
+// The config is process wide, so the runs happen one after another
+#[tokio::test(flavor = "multi_thread")]
+async fn resumes_only_from_the_same_folder() {
+    let root = temp_dir("checkpoint");
+    fs::write(root.join("module.py"), "def function(value):\n    return value\n").unwrap();
+    let other = temp_dir("checkpoint-other");
+    fs::write(other.join("module.py"), "def other(value):\n    return value\n").unwrap();
+
+    // Embeddings that fail leave the file incomplete, so the checkpoint is kept.
+    // This run starts afresh, over any checkpoint an earlier test run left.
+    let failing = mock_helix().await;
+    Mock::given(method("POST"))
+        .and(path("/embedSuperEntity"))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&failing)
+        .await;
+    let summary = Indexer::new(config(failing.address().port(), false)).ingest(&root).await.unwrap();
+    assert!(summary.embeddings_failed > 0);
+
+    // Another checkout with the same folder name must not add to that root
+    let server = mock_helix().await;
+    match Indexer::new(config(server.address().port(), true)).ingest(&other).await {
+        Err(IndexError::Config(message)) => assert!(message.contains("checkpoint"), "{}", message),
+        result => panic!("resumed another folder's checkpoint: {:?}", result.map(|summary| summary.root_id)),
+    }
+    assert!(server.received_requests().await.unwrap().is_empty());
+
+    // The folder the checkpoint is of continues in the root it created
+    let summary = Indexer::new(config(server.address().port(), true)).ingest(&root).await.unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&other).unwrap();
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert_eq!(summary.root_id, "root");
+    let requests = server.received_requests().await.unwrap();
+    assert!(bodies(&requests, "createRoot").is_empty());
+    assert_eq!(bodies(&requests, "createSuperFile").len(), 1);
+}
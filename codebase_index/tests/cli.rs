@@ -0,0 +1,34 @@
+// Run the binary the way a user does from a terminal
+mod common;
+
+use common::{respond, sample_dir};
+use serde_json::json;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use wiremock::MockServer;
+
+// A folder given positionally that cannot be indexed is replaced by the one
+// entered at the prompt, instead of asking again for ever
+#[tokio::test(flavor = "multi_thread")]
+async fn asks_again_for_a_missing_folder() {
+    let server = MockServer::start().await;
+    respond(&server, "getRoot", json!({ "root": [] })).await;
+    let port = server.address().port().to_string();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_codebase_index"))
+        .args(["/no/such/folder", "--helix-host", "127.0.0.1", "--port", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The folder to index, then Exit from the menu
+    let input = format!("{}\n9\n", sample_dir().display());
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = tokio::task::spawn_blocking(move || child.wait_with_output()).await.unwrap().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("Enter the folder to index").count(), 1, "{}", stdout);
+    assert!(stdout.contains("What would you like to do?"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
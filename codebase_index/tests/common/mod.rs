@@ -0,0 +1,75 @@
+// Mock Helix server shared by the integration tests
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+pub async fn respond(server: &MockServer, endpoint: &str, body: Value) {
+    Mock::given(method("POST"))
+        .and(path(format!("/{}", endpoint)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+// Helix answering the queries an ingestion sends. Every node of a kind gets the same id.
+pub async fn mock_helix() -> MockServer {
+    let server = MockServer::start().await;
+    respond(&server, "createRoot", json!({ "root": { "id": "root" } })).await;
+    respond(&server, "createSuperFolder", json!({ "folder": { "id": "folder" } })).await;
+    respond(&server, "createSubFolder", json!({ "subfolder": { "id": "subfolder" } })).await;
+    respond(&server, "updateFolderHash", json!({ "folder": { "id": "folder" } })).await;
+    respond(&server, "createSuperFile", json!({ "file": { "id": "super-file" } })).await;
+    respond(&server, "createFile", json!({ "file": { "id": "file" } })).await;
+    respond(&server, "createSuperEntity", json!({ "entity": { "id": "entity" } })).await;
+    respond(&server, "createSubEntity", json!({ "entity": { "id": "sub-entity" } })).await;
+    respond(&server, "embedSuperEntity", json!({ "embeded_code": { "id": "embedding" } })).await;
+    server
+}
+
+// Bodies of the requests sent to an endpoint
+pub fn bodies(requests: &[Request], endpoint: &str) -> Vec<Value> {
+    requests.iter()
+        .filter(|request| request.url.path() == format!("/{}", endpoint))
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect()
+}
+
+pub fn sample_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/sample")
+}
+
+// Empty directory under the system's temp directory, unique to the call
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir().join(format!("codebase-index-{}-{}-{}", name, std::process::id(), count));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Folder of `files` small Python files, spread over subfolders of `per_folder`
+// files, each with a function of its own so that no two files are alike
+pub fn synthetic_tree(name: &str, files: usize, per_folder: usize) -> PathBuf {
+    let root = temp_dir(name);
+    for i in 0..files {
+        let folder = root.join(format!("package_{}", i / per_folder));
+        fs::create_dir_all(&folder).unwrap();
+        let source = format!("def function_{i}(value):\n    return value * {i}\n");
+        fs::write(folder.join(format!("module_{}.py", i)), source).unwrap();
+    }
+    root
+}
+
+// The config is process wide, so tests in one file that each run an Indexer
+// hold this while they do
+pub async fn lock_config() -> tokio::sync::MutexGuard<'static, ()> {
+    static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    LOCK.lock().await
+}
@@ -0,0 +1,43 @@
+// Retry writing a vector to Helix only when Helix cannot have stored it
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, Indexer};
+use common::{bodies, mock_helix, temp_dir};
+use std::fs;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// A write that times out may have been stored, so sending it again could store
+// the vector twice
+#[tokio::test(flavor = "multi_thread")]
+async fn does_not_retry_timed_out_vector_writes() {
+    let server = mock_helix().await;
+    Mock::given(method("POST"))
+        .and(path("/embedSuperEntity"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+
+    let root = temp_dir("vector-write");
+    fs::write(root.join("scale.py"), "def scale(value):\n    return value * 2\n").unwrap();
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("vector-write-test".to_string()),
+        embed_provider: EmbedProvider::Mock,
+        embed_retries: 3,
+        helix_timeout: 1,
+        ..Config::default()
+    };
+    let summary = Indexer::new(config).ingest(&root).await.unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    // The failed embedding leaves the file incomplete, which keeps its checkpoint
+    let _ = fs::remove_file(".checkpoint-vector-write-test.json");
+
+    assert_eq!(summary.embeddings_failed, 1);
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(bodies(&requests, "embedSuperEntity").len(), 1);
+}
@@ -0,0 +1,22 @@
+// Call a Helix deployment whose queries are named differently
+mod common;
+
+use codebase_index::{Config, IndexError, Indexer};
+use common::respond;
+use serde_json::json;
+use wiremock::MockServer;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn calls_renamed_queries() {
+    let mut config = Config::default();
+    assert!(matches!(config.set("helix_endpoints", "getRoots=listRoots"), Err(IndexError::Config(_))));
+    assert!(matches!(config.set("helix_endpoints", "getRoot"), Err(IndexError::Config(_))));
+    config.set("helix_endpoints", "getRoot=listRoots").unwrap();
+
+    let server = MockServer::start().await;
+    respond(&server, "listRoots", json!({ "root": [{ "id": "root", "name": "sample" }] })).await;
+    let indexer = Indexer::new(Config { helix_host: "127.0.0.1".to_string(), port: server.address().port(), ..config });
+    let roots = indexer.roots().await.unwrap();
+    assert_eq!(roots.len(), 1);
+    assert_eq!(roots[0].name, "sample");
+}
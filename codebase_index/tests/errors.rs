@@ -0,0 +1,73 @@
+// Check that failures come back as the IndexError variant callers match on
+mod common;
+
+use codebase_index::{Config, IndexError, Indexer};
+use common::respond;
+use serde_json::json;
+use std::net::TcpListener;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn indexer(port: u16) -> Indexer {
+    Indexer::new(Config { helix_host: "127.0.0.1".to_string(), port, ..Config::default() })
+}
+
+// The config is process wide, so the cases run one after another
+#[tokio::test(flavor = "multi_thread")]
+async fn returns_structured_errors() {
+    let args: Vec<String> = ["codebase_index", "--concurrency", "0"].iter().map(|arg| arg.to_string()).collect();
+    assert!(matches!(Config::load(&args), Err(IndexError::Config(_))));
+
+    // Nothing listens on a port that was just released
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let error = indexer(port).roots().await.unwrap_err();
+    assert!(matches!(error, IndexError::HelixUnreachable(_)), "{:?}", error);
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/getRoot"))
+        .respond_with(ResponseTemplate::new(400).set_body_string("bad request"))
+        .mount(&server)
+        .await;
+    match indexer(server.address().port()).roots().await {
+        Err(IndexError::HelixError { endpoint, status, body }) => {
+            assert_eq!((endpoint.as_str(), status, body.as_str()), ("getRoot", 400, "bad request"));
+        }
+        other => panic!("expected a Helix error, got {:?}", other.map(|roots| roots.len())),
+    }
+
+    let server = MockServer::start().await;
+    respond(&server, "getRoot", json!({ "root": [] })).await;
+    let indexer = indexer(server.address().port());
+    let error = indexer.stats("missing").await.unwrap_err();
+    assert!(matches!(&error, IndexError::RootNotFound(id) if id == "missing"), "{:?}", error);
+
+    let error = indexer.inspect("no/such/file.py").unwrap_err();
+    assert!(matches!(&error, IndexError::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound), "{:?}", error);
+}
+
+// Blocking a runtime thread on a future is refused instead of panicking or deadlocking
+#[tokio::test]
+async fn refuses_block_on_within_a_runtime() {
+    let error = codebase_index::block_on(async { 1 }).unwrap_err();
+    assert!(matches!(&error, IndexError::Other(_)), "{:?}", error);
+}
+
+#[test]
+fn blocks_on_outside_a_runtime() {
+    assert_eq!(codebase_index::block_on(async { 1 }).unwrap(), 1);
+}
+
+// Only Gemini limits the vector length; other providers take any length
+#[test]
+fn checks_dimensions_for_gemini_only() {
+    let load = |args: &[&str]| {
+        let args: Vec<String> = ["codebase_index"].iter().chain(args).map(|arg| arg.to_string()).collect();
+        Config::load(&args)
+    };
+    assert!(matches!(load(&["--embed-dimensions", "64"]), Err(IndexError::Config(_))));
+    assert!(load(&["--embed-dimensions", "768"]).is_ok());
+    let config = load(&["--embed-dimensions", "64", "--embed-provider", "mock"]).unwrap();
+    assert_eq!(config.embed_dimensions, Some(64));
+    assert!(matches!(load(&["--embed-provider", "mock", "--embed-dimensions", "0"]), Err(IndexError::Config(_))));
+}
@@ -0,0 +1,179 @@
+// Ingest the sample directory against a mock Helix and embedding server, and
+// check the requests the indexer sends
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, Indexer};
+use common::{bodies, lock_config, mock_helix, respond, sample_dir, synthetic_tree, temp_dir};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::num::NonZeroU32;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, Request, ResponseTemplate};
+
+// Length of the vectors the mock embedding server returns
+const DIMENSIONS: usize = 8;
+
+// The Helix rate limit is sized by the first run in the process, so every test
+// here lifts it to keep the synthetic tree from waiting on it
+const HELIX_RPM: u32 = 1_000_000;
+
+fn names(bodies: &[Value]) -> BTreeSet<String> {
+    bodies.iter().map(|body| body["name"].as_str().unwrap().to_string()).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ingests_sample_directory() {
+    let _config = lock_config().await;
+    let server = mock_helix().await;
+    respond(&server, "embed", json!({ "embedding": vec![0.5; DIMENSIONS] })).await;
+
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("sample-ingestion-test".to_string()),
+        embed_provider: EmbedProvider::Http,
+        embed_url: Some(format!("{}/embed", server.uri())),
+        embed_response_path: "embedding".to_string(),
+        helix_rpm: NonZeroU32::new(HELIX_RPM).unwrap(),
+        ..Config::default()
+    };
+    let indexer = Indexer::new(config);
+    let summary = indexer.ingest(sample_dir()).await.unwrap();
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert!(!summary.interrupted);
+
+    let requests = server.received_requests().await.unwrap();
+    let roots = bodies(&requests, "createRoot");
+    assert_eq!(roots.len(), 1);
+    assert_eq!(roots[0]["name"], "sample-ingestion-test");
+    assert_eq!(roots[0]["embedding_provider"], "http");
+    assert_eq!(roots[0]["embedding_dimensions"], DIMENSIONS);
+
+    // Hidden files such as .gitignore are left out
+    assert_eq!(names(&bodies(&requests, "createSuperFolder")), BTreeSet::from(["simple_code".to_string()]));
+    // Folders whose files were all indexed get the fingerprint update compares against
+    let hashes = bodies(&requests, "updateFolderHash");
+    assert_eq!(hashes.len(), 1);
+    assert_eq!(hashes[0]["folder_id"], "folder");
+    assert!(!hashes[0]["hash"].as_str().unwrap().is_empty());
+    let super_files = bodies(&requests, "createSuperFile");
+    assert_eq!(names(&super_files), BTreeSet::from(["file2.txt".to_string(), "lab00.py".to_string()]));
+    assert!(super_files.iter().all(|file| file["root_id"] == "root"));
+    let files = bodies(&requests, "createFile");
+    let expected: BTreeSet<String> = [
+        "deploy", "ingestion_copy.py", "install.sh", "test.hs", "test.kt", "test.mjs", "test.rs", "test.scala", "test.swift", "test.zig",
+        "testfile.py",
+    ].iter().map(|name| name.to_string()).collect();
+    assert_eq!(names(&files), expected);
+    assert!(files.iter().all(|file| file["folder_id"] == "folder"));
+    assert_eq!(summary.files, super_files.len() + files.len());
+
+    let entities = bodies(&requests, "createSuperEntity");
+    let sub_entities = bodies(&requests, "createSubEntity");
+    assert_eq!(summary.entities, entities.len() + sub_entities.len());
+    assert!(entities.iter().any(|entity| entity["entity_type"] == "function_definition" && entity["name"] == "respect_your_cat" && entity["label"] == "function"));
+    assert!(entities.iter().any(|entity| entity["entity_type"] == "chunk" && entity["label"] == "chunk"));
+    for entity in entities.iter().chain(&sub_entities) {
+        assert!(!entity["entity_type"].as_str().unwrap().is_empty());
+        assert!(entity["start_byte"].as_u64().unwrap() <= entity["end_byte"].as_u64().unwrap());
+        // Blame is only added with with_blame set
+        assert_eq!(entity["author"], "");
+    }
+    assert!(sub_entities.iter().all(|entity| entity["entity_id"] == "entity" || entity["entity_id"] == "sub-entity"));
+
+    // The first embedding request checks the vector length
+    let embeddings = bodies(&requests, "embedSuperEntity");
+    assert_eq!(summary.embeddings_failed, 0);
+    assert_eq!(summary.embeddings, embeddings.len());
+    assert_eq!(bodies(&requests, "embed").len(), embeddings.len() + 1);
+    assert!(embeddings.iter().all(|embedding| embedding["vector"].as_array().unwrap().len() == DIMENSIONS));
+}
+
+// A tree with many more files than the embedding channel holds, with few
+// embeddings in flight, so file processing has to wait for the channel to drain
+#[tokio::test(flavor = "multi_thread")]
+async fn ingests_large_synthetic_tree() {
+    const FILES: usize = 3000;
+    let _config = lock_config().await;
+    let server = mock_helix().await;
+    let root = synthetic_tree("stress", FILES, 100);
+
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("synthetic-ingestion-test".to_string()),
+        embed_provider: EmbedProvider::Mock,
+        embed_dimensions: Some(DIMENSIONS),
+        concurrency_embeddings: 4,
+        helix_rpm: NonZeroU32::new(HELIX_RPM).unwrap(),
+        ..Config::default()
+    };
+    let indexer = Indexer::new(config);
+    let summary = indexer.ingest(&root).await.unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert!(!summary.interrupted);
+    assert_eq!(summary.files, FILES);
+
+    let progress = indexer.progress();
+    assert_eq!(progress.embeddings_completed, progress.embeddings_pending);
+    assert_eq!(progress.embeddings_failed, 0);
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(bodies(&requests, "createSuperFolder").len(), FILES / 100);
+    assert_eq!(bodies(&requests, "createFile").len(), FILES);
+    let entities = bodies(&requests, "createSuperEntity");
+    assert_eq!(entities.len(), FILES);
+    let functions: BTreeSet<&str> = entities.iter().map(|entity| entity["name"].as_str().unwrap()).collect();
+    assert_eq!(functions.len(), FILES);
+    // Every queued chunk was embedded exactly once, none lost to a full channel
+    assert_eq!(bodies(&requests, "embedSuperEntity").len(), summary.embeddings);
+    assert_eq!(summary.embeddings, progress.embeddings_pending);
+    assert!(summary.embeddings >= FILES);
+}
+
+// A file deleted after the walk listed it, as during a rebase or build, is
+// skipped and the rest of the run goes on
+#[tokio::test(flavor = "multi_thread")]
+async fn skips_file_deleted_during_run() {
+    let _config = lock_config().await;
+    let server = mock_helix().await;
+    let root = temp_dir("deleted-file");
+    fs::write(root.join("kept.py"), "def kept():\n    return 1\n").unwrap();
+    fs::create_dir(root.join("vanishing")).unwrap();
+    let deleted = root.join("vanishing/gone.py");
+    fs::write(&deleted, "def gone():\n    return 2\n").unwrap();
+
+    // Files wait for their folder to be created, so this runs after the walk and before the read
+    let to_delete = deleted.clone();
+    Mock::given(method("POST"))
+        .and(path("/createSuperFolder"))
+        .respond_with(move |_: &Request| {
+            let _ = fs::remove_file(&to_delete);
+            ResponseTemplate::new(200).set_body_json(json!({ "folder": { "id": "folder" } }))
+        })
+        .with_priority(1)
+        .mount(&server)
+        .await;
+
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("deleted-file-test".to_string()),
+        embed_provider: EmbedProvider::Mock,
+        helix_rpm: NonZeroU32::new(HELIX_RPM).unwrap(),
+        ..Config::default()
+    };
+    let summary = Indexer::new(config).ingest(&root).await.unwrap();
+    assert!(!deleted.exists());
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert_eq!(summary.report.skipped_files, 1);
+    assert_eq!(summary.files, 2);
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(names(&bodies(&requests, "createSuperFile")), BTreeSet::from(["kept.py".to_string()]));
+    assert!(bodies(&requests, "createFile").is_empty());
+}
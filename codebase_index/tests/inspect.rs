@@ -0,0 +1,79 @@
+// Parse and chunk sample files without Helix or embeddings
+use codebase_index::{Config, Indexer, InspectedEntity};
+use std::path::PathBuf;
+
+fn sample(file: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/sample").join(file)
+}
+
+#[tokio::test]
+async fn inspects_sample_files() {
+    let indexer = Indexer::new(Config::default());
+
+    // Top level entities are embedded, their children are not
+    let python = indexer.inspect(sample("lab00.py")).unwrap();
+    assert!(python.parsed && !python.skipped && !python.parse_errors);
+    assert!(python.chunks.is_empty());
+    let functions: Vec<_> = python.entities.iter()
+        .filter(|entity| entity.kind == "function_definition")
+        .map(|entity| entity.name.as_deref().unwrap_or_default())
+        .collect();
+    assert_eq!(functions, ["respect_your_cat", "main"]);
+    let orders: Vec<usize> = python.entities.iter().map(|entity| entity.order).collect();
+    assert!(orders.windows(2).all(|pair| pair[0] < pair[1]));
+    for entity in &python.entities {
+        assert!(!entity.chunks.is_empty());
+        assert!(entity.children.iter().all(|child| child.chunks.is_empty()));
+    }
+    let if_statement = python.entities[0].children.iter().find(|child| child.kind == "if_statement").unwrap();
+    assert!(if_statement.start_byte > python.entities[0].start_byte);
+
+    // Scripts without an extension are parsed by their shebang
+    let shell = indexer.inspect(sample("simple_code/deploy")).unwrap();
+    assert_eq!(shell.extension, "sh");
+    assert!(shell.entities.iter().any(|entity| entity.kind == "function_definition"));
+
+    // Methods are stored under their Scala class or object, through its body
+    let scala = indexer.inspect(sample("simple_code/test.scala")).unwrap();
+    assert!(scala.parsed && !scala.parse_errors);
+    let circle = scala.entities.iter().find(|entity| entity.name.as_deref() == Some("Circle")).unwrap();
+    assert_eq!(circle.kind, "class_definition");
+    let contains = circle.children.iter().find(|child| child.name.as_deref() == Some("contains")).unwrap();
+    assert_eq!(contains.kind, "function_definition");
+    assert!(contains.children.iter().any(|child| child.name.as_deref() == Some("squared")));
+    assert!(scala.entities.iter().any(|entity| entity.kind == "object_definition" && entity.name.as_deref() == Some("Main")));
+    assert!(scala.entities.iter().any(|entity| entity.kind == "trait_definition" && entity.name.as_deref() == Some("Shape")));
+
+    // Haskell declarations are top level entities, where clauses are under their function
+    let haskell = indexer.inspect(sample("simple_code/test.hs")).unwrap();
+    assert!(haskell.parsed && !haskell.parse_errors);
+    let named = |kind: &str, name: &str| haskell.entities.iter()
+        .find(|entity| entity.kind == kind && entity.name.as_deref() == Some(name));
+    assert!(named("data_type", "Shape").is_some());
+    assert!(named("signature", "distance").is_some());
+    let contains = named("function", "contains").unwrap();
+    assert!(contains.children.iter().any(|child| child.kind == "function" && child.name.as_deref() == Some("within")));
+}
+
+// Order of every entity, depth first, with where it starts
+fn orders(entities: &[InspectedEntity], out: &mut Vec<(usize, usize, usize)>, depth: usize) {
+    for entity in entities {
+        out.push((depth, entity.order, entity.start_byte));
+        orders(&entity.children, out, depth + 1);
+    }
+}
+
+// Orders come from source positions, so inspecting a file again gives the same ones
+#[tokio::test]
+async fn orders_entities_the_same_every_run() {
+    let indexer = Indexer::new(Config::default());
+    for file in ["lab00.py", "simple_code/test.rs", "simple_code/test.scala", "simple_code/test.hs"] {
+        let runs: Vec<Vec<(usize, usize, usize)>> = (0..2).map(|_| {
+            let mut sequence = Vec::new();
+            orders(&indexer.inspect(sample(file)).unwrap().entities, &mut sequence, 0);
+            sequence
+        }).collect();
+        assert!(!runs[0].is_empty(), "{}", file);
+        assert_eq!(runs[0], runs[1], "{}", file);
+    }
+}
@@ -0,0 +1,81 @@
+// Run the whole ingest and embed path offline with the mock embedding provider
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, Indexer};
+use common::{bodies, lock_config, mock_helix, sample_dir};
+use serde_json::json;
+use std::collections::HashSet;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn embeds_every_chunk_without_network() {
+    let _config = lock_config().await;
+    let server = mock_helix().await;
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("sample-mock-embeddings-test".to_string()),
+        embed_provider: EmbedProvider::Mock,
+        embed_dimensions: Some(128),
+        ..Config::default()
+    };
+    let indexer = Indexer::new(config);
+    let summary = indexer.ingest(sample_dir()).await.unwrap();
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+
+    let progress = indexer.progress();
+    assert!(progress.embeddings_pending > 0);
+    assert_eq!(progress.embeddings_completed, progress.embeddings_pending);
+    assert_eq!(progress.embeddings_failed, 0);
+
+    let requests = server.received_requests().await.unwrap();
+    let roots = bodies(&requests, "createRoot");
+    assert_eq!(roots[0]["embedding_provider"], "mock");
+    assert_eq!(roots[0]["embedding_dimensions"], 128);
+
+    let embeddings = bodies(&requests, "embedSuperEntity");
+    assert_eq!(embeddings.len(), summary.embeddings);
+    assert!(embeddings.iter().all(|embedding| embedding["vector"].as_array().unwrap().len() == 128));
+    // Vectors are derived from the text, so different chunks get different ones
+    let vectors: HashSet<String> = embeddings.iter().map(|embedding| embedding["vector"].to_string()).collect();
+    assert!(vectors.len() > 1);
+}
+
+// Storing vectors is slower than walking the tree here, so the walk ends with
+// embeddings still queued; the run returns only once every one has finished
+#[tokio::test(flavor = "multi_thread")]
+async fn returns_once_embeddings_drain() {
+    let _config = lock_config().await;
+    let server = mock_helix().await;
+    Mock::given(method("POST"))
+        .and(path("/embedSuperEntity"))
+        .respond_with(ResponseTemplate::new(200)
+            .set_body_json(json!({ "embeded_code": { "id": "embedding" } }))
+            .set_delay(Duration::from_millis(100)))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("sample-drain-test".to_string()),
+        embed_provider: EmbedProvider::Mock,
+        concurrency_embeddings: 2,
+        ..Config::default()
+    };
+    let indexer = Indexer::new(config);
+    let summary = indexer.ingest(sample_dir()).await.unwrap();
+
+    let progress = indexer.progress();
+    assert!(!progress.walking);
+    assert!(progress.embeddings_pending > 0);
+    assert_eq!(progress.embeddings_completed + progress.embeddings_failed, progress.embeddings_pending);
+    assert_eq!(summary.embeddings, progress.embeddings_completed);
+    assert_eq!(summary.embeddings_failed, 0);
+    // Every vector sent to Helix was answered before the run returned
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(bodies(&requests, "embedSuperEntity").len(), summary.embeddings);
+}
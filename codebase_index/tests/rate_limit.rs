@@ -0,0 +1,59 @@
+// Retry embedding requests that the API turns away for its rate limit
+mod common;
+
+use codebase_index::config::EmbedProvider;
+use codebase_index::{Config, Indexer};
+use common::{mock_helix, temp_dir};
+use serde_json::json;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, Request, ResponseTemplate};
+
+// Wait before the first retry of a transient failure
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retries_rate_limited_embeddings() {
+    let server = mock_helix().await;
+    // The dimension check gets a vector, the chunk's first request is rate
+    // limited, and its retry succeeds
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let recorded = calls.clone();
+    Mock::given(method("POST"))
+        .and(path("/embed"))
+        .respond_with(move |_: &Request| {
+            let mut calls = recorded.lock().unwrap();
+            calls.push(Instant::now());
+            if calls.len() == 2 {
+                ResponseTemplate::new(429).set_body_string("quota exceeded")
+            } else {
+                ResponseTemplate::new(200).set_body_json(json!({ "embedding": vec![0.5; 8] }))
+            }
+        })
+        .mount(&server)
+        .await;
+
+    let root = temp_dir("rate-limit");
+    fs::write(root.join("scale.py"), "def scale(value):\n    return value * 2\n").unwrap();
+    let config = Config {
+        helix_host: "127.0.0.1".to_string(),
+        port: server.address().port(),
+        root_name: Some("rate-limit-test".to_string()),
+        embed_provider: EmbedProvider::Http,
+        embed_url: Some(format!("{}/embed", server.uri())),
+        embed_response_path: "embedding".to_string(),
+        embed_retries: 2,
+        ..Config::default()
+    };
+    let summary = Indexer::new(config).ingest(&root).await.unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(summary.report.failures.is_empty(), "{:?}", summary.report.failures);
+    assert_eq!(summary.embeddings, 1);
+    assert_eq!(summary.embeddings_failed, 0);
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 3);
+    assert!(calls[2] - calls[1] >= FIRST_BACKOFF, "retried after {:?}", calls[2] - calls[1]);
+}
@@ -0,0 +1,62 @@
+// Check what a repository's .codebaseindex/config.toml may set
+mod common;
+
+use codebase_index::{Config, IndexError};
+use common::temp_dir;
+use std::fs;
+
+// Load the config for a folder with this config.toml
+fn load(contents: &str) -> Result<Config, IndexError> {
+    let dir = temp_dir("repo-config");
+    fs::create_dir(dir.join(".codebaseindex")).unwrap();
+    fs::write(dir.join(".codebaseindex/config.toml"), contents).unwrap();
+    let args = vec!["codebase_index".to_string(), dir.to_string_lossy().to_string()];
+    let config = Config::load(&args);
+    fs::remove_dir_all(&dir).unwrap();
+    config
+}
+
+#[test]
+fn sets_indexing_options() {
+    let config = load("max_entity_depth = 4\nskip-dirs = [\"generated\"]\n").unwrap();
+    assert_eq!(config.max_entity_depth, 4);
+    assert_eq!(config.skip_dirs, ["generated"]);
+}
+
+// A repository being indexed must not choose where its code and EMBED_API_KEY go,
+// or which queries are run
+#[test]
+fn cannot_redirect_code() {
+    let settings = [
+        "helix_host = \"attacker.example\"",
+        "embed_provider = \"http\"",
+        "embed_url = \"http://attacker.example/embed\"",
+        "embed-url = \"http://attacker.example/embed\"",
+        "embed_body = '{\"input\": \"{text}\"}'",
+        "embed_response_path = \"data.0.embedding\"",
+        "embed_model = \"other-model\"",
+        "helix_endpoints = [\"createFile=deleteRoot\"]",
+        "helix-endpoints = \"createFile=deleteRoot\"",
+    ];
+    for setting in settings {
+        match load(setting) {
+            Err(IndexError::Config(message)) => assert!(message.contains("cannot be set"), "{}", message),
+            other => panic!("{} was accepted: {:?}", setting, other.map(|config| config.embed_url)),
+        }
+    }
+}
+
+// A file_types.json with an entry of the wrong type is a config error, not a panic
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_invalid_file_types() {
+    let dir = temp_dir("file-types");
+    fs::write(dir.join("file_types.json"), "{ \"unsupported\": \"txt\" }").unwrap();
+    fs::write(dir.join("notes.txt"), "notes\n").unwrap();
+    let config = Config { file_types_path: Some(dir.join("file_types.json")), ..Config::default() };
+    let result = codebase_index::Indexer::new(config).inspect(dir.join("notes.txt"));
+    fs::remove_dir_all(&dir).unwrap();
+    match result {
+        Err(IndexError::Config(message)) => assert!(message.contains("unsupported"), "{}", message),
+        other => panic!("expected a config error, got {:?}", other.map(|inspection| inspection.extension)),
+    }
+}
@@ -1,6 +1,6 @@
 // Create Root
-QUERY createRoot(name: String) => 
-    root <- AddN<Root>({name:name})
+QUERY createRoot(name: String, embedding_provider: String, embedding_model: String, embedding_dimensions: I64) => 
+    root <- AddN<Root>({name:name, embedding_provider:embedding_provider, embedding_model:embedding_model, embedding_dimensions:embedding_dimensions})
     RETURN root
 
 // Create Folders
@@ -17,22 +17,31 @@ QUERY createSubFolder(folder_id: ID, name: String) =>
     RETURN subfolder
 
 // Create Files
-QUERY createSuperFile(root_id: ID, name: String, extension: String, text: String) => 
+QUERY createSuperFile(root_id: ID, name: String, extension: String, text: String, hash: String, size: I64, lines: I64, modified_at: Date, language: String, parse_errors: Boolean) => 
     root <- N<Root>(root_id)
-    file <- AddN<File>({name:name, extension:extension, text:text})
+    file <- AddN<File>({name:name, extension:extension, text:text, hash:hash, size:size, lines:lines, modified_at:modified_at, language:language, parse_errors:parse_errors})
     AddE<Root_to_File>()::From(root)::To(file)
     RETURN file
 
-QUERY createFile(folder_id: ID, name: String, extension: String, text: String) => 
+QUERY createFile(folder_id: ID, name: String, extension: String, text: String, hash: String, size: I64, lines: I64, modified_at: Date, language: String, parse_errors: Boolean) => 
     folder <- N<Folder>(folder_id)
-    file <- AddN<File>({name:name, extension:extension, text:text})
+    file <- AddN<File>({name:name, extension:extension, text:text, hash:hash, size:size, lines:lines, modified_at:modified_at, language:language, parse_errors:parse_errors})
     AddE<Folder_to_File>()::From(folder)::To(file)
     RETURN file
 
+// Link a copy of a file to the entities of the file it duplicates
+QUERY linkFileEntities(file_id: ID, source_file_id: ID) => 
+    file <- N<File>(file_id)
+    entities <- N<File>(source_file_id)::Out<File_to_Entity>
+    FOR entity IN entities {
+        AddE<File_to_Entity>()::From(file)::To(entity)
+    }
+    RETURN file
+
 // Create Entities
-QUERY createSuperEntity(file_id: ID, entity_type: String, start_byte: I64, end_byte: I64, order: I64, text: String) => 
+QUERY createSuperEntity(file_id: ID, entity_type: String, label: String, name: String, start_byte: I64, end_byte: I64, order: I64, text: String, hash: String, author: String, commit_hash: String, committed_at: I64) => 
     file <- N<File>(file_id)
-    entity <- AddN<Entity>({entity_type:entity_type, start_byte:start_byte, end_byte:end_byte, order:order, text:text})
+    entity <- AddN<Entity>({entity_type:entity_type, label:label, name:name, start_byte:start_byte, end_byte:end_byte, order:order, text:text, hash:hash, author:author, commit_hash:commit_hash, committed_at:committed_at})
     AddE<File_to_Entity>()::From(file)::To(entity)
     RETURN entity
 
@@ -42,9 +51,9 @@ QUERY embedSuperEntity(entity_id: ID, vector: [F64]) =>
     AddE<Entity_to_EmbededCode>()::From(entity)::To(embeded_code)
     RETURN embeded_code
 
-QUERY createSubEntity(entity_id: ID, entity_type: String, start_byte: I64, end_byte: I64, order: I64, text: String) => 
+QUERY createSubEntity(entity_id: ID, entity_type: String, label: String, name: String, start_byte: I64, end_byte: I64, order: I64, text: String, hash: String, author: String, commit_hash: String, committed_at: I64) => 
     parent <- N<Entity>(entity_id)
-    entity <- AddN<Entity>({entity_type:entity_type, start_byte:start_byte, end_byte:end_byte, order:order, text:text})
+    entity <- AddN<Entity>({entity_type:entity_type, label:label, name:name, start_byte:start_byte, end_byte:end_byte, order:order, text:text, hash:hash, author:author, commit_hash:commit_hash, committed_at:committed_at})
     AddE<Entity_to_Entity>()::From(parent)::To(entity)
     RETURN entity
 
@@ -59,6 +68,11 @@ QUERY getRootById(root_id: ID) =>
     root <- N<Root>(root_id)
     RETURN root
 
+// Roots with a name, normally the name of the indexed folder
+QUERY getRootByName(name: String) => 
+    roots <- N<Root>::WHERE(_::{name}::EQ(name))
+    RETURN roots
+
 QUERY getFolderRoot(folder_id: ID) => 
     root <- N<Folder>(folder_id)::In<Root_to_Folder>
     RETURN root
@@ -123,7 +137,7 @@ QUERY getFileByExtension(extension: String) =>
 
 QUERY getFileContent(file_id: ID) => 
     file <- N<File>(file_id)
-    RETURN file::{text}
+    RETURN file::{text, language}
 
 // Get Entity
 QUERY getFileEntities(file_id: ID) => 
@@ -147,15 +161,50 @@ QUERY getSuperEntity(entity_id: ID) =>
     entity <- N<Entity>(entity_id)::In<Entity_to_Entity>
     RETURN entity
 
+// Entities whose symbol name matches, e.g. a function or class name
+QUERY getEntitiesByName(name: String) => 
+    entities <- N<Entity>::WHERE(_::{name}::EQ(name))
+    RETURN entities
+
+// Number of embeddings of an entity, to find entities left without one
+QUERY countEntityEmbeddings(entity_id: ID) => 
+    count <- N<Entity>(entity_id)::Out<Entity_to_EmbededCode>::COUNT
+    RETURN count
+
 QUERY getEntityByType(file_id: ID, entity_type: String) => 
     entity <- N<File>(file_id)::Out<File_to_Entity>::WHERE(_::{entity_type}::EQ(entity_type))
     RETURN entity
 
+// Record the embedding model and vector length a root's entities were embedded with
+QUERY updateRootEmbedding(root_id: ID, embedding_provider: String, embedding_model: String, embedding_dimensions: I64) => 
+    root <- N<Root>(root_id)::UPDATE({embedding_provider:embedding_provider, embedding_model:embedding_model, embedding_dimensions:embedding_dimensions})
+    RETURN root
+
 // Update File
-QUERY updateFile(file_id: ID, text: String, extracted_at: Date) => 
-    file <- N<File>(file_id)::UPDATE({text:text, extracted_at: extracted_at})
+QUERY updateFile(file_id: ID, text: String, hash: String, extracted_at: Date, size: I64, lines: I64, modified_at: Date, language: String, parse_errors: Boolean) => 
+    file <- N<File>(file_id)::UPDATE({text:text, hash:hash, extracted_at: extracted_at, size:size, lines:lines, modified_at:modified_at, language:language, parse_errors:parse_errors})
     RETURN file
 
+// Store the fingerprint of what a folder held when it was last indexed
+QUERY updateFolderHash(folder_id: ID, hash: String) => 
+    folder <- N<Folder>(folder_id)::UPDATE({hash:hash})
+    RETURN folder
+
+// Store the summary embedded for an entity
+QUERY updateEntitySummary(entity_id: ID, summary: String) => 
+    entity <- N<Entity>(entity_id)::UPDATE({summary:summary})
+    RETURN entity
+
+// Move an unchanged entity to its position in the updated file
+QUERY updateEntityPosition(entity_id: ID, start_byte: I64, end_byte: I64, order: I64) => 
+    entity <- N<Entity>(entity_id)::UPDATE({start_byte:start_byte, end_byte:end_byte, order:order})
+    RETURN entity
+
+// Delete Root
+QUERY deleteRoot(root_id: ID) => 
+    DROP N<Root>(root_id)
+    RETURN "success"
+
 // Delete Folder
 QUERY deleteFolder(folder_id: ID) => 
     DROP N<Folder>(folder_id)::InE<Folder_to_Folder>
@@ -180,4 +229,27 @@ QUERY deleteSuperEntity(entity_id: ID) =>
 QUERY deleteSubEntity(entity_id: ID) => 
     DROP N<Entity>(entity_id)::InE<Entity_to_Entity>
     DROP N<Entity>(entity_id)
+    RETURN "success"
+
+// Drop an entity's embeddings, keeping the entity, before it is embedded again
+// Ids of an entity's embeddings, so that they can be deleted once replaced
+QUERY getEntityEmbeddings(entity_id: ID) => 
+    embeddings <- N<Entity>(entity_id)::Out<Entity_to_EmbededCode>
+    RETURN embeddings::ID
+
+// Delete a batch of embeddings by id
+QUERY deleteEmbeddings(embedding_ids: [ID]) => 
+    FOR embedding_id IN embedding_ids {
+        DROP V<EmbededCode>(embedding_id)
+    }
+    RETURN "success"
+
+// Delete a batch of entities with their embeddings
+QUERY deleteEntities(entity_ids: [ID]) => 
+    FOR entity_id IN entity_ids {
+        DROP N<Entity>(entity_id)::InE<File_to_Entity>
+        DROP N<Entity>(entity_id)::InE<Entity_to_Entity>
+        DROP N<Entity>(entity_id)::Out<Entity_to_EmbededCode>
+        DROP N<Entity>(entity_id)
+    }
     RETURN "success"
\ No newline at end of file
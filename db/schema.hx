@@ -1,10 +1,14 @@
 N::Root {
     name: String,
+    embedding_provider: String DEFAULT "",
+    embedding_model: String,
+    embedding_dimensions: I64,
     extracted_at: Date DEFAULT NOW
 }
 
 N::Folder {
     name: String,
+    hash: String DEFAULT "",
     extracted_at: Date DEFAULT NOW
 }
 
@@ -12,15 +16,28 @@ N::File {
     name: String,
     extension: String,
     text: String,
+    hash: String,
+    size: I64,
+    lines: I64,
+    modified_at: Date,
+    language: String,
+    parse_errors: Boolean,
     extracted_at: Date DEFAULT NOW
 }
 
 N::Entity {
     entity_type: String,
+    label: String DEFAULT "",
+    name: String,
     start_byte: I64,
     end_byte: I64,
     order: I64,
     text: String,
+    hash: String,
+    summary: String DEFAULT "",
+    author: String DEFAULT "",
+    commit_hash: String DEFAULT "",
+    committed_at: I64 DEFAULT 0,
     extracted_at: Date DEFAULT NOW
 }
 